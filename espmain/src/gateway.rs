@@ -0,0 +1,41 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use vcp::serial::{encode_message, Downlink, FrameDecoder, Uplink};
+
+/// Commands of the host that can wait for the node task
+const DOWNLINK_QUEUE_LEN: usize = 4;
+/// How long the reader waits when the console has nothing. Without a driver installed
+/// for the console, reading it does not block.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Commands of the host, on their way from the console reader to the node task
+pub static DOWNLINKS: Channel<CriticalSectionRawMutex, Downlink, DOWNLINK_QUEUE_LEN> =
+    Channel::new();
+
+/// Read the frames of the host from the console, forever. Blocks, so it needs its own thread.
+pub fn read_console() {
+    let mut stdin = std::io::stdin();
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0; 64];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(len) if len > 0 => decoder.push(&buf[..len]),
+            _ => std::thread::sleep(POLL_INTERVAL),
+        }
+        while let Some(downlink) = decoder.next_message() {
+            // a full queue stops the reader, never the node task
+            embassy_futures::block_on(DOWNLINKS.send(downlink));
+        }
+    }
+}
+
+/// Send an uplink to the host. A frame that is cut off is skipped by the host.
+pub fn send(uplink: &Uplink) {
+    let mut stdout = std::io::stdout().lock();
+    // errors would be reported on the console that just failed
+    let _ = stdout
+        .write_all(&encode_message(uplink))
+        .and_then(|()| stdout.flush());
+}
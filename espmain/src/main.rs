@@ -1,39 +1,37 @@
-// ignore unused imports
-#![allow(unused_imports)]
-// ignore unused variables
-#![allow(unused_variables)]
-// ignore unused functions
-#![allow(dead_code)]
-
-use ::vcp::vcp::{Packet, Vcp};
-use esp_idf_svc::sys::system;
-use serde_json;
-
-use core::convert::TryInto;
-use std::cell::RefCell;
-use std::io::Read;
-use std::ops::Deref;
-use std::rc::Rc;
-use std::sync::{Arc, RwLock};
-
-use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration};
+use ::vcp::auth::Authenticator;
+use ::vcp::e2e::E2e;
+use ::vcp::limits::Limits;
+use ::vcp::manage::Manager;
+use ::vcp::persist::{PersistError, Persistence};
+use ::vcp::rpc::Rpc;
+use ::vcp::vcp::{Vcp, VcpConfig};
+
+use esp_idf_svc::wifi::{AccessPointConfiguration, Configuration};
 
 use esp_idf_svc::espnow::{EspNow, PeerInfo, BROADCAST};
 use esp_idf_svc::hal::prelude::Peripherals;
-use esp_idf_svc::log::EspLogger;
-use esp_idf_svc::sys::esp_nofail;
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
 
-use esp_idf_svc::hal::cpu::Core;
-use esp_idf_svc::hal::{sys::EspError, task::thread::ThreadSpawnConfiguration};
+use esp_idf_svc::hal::task::block_on;
 
-use log::info;
-use vcp::vcp;
+mod gateway;
+mod runtime;
+mod storage;
 
 // const SSID: &str = env!("WIFI_SSID");
 // const PASSWORD: &str = env!("WIFI_PASS");
 const ESP_NOW_CHANNEL: u8 = 1;
+/// Pre-shared key of the network, set at build time. Nodes without it can't send packets
+/// the others accept. Without a key the packets are neither signed nor checked.
+const NETWORK_KEY: Option<&str> = option_env!("VCP_NETWORK_KEY");
+/// Set at build time to make the node a gateway: it bridges the cord to a host on the
+/// USB-serial console, see `vcp::serial`.
+const GATEWAY: bool = option_env!("VCP_GATEWAY").is_some();
+/// Key of the operators, set at build time. Without it the node can't be managed remotely.
+const MANAGE_KEY: Option<&str> = option_env!("VCP_MANAGE_KEY");
+/// How often the stored state is read before the node gives up and restarts
+const STATE_READ_ATTEMPTS: u32 = 3;
 
 fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -43,13 +41,91 @@ fn main() {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    init_espnow().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    // keep the Wi-Fi alive, ESP-NOW needs it
+    let (_wifi, esp_now) = init_espnow(nvs.clone()).unwrap();
+
+    // the heap is small, keep the tables bounded
+    // the signature of a frame takes room from the packet
+    let config = VcpConfig {
+        limits: Limits::constrained(),
+        max_frame_len: match NETWORK_KEY {
+            Some(_) => runtime::MAX_FRAME_LEN - ::vcp::auth::OVERHEAD,
+            None => runtime::MAX_FRAME_LEN,
+        },
+        ..Default::default()
+    };
+    let mut the_vcp = Vcp::with_config(true, config); // TODO: change the is_first
+    // nodes that join at the same moment must draw different join tokens
+    the_vcp.seed(unsafe { esp_idf_svc::sys::esp_random() });
+
+    // take the position of the last boot back, the neighbors still have to confirm it
+    let storage = || storage::NvsStorage::new(nvs.clone()).unwrap();
+    let mut attempt = 1;
+    let persistence = loop {
+        match Persistence::open(storage(), storage::new_secret) {
+            Ok(persistence) => break persistence,
+            // the blob is broken for good, start over with a new secret
+            Err(PersistError::Corrupt) => {
+                log::error!("The stored state is corrupt, starting over");
+                break Persistence::reset(storage(), storage::new_secret).unwrap();
+            }
+            // the flash may fail only for a moment, a reset would throw the secret away
+            Err(PersistError::Storage(e)) if attempt < STATE_READ_ATTEMPTS => {
+                log::warn!("Can't read the stored state, trying again: {:?}", e);
+                attempt += 1;
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(e) => panic!("Can't read the stored state: {}", e),
+        }
+    };
+    persistence.restore(&mut the_vcp);
+    let state = persistence.state();
+    log::info!("Restored position: {:?}, epoch {}", state.c_id, state.epoch);
+
+    // the secret of the node is its X25519 key, payloads sealed for it only it can read
+    let e2e = E2e::new(state.secret, state.boots);
+    the_vcp.public_key = Some(e2e.public_key());
+    let auth = NETWORK_KEY.map(|key| {
+        let node_id = ::vcp::vcp::node_id(&e2e.public_key());
+        Authenticator::new(key.as_bytes(), node_id, state.boots)
+    });
+    if auth.is_none() {
+        log::warn!("Built without VCP_NETWORK_KEY, packets are not authenticated");
+    }
 
-    //vcp::complex_example_func();
-    log::info!("Going into busy loop!=====");
-    loop {
-        esp_idf_svc::hal::delay::Delay::new_default().delay_ms(1000);
+    let mut rpc = Rpc::new();
+    runtime::serve(&mut rpc, &mut the_vcp).unwrap();
+    let manager = MANAGE_KEY.map(|key| {
+        let manager = Manager::new(key.as_bytes(), state.boots);
+        if !manager.serve(&mut the_vcp) {
+            log::warn!("Can't bind the management port");
+        }
+        manager
+    });
+
+    if GATEWAY {
+        // the console carries the frames now, what ESP-IDF still prints the host skips
+        log::info!("Running as gateway, logging stops");
+        log::set_max_level(log::LevelFilter::Off);
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(gateway::read_console)
+            .unwrap();
     }
+
+    esp_now.register_recv_cb(runtime::on_receive).unwrap();
+    log::info!("Running the node");
+    block_on(runtime::run(
+        esp_now,
+        the_vcp,
+        persistence,
+        auth,
+        e2e,
+        rpc,
+        manager,
+        GATEWAY,
+    ));
 }
 
 pub fn mac_to_string(mac: &[u8]) -> String {
@@ -63,12 +139,14 @@ pub fn mac_to_string(mac: &[u8]) -> String {
     mac_str
 }
 
-fn init_espnow() -> anyhow::Result<(), anyhow::Error> {
+/// Start the Wi-Fi and ESP-NOW, and add the broadcast peer
+fn init_espnow(
+    nvs: EspDefaultNvsPartition,
+) -> anyhow::Result<(BlockingWifi<EspWifi<'static>>, EspNow<'static>)> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))
@@ -113,56 +191,5 @@ fn init_espnow() -> anyhow::Result<(), anyhow::Error> {
         .map_err(|e| e.panic())
         .unwrap();
 
-    // Set current threads name
-    ThreadSpawnConfiguration {
-        name: Some("Receive Thread\0".as_bytes()),
-        stack_size: 8096,
-        priority: 15,
-        pin_to_core: None,
-        ..Default::default()
-    }
-    .set()
-    .map_err(|e| e.panic())
-    .unwrap();
-
-    // TODO: I dont have a clue if this works!!!! - Jonathan
-    let the_vcp = Arc::new(RwLock::new(Vcp::new(true))); // TODO: change the is_first
-    let the_vcp2 = Arc::clone(&the_vcp);
-
-    let esp_now_recv_cb = move |src: &[u8], data: &[u8]| {
-        log::info!("Data recv from {}, len {}", mac_to_string(src), data.len());
-        let data_str = std::str::from_utf8(data).unwrap();
-        let data_json: Packet = serde_json::from_str(data_str).unwrap(); // TODO: handle error gracefullly
-        the_vcp.write().unwrap().receive(&data_json);
-
-        log::info!("Data: {:#?}", data_json);
-    };
-    esp_now.register_recv_cb(esp_now_recv_cb).unwrap();
-
-    let send_thread = std::thread::Builder::new()
-        .stack_size(8196)
-        .spawn(move || {
-            let mut count = 0;
-            loop {
-                for m in the_vcp2.read().unwrap().outgoing_msgs.iter() {
-                    let data = serde_json::to_string(&m).unwrap();
-                    esp_now
-                        .send(BROADCAST, data.as_bytes())
-                        .map_err(|e| e.panic())
-                        .unwrap();
-                    log::info!("Data sent, count {}", count);
-                    count += 1;
-                }
-                {
-                    let mut lock = the_vcp2.write().unwrap();
-                    lock.outgoing_msgs.clear();
-                    lock.timer_call();
-                }
-                esp_idf_svc::hal::delay::Delay::new_default().delay_ms(1000);
-            }
-        })
-        .unwrap();
-    send_thread.join().unwrap();
-
-    Ok(())
+    Ok((wifi, esp_now))
 }
@@ -0,0 +1,223 @@
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use esp_idf_svc::espnow::{EspNow, BROADCAST};
+use vcp::{
+    auth::Authenticator,
+    clock::{Clock, Millis},
+    e2e::E2e,
+    manage::{Action, Manager},
+    persist::Persistence,
+    rpc::{Method, MethodId, Rpc},
+    serial::{self, Uplink},
+    vcp::{Packet, Vcp, Wakeup},
+};
+
+use crate::{gateway, mac_to_string, storage::NvsStorage};
+
+/// Largest payload of a single ESP-NOW frame
+pub const MAX_FRAME_LEN: usize = 250;
+/// Frames that can wait for the node task, before new ones are dropped
+const FRAME_QUEUE_LEN: usize = 8;
+/// How often `Vcp::timer_call` runs. The protocol times are durations in `VcpConfig`,
+/// the tick only sets how precisely they are kept.
+pub const TICK: Duration = Duration::from_millis(100);
+/// How often a gateway tells the host its position and neighbors
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a reboot waits for the radio. `EspNow::send` only queues a frame, a restart
+/// right after it loses the reply to the operator.
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// Milliseconds since boot, from the embassy time driver
+struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now(&self) -> Millis {
+        Instant::now().as_millis()
+    }
+}
+
+/// Asks a node how long it is running, in milliseconds
+pub struct Uptime;
+
+impl Method for Uptime {
+    const ID: MethodId = 1;
+    type Request = ();
+    type Response = Millis;
+}
+
+/// The methods every node answers
+pub fn serve(rpc: &mut Rpc<'static>, vcp: &mut Vcp) -> anyhow::Result<()> {
+    rpc.serve::<Uptime>(vcp, |_, ()| EmbassyClock.now())
+        .map_err(anyhow::Error::msg)
+}
+
+/// A frame as it was received, before it is decoded
+pub struct Frame {
+    pub src: [u8; 6],
+    pub data: heapless::Vec<u8, MAX_FRAME_LEN>,
+}
+
+/// Received frames, on their way from the Wi-Fi driver to the node task
+pub static FRAMES: Channel<CriticalSectionRawMutex, Frame, FRAME_QUEUE_LEN> = Channel::new();
+
+/// Callback for `EspNow::register_recv_cb`. Runs in the Wi-Fi driver, so it only copies
+/// the frame into the channel and never waits.
+pub fn on_receive(src: &[u8], data: &[u8]) {
+    let (Ok(src), Ok(data)) = (src.try_into(), heapless::Vec::from_slice(data)) else {
+        log::warn!("Dropping oversized frame from {}", mac_to_string(src));
+        return;
+    };
+    if FRAMES.try_send(Frame { src, data }).is_err() {
+        log::warn!("Frame queue full, dropping frame from {}", mac_to_string(&src));
+    }
+}
+
+/// The node task. It owns the `Vcp`, so no lock is needed: received frames and
+/// timer ticks are handled one after the other. Forwarded packets are sent as soon as
+/// `Vcp::receive` asks for it, they don't wait for the next tick.
+/// With an `Authenticator` only signed frames are accepted, and every sent frame is signed.
+/// Payloads sealed for the node are opened with `e2e`. `rpc` answers the requests that
+/// arrived and times out its calls after every frame and tick.
+/// A `gateway` passes every received packet and the texts and data for it to the host on
+/// the console, and does what the host asks in `gateway::DOWNLINKS`.
+/// With a `Manager` the node carries out the commands of an operator, see `vcp::manage`.
+pub async fn run(
+    esp_now: EspNow<'static>,
+    mut vcp: Vcp,
+    mut persistence: Persistence<NvsStorage>,
+    mut auth: Option<Authenticator>,
+    e2e: E2e,
+    mut rpc: Rpc<'static>,
+    mut manager: Option<Manager>,
+    gateway: bool,
+) -> ! {
+    let mut ticker = Ticker::every(TICK);
+    let mut next_status = Instant::now();
+    loop {
+        let mut actions = Vec::new();
+        match select3(
+            FRAMES.receive(),
+            ticker.next(),
+            gateway::DOWNLINKS.receive(),
+        )
+        .await
+        {
+            Either3::First(frame) => match decode(&mut auth, &frame.data) {
+                // anyone in range can send anything, drop what is not a packet.
+                // The receive callback of this esp-idf-svc does not report the RSSI,
+                // so links are judged by their Hellos only.
+                Ok(packet) => {
+                    if gateway {
+                        gateway::send(&Uplink::Packet(Box::new(packet.clone())));
+                    }
+                    let wakeup = vcp.receive(&packet);
+                    for payload in e2e.receive(&mut vcp) {
+                        match payload {
+                            Ok((_, plaintext)) => log::info!("Sealed payload: {:?}", plaintext),
+                            Err(e) => log::warn!("Dropping sealed payload: {}", e),
+                        }
+                    }
+                    let answered = rpc.poll(&mut vcp);
+                    if let Some(manager) = &mut manager {
+                        actions = manager.poll(&mut vcp, Some(free_heap()));
+                    }
+                    if gateway {
+                        send_uplinks(&mut vcp);
+                    }
+                    // a manager reply waits in the queue
+                    if answered == 0 && wakeup == Wakeup::Idle && vcp.outgoing_msgs.is_empty() {
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Dropping frame from {}: {}", mac_to_string(&frame.src), e);
+                    continue;
+                }
+            },
+            Either3::Second(()) => {
+                vcp.timer_call(&EmbassyClock);
+                rpc.poll(&mut vcp);
+                if gateway {
+                    send_uplinks(&mut vcp);
+                    if Instant::now() >= next_status {
+                        gateway::send(&serial::status(&vcp));
+                        next_status += STATUS_INTERVAL;
+                    }
+                }
+                // only written when the position changed
+                if let Err(e) = persistence.sync(&vcp) {
+                    log::warn!("Storing the node state failed: {}", e);
+                }
+            }
+            Either3::Third(downlink) => {
+                if !serial::apply(&mut vcp, &downlink) {
+                    log::warn!("Can't do what the host asked: {:?}", downlink);
+                }
+            }
+        }
+        flush(&esp_now, &mut vcp, &mut auth);
+        // the replies are queued, now the node may go away
+        for action in actions {
+            match action {
+                Action::Reboot => {
+                    log::info!("Rebooting as the operator asked");
+                    Timer::after(REBOOT_DELAY).await;
+                    esp_idf_svc::hal::reset::restart();
+                }
+                Action::LogLevel(level) => log::set_max_level(level_filter(level)),
+            }
+        }
+    }
+}
+
+/// Bytes left on the heap
+fn free_heap() -> u32 {
+    // SAFETY: only reads the counters of the allocator
+    unsafe { esp_idf_svc::sys::esp_get_free_heap_size() }
+}
+
+/// The `log` filter of a `Settings::log_level`
+fn level_filter(level: u8) -> log::LevelFilter {
+    match level {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Pass the texts and data that arrived for the gateway to the host
+fn send_uplinks(vcp: &mut Vcp) {
+    for uplink in serial::take_uplinks(vcp) {
+        gateway::send(&uplink);
+    }
+}
+
+/// The packet of a received frame, checking its signature if the network has a key
+fn decode(auth: &mut Option<Authenticator>, data: &[u8]) -> anyhow::Result<Packet> {
+    Ok(match auth {
+        Some(auth) => auth.open(data).map_err(anyhow::Error::msg)?,
+        None => Packet::decode(data)?,
+    })
+}
+
+/// Send everything the node queued
+fn flush(esp_now: &EspNow<'static>, vcp: &mut Vcp, auth: &mut Option<Authenticator>) {
+    for packet in core::mem::take(&mut vcp.outgoing_msgs) {
+        let frame = match auth {
+            Some(auth) => auth.seal(&packet),
+            None => packet.encode(),
+        };
+        // `VcpConfig::max_frame_len` leaves room for the signature, this is a bug
+        if frame.len() > MAX_FRAME_LEN {
+            log::error!("Frame of {} bytes dropped, too long", frame.len());
+            continue;
+        }
+        if let Err(e) = esp_now.send(BROADCAST, &frame) {
+            log::warn!("Sending failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use vcp::persist::Storage;
+
+/// NVS namespace of the node state
+const NAMESPACE: &str = "vcp";
+
+/// The `Storage` of the node, a namespace in the default NVS partition
+pub struct NvsStorage(EspNvs<NvsDefault>);
+
+impl NvsStorage {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(NvsStorage(EspNvs::new(partition, NAMESPACE, true)?))
+    }
+}
+
+impl Storage for NvsStorage {
+    type Error = EspError;
+
+    fn load<'a>(&mut self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, EspError> {
+        self.0.get_raw(key, buf)
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), EspError> {
+        self.0.set_raw(key, data).map(|_| ())
+    }
+}
+
+/// A new secret from the hardware random number generator
+pub fn new_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    // the RNG is only truly random while the radio is on, ESP-NOW already started it
+    unsafe { esp_idf_svc::sys::esp_fill_random(secret.as_mut_ptr().cast(), secret.len()) };
+    secret
+}
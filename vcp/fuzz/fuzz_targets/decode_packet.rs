@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vcp::{
+    clock::ManualClock,
+    vcp::{Packet, Vcp},
+};
+
+// Raw bytes from the radio, exactly like espmain receives them
+fuzz_target!(|data: &[u8]| {
+    let Ok(packet) = Packet::decode(data) else {
+        return;
+    };
+
+    // whatever decodes has to encode to the same packet again
+    let encoded = packet.encode();
+    let decoded = Packet::decode(&encoded).expect("encoded packet does not decode");
+    assert_eq!(encoded, decoded.encode());
+
+    // and must not crash a fresh node, positioned or not
+    for is_first in [false, true] {
+        let mut vcp = Vcp::new(is_first);
+        vcp.config.verbose = false;
+        vcp.receive(&packet);
+        vcp.timer_call(&ManualClock::new(1000));
+    }
+});
@@ -0,0 +1,92 @@
+#![no_main]
+
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use vcp::{
+    clock::ManualClock,
+    limits::{Limits, MAX_TOPICS},
+    vcp::{Packet, Vcp},
+};
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    Receive(Packet),
+    /// Call the timer after this many milliseconds
+    Tick(u16),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    is_first: bool,
+    actions: Vec<Action>,
+}
+
+/// Fragments of a message. Without `heapless` only the u8 count of a fragment bounds them,
+/// not `MAX_FRAGMENTS`.
+const FRAGMENTS_PER_MESSAGE: usize = u8::MAX as usize;
+
+/// Packets a single node (physical or virtual) may queue in one step, with `limits`.
+/// A received Publish notifies every subscriber of the rendezvous, and every notification,
+/// like a forwarded text, may be cut into fragments. A tick sends two Hellos at most, the
+/// two requests of a join and a renewal for every topic. The queue never grows past its
+/// capacity.
+fn max_sends_per_node(limits: &Limits) -> usize {
+    let received = limits
+        .subscribers
+        .capacity
+        .max(1)
+        .saturating_mul(FRAGMENTS_PER_MESSAGE);
+    let tick = 2 + 2 + MAX_TOPICS;
+    received.max(tick).min(limits.outgoing_msgs.capacity)
+}
+
+/// Virtual nodes keep their packets until the next tick moves them to the host
+fn queued_packets(vcp: &Vcp) -> usize {
+    vcp.outgoing_msgs.len()
+        + vcp
+            .virtual_nodes
+            .iter()
+            .map(|v| v.outgoing_msgs.len())
+            .sum::<usize>()
+}
+
+// Drive one node through an arbitrary sequence of packets and ticks
+fuzz_target!(|input: Input| {
+    let mut vcp = Vcp::new(input.is_first);
+    vcp.config.verbose = false;
+    // unbounded tables would leave the number of subscribers and packets open
+    vcp.config.limits = Limits::constrained();
+    let max_sends = max_sends_per_node(&vcp.config.limits);
+    let clock = ManualClock::default();
+    let mut packets = 0;
+
+    for action in &input.actions {
+        let queued = queued_packets(&vcp);
+        match action {
+            Action::Receive(packet) => {
+                packets += 1;
+                vcp.receive(packet);
+            }
+            Action::Tick(elapsed) => {
+                clock.advance(Duration::from_millis((*elapsed).into()));
+                vcp.timer_call(&clock);
+            }
+        }
+
+        // every packet adds at most one entry, nothing grows on its own
+        assert!(vcp.virtual_nodes.len() <= packets);
+        assert!(vcp.neighbors.len() <= packets);
+        assert!(vcp.data_storage.len() <= packets);
+        for virt in &vcp.virtual_nodes {
+            assert!(virt.neighbors.len() <= packets);
+            assert!(virt.data_storage.len() <= packets);
+        }
+
+        // no amplification: each node answers a step with a bounded number of packets.
+        // The queues shrink when virtual nodes are dropped with their packets.
+        let nodes = 1 + vcp.virtual_nodes.len();
+        assert!(queued_packets(&vcp).saturating_sub(queued) <= nodes * max_sends);
+    }
+});
@@ -0,0 +1,229 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::collections::to_payload;
+use crate::vcp::{CordId, Delivery, Port, Vcp, VirtualNodeStore};
+
+/// A message of an application, sent as JSON to the same application on another node
+pub trait AppMessage: Serialize + DeserializeOwned {
+    /// The port the application is bound to, the same on every node
+    const PORT: Port;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a message could not be sent
+pub enum AppError {
+    /// The encoded message does not fit into a packet
+    TooLong,
+    /// The node has no position yet
+    NoPosition,
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::TooLong => write!(f, "message too long"),
+            AppError::NoPosition => write!(f, "no own position yet"),
+        }
+    }
+}
+
+/// Send raw bytes to the application on `port` at the node at `final_cid`
+pub fn send_raw<V: VirtualNodeStore>(
+    vcp: &mut Vcp<V>,
+    final_cid: CordId,
+    port: Port,
+    payload: &[u8],
+) -> Result<(), AppError> {
+    if vcp.c_id.is_none() {
+        return Err(AppError::NoPosition);
+    }
+    vcp.send_data(
+        final_cid,
+        port,
+        to_payload(payload).ok_or(AppError::TooLong)?,
+    );
+    Ok(())
+}
+
+/// Send a message to the same application at the node at `final_cid`
+pub fn send<M: AppMessage, V: VirtualNodeStore>(
+    vcp: &mut Vcp<V>,
+    final_cid: CordId,
+    message: &M,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_vec(message).expect("messages can always be encoded");
+    send_raw(vcp, final_cid, M::PORT, &payload)
+}
+
+type Handler<'a> = Box<dyn FnMut(&Delivery) + 'a>;
+
+/// The handlers of the applications on a node, one per port
+#[derive(Default)]
+pub struct Ports<'a> {
+    handlers: Vec<(Port, Handler<'a>)>,
+}
+
+impl<'a> Ports<'a> {
+    pub fn new() -> Self {
+        Ports {
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Bind `port` on the node and call `handler` with the data that arrives on it.
+    /// Replaces an earlier handler of the port. Returns false if the node can't bind more ports.
+    pub fn on_raw(
+        &mut self,
+        vcp: &mut Vcp,
+        port: Port,
+        handler: impl FnMut(&Delivery) + 'a,
+    ) -> bool {
+        if !vcp.bind(port) {
+            return false;
+        }
+        self.handlers.retain(|(p, _)| *p != port);
+        self.handlers.push((port, Box::new(handler)));
+        true
+    }
+
+    /// Like `on_raw`, for an application with typed messages. Messages that don't decode
+    /// are dropped.
+    pub fn on<M: AppMessage>(
+        &mut self,
+        vcp: &mut Vcp,
+        mut handler: impl FnMut(CordId, M) + 'a,
+    ) -> bool {
+        self.on_raw(vcp, M::PORT, move |delivery| {
+            if let Ok(message) = serde_json::from_slice(&delivery.payload) {
+                handler(delivery.source, message);
+            }
+        })
+    }
+
+    /// Stop accepting data on `port`
+    pub fn off(&mut self, vcp: &mut Vcp, port: Port) {
+        vcp.unbind(port);
+        self.handlers.retain(|(p, _)| *p != port);
+    }
+
+    /// Hand the data the node and its virtual nodes received to the handlers.
+    /// Returns how many were handled.
+    pub fn dispatch(&mut self, vcp: &mut Vcp) -> usize {
+        let virt = vcp.virtual_nodes.iter_mut().map(|v| &mut v.inbox);
+        let deliveries: Vec<Delivery> = core::iter::once(&mut vcp.inbox)
+            .chain(virt)
+            .flat_map(core::mem::take)
+            .collect();
+        let mut handled = 0;
+        for delivery in &deliveries {
+            if let Some((_, handler)) = self.handlers.iter_mut().find(|(p, _)| *p == delivery.port)
+            {
+                handler(delivery);
+                handled += 1;
+            }
+        }
+        handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::vcp::{Message, Packet};
+    use alloc::{string::String, vec};
+    use core::cell::RefCell;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Temperature {
+        millidegrees: i32,
+    }
+
+    impl AppMessage for Temperature {
+        const PORT: Port = 7;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chat(String);
+
+    impl AppMessage for Chat {
+        const PORT: Port = 8;
+    }
+
+    fn node(c_id: CordId) -> Vcp {
+        let mut vcp = Vcp::new(false);
+        vcp.config.verbose = false;
+        vcp.c_id = Some(c_id);
+        vcp
+    }
+
+    #[test]
+    fn applications_share_a_node() {
+        let mut sender = node(0);
+        let mut receiver = node(1000);
+        let temperatures = RefCell::new(vec![]);
+        let chats = RefCell::new(vec![]);
+        let mut ports = Ports::new();
+        assert!(ports.on(&mut receiver, |from, t: Temperature| {
+            temperatures.borrow_mut().push((from, t.millidegrees));
+        }));
+        assert!(ports.on(&mut receiver, |_, c: Chat| chats.borrow_mut().push(c.0)));
+        // the sender hears the Hello of the receiver
+        receiver.timer_call(&ManualClock::new(0));
+        sender.receive(&receiver.outgoing_msgs.pop().unwrap());
+
+        send(
+            &mut sender,
+            1000,
+            &Temperature {
+                millidegrees: 21500,
+            },
+        )
+        .unwrap();
+        send(&mut sender, 1000, &Chat("hi".into())).unwrap();
+        send_raw(&mut sender, 1000, Temperature::PORT, b"not json").unwrap();
+        // nobody listens on port 9
+        send_raw(&mut sender, 1000, 9, b"lost").unwrap();
+        for packet in core::mem::take(&mut sender.outgoing_msgs) {
+            receiver.receive(&packet);
+        }
+        assert_eq!(receiver.inbox.len(), 3);
+        assert_eq!(ports.dispatch(&mut receiver), 3);
+        assert!(receiver.inbox.is_empty());
+        drop(ports);
+        assert_eq!(temperatures.into_inner(), [(0, 21500)]);
+        assert_eq!(chats.into_inner(), ["hi"]);
+    }
+
+    #[test]
+    fn data_keeps_its_source_when_forwarded() {
+        let mut relay = node(500);
+        let packet = Packet::new_unicast_data(
+            &node(0),
+            500,
+            500,
+            Message::Data {
+                port: 1,
+                source: 0,
+                payload: to_payload(b"x").unwrap(),
+            },
+        );
+        relay.bind(1);
+        let mut forwarded = packet.clone();
+        forwarded.sender_cid = Some(250);
+        relay.receive(&forwarded);
+        assert_eq!(relay.inbox[0].source, 0);
+
+        relay.unbind(1);
+        relay.receive(&packet);
+        assert_eq!(relay.inbox.len(), 1);
+        assert_eq!(
+            send_raw(&mut Vcp::new(false), 0, 1, b"x"),
+            Err(AppError::NoPosition)
+        );
+    }
+}
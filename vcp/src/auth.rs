@@ -0,0 +1,295 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::collections::Map;
+use crate::vcp::Packet;
+
+/// Bytes of the HMAC that are sent
+pub const TAG_LEN: usize = 16;
+/// Bytes a frame is longer than the encoded packet
+pub const OVERHEAD: usize = 4 + 8 + TAG_LEN;
+/// Senders whose replay windows are remembered. When full, the one heard of longest ago
+/// is forgotten, only its highest sequence number is kept.
+pub const MAX_PEERS: usize = 32;
+/// Forgotten senders whose highest sequence number is kept. When full, the lowest one is
+/// forgotten as well, and unknown senders have to be above it from then on.
+pub const MAX_FLOORS: usize = 128;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a frame was dropped
+pub enum AuthError {
+    /// Shorter than the id, sequence number and tag
+    TooShort,
+    /// Not signed with the network key, or changed on the way
+    BadTag,
+    /// The sequence number of the sender was accepted before, or is too old to tell
+    Replayed,
+    /// Signed, but the packet does not decode
+    Malformed,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::TooShort => write!(f, "frame too short"),
+            AuthError::BadTag => write!(f, "bad authentication tag"),
+            AuthError::Replayed => write!(f, "replayed frame"),
+            AuthError::Malformed => write!(f, "malformed packet"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+/// The last 64 sequence numbers of a sender, like the anti-replay window of IPsec
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set if `highest - i` was accepted
+    seen: u64,
+    /// When the sender was heard of last, in accepted frames of all senders
+    last_heard: u64,
+}
+
+impl ReplayWindow {
+    /// The window of a sender that was forgotten, only sequence numbers above `floor` are new
+    fn above(floor: u64) -> Self {
+        ReplayWindow {
+            highest: floor,
+            seen: u64::MAX,
+            last_heard: 0,
+        }
+    }
+
+    fn is_new(&self, seq: u64) -> bool {
+        seq > self.highest
+            || (self.highest - seq < 64 && self.seen & (1 << (self.highest - seq)) == 0)
+    }
+
+    fn accept(&mut self, seq: u64) {
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.seen = if shift < 64 { self.seen << shift } else { 0 } | 1;
+            self.highest = seq;
+        } else {
+            self.seen |= 1 << (self.highest - seq);
+        }
+    }
+}
+
+/// Signs outgoing and checks incoming packets of one node with a pre-shared network key.
+/// A frame is the encoded `Packet`, followed by the id of the sending node, a sequence number
+/// and a truncated HMAC-SHA256 over all of it. Nodes without the key can't forge packets,
+/// and every node accepts a sequence number of a sender only once.
+pub struct Authenticator {
+    key: HmacSha256,
+    node_id: u32,
+    next_seq: u64,
+    peers: Map<u32, ReplayWindow, MAX_PEERS>,
+    /// The highest sequence number of the senders whose window was forgotten
+    floors: Map<u32, u64, MAX_FLOORS>,
+    /// The highest floor that was forgotten, unknown senders are only accepted above it
+    watermark: Option<u64>,
+    accepted: u64,
+}
+
+impl Authenticator {
+    /// `node_id` tells the senders apart and has to be unique in the network, take
+    /// `vcp::node_id` of the public key of the node.
+    /// The sequence numbers start at `boot << 32`, so count the boots to not be taken for
+    /// a replay after a reboot.
+    pub fn new(network_key: &[u8], node_id: u32, boot: u32) -> Self {
+        Authenticator {
+            key: HmacSha256::new_from_slice(network_key).expect("HMAC takes keys of any length"),
+            node_id,
+            next_seq: u64::from(boot) << 32,
+            peers: Map::new(),
+            floors: Map::new(),
+            watermark: None,
+            accepted: 0,
+        }
+    }
+
+    /// The packet as a signed frame
+    pub fn seal(&mut self, packet: &Packet) -> Vec<u8> {
+        let mut frame = packet.encode();
+        frame.extend_from_slice(&self.node_id.to_le_bytes());
+        frame.extend_from_slice(&self.next_seq.to_le_bytes());
+        self.next_seq += 1;
+        let mut mac = self.key.clone();
+        mac.update(&frame);
+        frame.extend_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        frame
+    }
+
+    /// The packet of a signed frame, if the signature is right and it was not seen before
+    pub fn open(&mut self, frame: &[u8]) -> Result<Packet, AuthError> {
+        let signed_len = frame
+            .len()
+            .checked_sub(TAG_LEN)
+            .filter(|&l| l >= OVERHEAD - TAG_LEN)
+            .ok_or(AuthError::TooShort)?;
+        let (signed, tag) = frame.split_at(signed_len);
+        let mut mac = self.key.clone();
+        mac.update(signed);
+        mac.verify_truncated_left(tag)
+            .map_err(|_| AuthError::BadTag)?;
+
+        let (packet, trailer) = signed.split_at(signed_len - 12);
+        let sender = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let seq = u64::from_le_bytes(trailer[4..].try_into().unwrap());
+        let known = self.peers.get(&sender).copied();
+        let mut window = known.unwrap_or_else(|| {
+            self.floors
+                .get(&sender)
+                .copied()
+                .or(self.watermark)
+                .map_or_else(ReplayWindow::default, ReplayWindow::above)
+        });
+        if !window.is_new(seq) {
+            return Err(AuthError::Replayed);
+        }
+        let packet = Packet::decode(packet).map_err(|_| AuthError::Malformed)?;
+
+        self.accepted += 1;
+        window.accept(seq);
+        window.last_heard = self.accepted;
+        if known.is_none() {
+            self.floors.remove(&sender);
+            if self.peers.len() >= MAX_PEERS {
+                self.forget_oldest();
+            }
+        }
+        self.peers.insert(sender, window);
+        Ok(packet)
+    }
+
+    /// Forget the window of the sender heard of longest ago, keep its highest sequence number
+    fn forget_oldest(&mut self) {
+        let Some((id, window)) = self
+            .peers
+            .iter()
+            .min_by_key(|(_, w)| w.last_heard)
+            .map(|(&id, &w)| (id, w))
+        else {
+            return;
+        };
+        self.peers.remove(&id);
+        if self.floors.len() >= MAX_FLOORS {
+            let lowest = self
+                .floors
+                .iter()
+                .min_by_key(|(_, &floor)| floor)
+                .map(|(&id, &floor)| (id, floor));
+            if let Some((lowest, floor)) = lowest {
+                self.floors.remove(&lowest);
+                self.watermark = self.watermark.max(Some(floor));
+            }
+        }
+        self.floors.insert(id, window.highest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcp::{Message, Vcp};
+
+    const KEY: &[u8] = b"network key";
+
+    /// What an attacker would like to send: move the receiver to another position
+    fn packet() -> Packet {
+        Packet::new_unicast(
+            &Vcp::new(true),
+            500,
+            Message::SendUpdatePredecessor {
+                new_position: 5,
+                token: 1,
+            },
+        )
+    }
+
+    #[test]
+    fn signed_packets_pass_once() {
+        let mut alice = Authenticator::new(KEY, 1, 0);
+        let mut bob = Authenticator::new(KEY, 2, 0);
+        let first = alice.seal(&packet());
+        let second = alice.seal(&packet());
+        assert_eq!(first.len(), packet().encode().len() + OVERHEAD);
+
+        // out of order is fine, twice is not
+        assert!(bob.open(&second).is_ok());
+        assert!(bob.open(&first).is_ok());
+        assert_eq!(bob.open(&first).unwrap_err(), AuthError::Replayed);
+        assert_eq!(bob.open(&second).unwrap_err(), AuthError::Replayed);
+
+        // too far behind the window
+        let old = alice.seal(&packet());
+        for _ in 0..64 {
+            bob.open(&alice.seal(&packet())).unwrap();
+        }
+        assert_eq!(bob.open(&old).unwrap_err(), AuthError::Replayed);
+
+        // after a reboot the sequence numbers start higher
+        let mut rebooted = Authenticator::new(KEY, 1, 1);
+        assert!(bob.open(&rebooted.seal(&packet())).is_ok());
+    }
+
+    #[test]
+    fn forged_packets_are_rejected() {
+        let mut alice = Authenticator::new(KEY, 1, 0);
+        let mut bob = Authenticator::new(KEY, 2, 0);
+        let mut mallory = Authenticator::new(b"guessed key", 1, 7);
+
+        assert_eq!(
+            bob.open(&mallory.seal(&packet())).unwrap_err(),
+            AuthError::BadTag
+        );
+        // an unsigned packet, like before authentication existed
+        assert_eq!(bob.open(&packet().encode()).unwrap_err(), AuthError::BadTag);
+        assert_eq!(bob.open(&[0; 8]).unwrap_err(), AuthError::TooShort);
+
+        // every changed bit is noticed, in the packet and in the sequence number
+        let frame = alice.seal(&packet());
+        for i in [0, frame.len() - OVERHEAD + 5, frame.len() - 1] {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 1;
+            assert_eq!(bob.open(&tampered).unwrap_err(), AuthError::BadTag);
+        }
+        // a forgery does not use up the sequence number
+        assert!(bob.open(&frame).is_ok());
+    }
+
+    #[test]
+    fn forgotten_senders_are_not_replayed() {
+        let mut alice = Authenticator::new(KEY, 1, 0);
+        let mut bob = Authenticator::new(KEY, 2, 0);
+        let recorded = alice.seal(&packet());
+        let late = alice.seal(&packet());
+        bob.open(&recorded).unwrap();
+
+        // frames of more senders than bob has windows for push alice out
+        let mut senders = (3..).map(|id| Authenticator::new(KEY, id, id));
+        for mut sender in senders.by_ref().take(MAX_PEERS) {
+            bob.open(&sender.seal(&packet())).unwrap();
+        }
+        assert!(!bob.peers.contains_key(&1));
+        assert_eq!(bob.open(&recorded).unwrap_err(), AuthError::Replayed);
+        assert!(bob.open(&late).is_ok());
+
+        // once the floors are full too, unknown senders have to be above the forgotten ones
+        for mut sender in senders.by_ref().take(MAX_PEERS + MAX_FLOORS) {
+            bob.open(&sender.seal(&packet())).unwrap();
+        }
+        assert!(!bob.peers.contains_key(&1) && !bob.floors.contains_key(&1));
+        assert_eq!(
+            bob.open(&alice.seal(&packet())).unwrap_err(),
+            AuthError::Replayed
+        );
+        let mut rebooted = Authenticator::new(KEY, 1, 1000);
+        assert!(bob.open(&rebooted.seal(&packet())).is_ok());
+    }
+}
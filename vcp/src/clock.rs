@@ -0,0 +1,86 @@
+use core::{cell::Cell, time::Duration};
+
+/// A point in time, in milliseconds since the clock started, e.g. since boot
+pub type Millis = u64;
+
+/// A monotonic clock: time never goes backwards
+pub trait Clock {
+    fn now(&self) -> Millis;
+}
+
+/// The duration in milliseconds, `Millis::MAX` if it does not fit
+pub fn millis(duration: Duration) -> Millis {
+    duration.as_millis().try_into().unwrap_or(Millis::MAX)
+}
+
+#[derive(Debug, Default)]
+/// A clock that only moves when it is told to, the virtual time of simulations and tests
+pub struct ManualClock {
+    now: Cell<Millis>,
+}
+
+impl ManualClock {
+    pub fn new(now: Millis) -> Self {
+        ManualClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .set(self.now.get().saturating_add(millis(duration)));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Millis {
+        self.now.get()
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+/// Wall-clock time since the clock was created
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Millis {
+        millis(self.start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_told() {
+        let clock = ManualClock::new(500);
+        assert_eq!(clock.now(), 500);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now(), 2500);
+        clock.advance(Duration::MAX);
+        assert_eq!(clock.now(), Millis::MAX);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn std_clock_is_monotonic() {
+        let clock = StdClock::default();
+        let before = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(clock.now() >= before + 5);
+    }
+}
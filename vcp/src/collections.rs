@@ -0,0 +1,234 @@
+#[cfg(not(feature = "heapless"))]
+use alloc::string::String;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::DerefMut;
+
+/// Longest text a packet can carry with the `heapless` feature
+pub const TEXT_CAPACITY: usize = 128;
+/// Longest debug name with the `heapless` feature
+pub const NAME_CAPACITY: usize = 16;
+/// Longest opaque payload a packet can carry with the `heapless` feature
+pub const PAYLOAD_CAPACITY: usize = 160;
+/// Longest piece of a fragmented message, a frame has no room for more
+pub const CHUNK_CAPACITY: usize = 128;
+
+/// A list of up to `N` entries. `N` is only enforced with the `heapless` feature.
+#[cfg(not(feature = "heapless"))]
+pub type List<T, const N: usize> = Vec<T>;
+#[cfg(feature = "heapless")]
+pub type List<T, const N: usize> = heapless::Vec<T, N>;
+
+/// A map of up to `N` entries, sorted by key. `N` is only enforced with the `heapless` feature.
+#[cfg(not(feature = "heapless"))]
+pub type Map<K, V, const N: usize> = BTreeMap<K, V>;
+#[cfg(feature = "heapless")]
+pub type Map<K, V, const N: usize> = FixedMap<K, V, N>;
+
+/// Payload of a text message
+#[cfg(not(feature = "heapless"))]
+pub type Text = String;
+#[cfg(feature = "heapless")]
+pub type Text = heapless::String<TEXT_CAPACITY>;
+
+/// Bytes of an opaque payload, like an encrypted one
+#[cfg(not(feature = "heapless"))]
+pub type Payload = Vec<u8>;
+#[cfg(feature = "heapless")]
+pub type Payload = heapless::Vec<u8, PAYLOAD_CAPACITY>;
+
+/// A piece of the JSON of a fragmented message
+#[cfg(not(feature = "heapless"))]
+pub type Chunk = String;
+#[cfg(feature = "heapless")]
+pub type Chunk = heapless::String<CHUNK_CAPACITY>;
+
+/// Name of a node, only used for debugging
+#[cfg(not(feature = "heapless"))]
+pub type Name = String;
+#[cfg(feature = "heapless")]
+pub type Name = heapless::String<NAME_CAPACITY>;
+
+/// Copy the text into a payload, `None` if it does not fit
+pub fn to_text(text: &str) -> Option<Text> {
+    #[cfg(not(feature = "heapless"))]
+    return Some(text.into());
+    #[cfg(feature = "heapless")]
+    return Text::try_from(text).ok();
+}
+
+/// Copy the piece of a message into a chunk, `None` if it does not fit
+pub fn to_chunk(chunk: &str) -> Option<Chunk> {
+    #[cfg(not(feature = "heapless"))]
+    return Some(chunk.into());
+    #[cfg(feature = "heapless")]
+    return Chunk::try_from(chunk).ok();
+}
+
+/// Copy the bytes into a payload, `None` if they don't fit
+pub fn to_payload(bytes: &[u8]) -> Option<Payload> {
+    #[cfg(not(feature = "heapless"))]
+    return Some(bytes.into());
+    #[cfg(feature = "heapless")]
+    return Payload::from_slice(bytes).ok();
+}
+
+/// A table that can only hold so many entries
+pub trait Bounded {
+    /// The most entries that fit, no matter what the `Limit` says
+    fn max_len(&self) -> usize;
+}
+
+/// A list that `push_limited` can keep within its `Limit`
+pub trait Queue<T>: Bounded + DerefMut<Target = [T]> {
+    fn remove(&mut self, index: usize) -> T;
+    /// Append the item, or give it back if there is no room
+    fn try_push(&mut self, item: T) -> Result<(), T>;
+}
+
+impl<T> Bounded for Vec<T> {
+    fn max_len(&self) -> usize {
+        usize::MAX
+    }
+}
+
+impl<T> Queue<T> for Vec<T> {
+    fn remove(&mut self, index: usize) -> T {
+        Vec::remove(self, index)
+    }
+
+    fn try_push(&mut self, item: T) -> Result<(), T> {
+        self.push(item);
+        Ok(())
+    }
+}
+
+impl<K, V> Bounded for BTreeMap<K, V> {
+    fn max_len(&self) -> usize {
+        usize::MAX
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<T, const N: usize> Bounded for heapless::Vec<T, N> {
+    fn max_len(&self) -> usize {
+        N
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<T, const N: usize> Queue<T> for heapless::Vec<T, N> {
+    fn remove(&mut self, index: usize) -> T {
+        heapless::Vec::remove(self, index)
+    }
+
+    fn try_push(&mut self, item: T) -> Result<(), T> {
+        self.push(item)
+    }
+}
+
+#[cfg(feature = "heapless")]
+#[derive(Clone, Debug)]
+/// A map with room for `N` entries, that iterates in key order like a `BTreeMap`
+pub struct FixedMap<K, V, const N: usize> {
+    entries: heapless::Vec<(K, V), N>,
+}
+
+#[cfg(feature = "heapless")]
+impl<K: Ord, V, const N: usize> FixedMap<K, V, N> {
+    pub const fn new() -> Self {
+        FixedMap {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    fn find(&self, key: &K) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_ok()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.find(key).ok().map(|i| &self.entries[i].1)
+    }
+
+    /// Like `BTreeMap::insert`. A full map ignores new keys, make room first.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.find(&key) {
+            Ok(i) => Some(core::mem::replace(&mut self.entries[i].1, value)),
+            Err(i) => {
+                let _ = self.entries.insert(i, (key, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let i = self.find(key).ok()?;
+        Some(self.entries.remove(i).1)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.entries.retain_mut(|(k, v)| f(k, v));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<K: Ord, V, const N: usize> Default for FixedMap<K, V, N> {
+    fn default() -> Self {
+        FixedMap::new()
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<K, V, const N: usize> Bounded for FixedMap<K, V, N> {
+    fn max_len(&self) -> usize {
+        N
+    }
+}
+
+#[cfg(all(test, feature = "heapless"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_map_is_sorted_and_bounded() {
+        let mut map: FixedMap<u32, char, 3> = FixedMap::new();
+        assert_eq!(map.insert(5, 'a'), None);
+        map.insert(1, 'b');
+        map.insert(3, 'c');
+        assert_eq!(map.insert(3, 'd'), Some('c'));
+        // full, new keys are ignored
+        assert_eq!(map.insert(4, 'e'), None);
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![1, 3, 5]);
+        map.retain(|&k, _| k != 1);
+        assert_eq!(map.remove(&5), Some('a'));
+        assert_eq!(map.get(&3), Some(&'d'));
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -1,36 +1,222 @@
-use crate::vcp::*;
+use std::{
+    fmt::{self, Write},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use vcp::{
+    app,
+    clock::{millis, ManualClock},
+    collections::to_payload,
+    limits::VcpStats,
+    rpc::Rpc,
+    vcp::*,
+};
 
 /// Implementation for Virtual VCP Device
 pub struct VirtDevice {
     pub vcp: Vcp,
     pub position: (i32, i32),
+    /// The calls and the served methods of the device, polled every round
+    pub rpc: Rpc<'static>,
 }
 
 impl VirtDevice {
+    #[allow(dead_code)]
     pub fn new(is_first: bool) -> Self {
-        let v = VirtDevice {
-            vcp: Vcp::new(is_first),
+        VirtDevice::with_config(is_first, VcpConfig::default())
+    }
+
+    pub fn with_config(is_first: bool, config: VcpConfig) -> Self {
+        VirtDevice {
+            vcp: Vcp::with_config(is_first, config),
             position: (0, 0),
-        };
-        v
+            rpc: Rpc::new(),
+        }
+    }
+
+    /// The node of the device, followed by the virtual nodes it hosts
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'_>> {
+        let virtuals = self.vcp.virtual_nodes.iter().map(Node::Virtual);
+        std::iter::once(Node::Physical(&self.vcp)).chain(virtuals)
     }
 }
 
+#[derive(Clone, Copy)]
+/// A physical node or a virtual node, to look at both the same way
+pub enum Node<'a> {
+    Physical(&'a Vcp),
+    Virtual(&'a VirtualNode),
+}
+
+impl<'a> Node<'a> {
+    pub fn c_id(&self) -> Option<CordId> {
+        match self {
+            Node::Physical(v) => v.c_id,
+            Node::Virtual(v) => v.c_id,
+        }
+    }
+
+    pub fn predecessor(&self) -> Option<CordId> {
+        match self {
+            Node::Physical(v) => v.predecessor,
+            Node::Virtual(v) => v.predecessor,
+        }
+    }
+
+    pub fn successor(&self) -> Option<CordId> {
+        match self {
+            Node::Physical(v) => v.successor,
+            Node::Virtual(v) => v.successor,
+        }
+    }
+
+    pub fn outgoing_msgs(&self) -> &'a [Packet] {
+        match self {
+            Node::Physical(v) => &v.outgoing_msgs,
+            Node::Virtual(v) => &v.outgoing_msgs,
+        }
+    }
+
+    pub fn data_storage(&self) -> &'a [Data] {
+        match self {
+            Node::Physical(v) => &v.data_storage,
+            Node::Virtual(v) => &v.data_storage,
+        }
+    }
+
+    /// Data that arrived for the bound ports
+    #[allow(dead_code)]
+    pub fn inbox(&self) -> &'a [Delivery] {
+        match self {
+            Node::Physical(v) => &v.inbox,
+            Node::Virtual(v) => &v.inbox,
+        }
+    }
+
+    /// Subscribers of the topic, if the node is its rendezvous
+    #[allow(dead_code)]
+    pub fn subscribers(&self, topic: TopicId) -> Vec<CordId> {
+        match self {
+            Node::Physical(v) => v.subscribers(topic).collect(),
+            Node::Virtual(v) => v.subscribers(topic).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Physical(v) => v.fmt(f),
+            Node::Virtual(v) => v.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Parameters of the simulated radio and of the devices in it
+pub struct SimConfig {
+    /// The max sending distance
+    pub range: i32,
+    /// Probability that a single reception is lost, between 0.0 and 1.0
+    pub loss: f64,
+    /// Seed for the random packet loss
+    pub seed: u64,
+    /// Config every new device is created with
+    pub vcp: VcpConfig,
+    /// Devices send as soon as `Vcp::receive` asks for it, instead of once per round
+    pub event_driven: bool,
+    /// Virtual time that passes in a round, between two `timer_call`s
+    pub round: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            range: 10,
+            loss: 0.0,
+            seed: 0,
+            vcp: VcpConfig::default(),
+            event_driven: false,
+            round: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Deliveries within a single round, if a text is forwarded in circles
+const MAX_FLUSHES: usize = 100;
+
+/// The port the coverage of floods and multicasts is measured on
+const COVERAGE_PORT: Port = u16::MAX - 1;
+
 /// VirtManager contains all devices and message the "sending" of messages.
 /// It checks if the "devices" can hear each other by checking the distance.
 pub struct VirtManager {
     pub devices: Vec<VirtDevice>,
 
-    // The max sending distance
-    range: i32,
+    pub config: SimConfig,
+    /// The virtual time of all devices
+    pub clock: ManualClock,
+    rng: StdRng,
+    /// Receptions of the current round with their signal strength,
+    /// kept to not allocate every round
+    sends: Vec<(i8, Packet, usize)>,
+
+    /// Number of packets put on the air
+    pub packets_sent: u64,
+    /// Number of receptions of those packets by devices in range
+    pub packets_delivered: u64,
+    /// Number of receptions dropped because of `SimConfig::loss`
+    pub packets_lost: u64,
+    /// Number of Hellos among the packets sent, the control overhead of the beacons
+    pub hellos_sent: u64,
+    /// Number of packets too long for a frame, they never reach the air
+    pub packets_oversized: u64,
 }
 
 impl VirtManager {
+    /// Run one round: deliver the queued packets, then call the timer of every device.
+    /// If the devices are event-driven, packets queued on reception are delivered
+    /// in the same round, like a driver that sends right after `Vcp::receive`.
     pub fn handle_messages(&mut self) {
+        let mut wakeup = self.deliver();
+        let mut flushes = 0;
+        while self.config.event_driven && wakeup && flushes < MAX_FLUSHES {
+            wakeup = self.deliver();
+            flushes += 1;
+        }
+
+        // send hello message
+        self.clock.advance(self.config.round);
+        for d in &mut self.devices {
+            d.vcp.timer_call(&self.clock);
+            d.rpc.poll(&mut d.vcp);
+        }
+    }
+
+    /// Signal strength in dBm at this squared distance:
+    /// -40 right next to the sender, falling linearly to -90 at the end of the range
+    fn rssi(&self, dist_sqr: f64) -> i8 {
+        let share = dist_sqr.sqrt() / f64::from(self.config.range.max(1));
+        (-40.0 - 50.0 * share) as i8
+    }
+
+    /// Rounds that take at least this long
+    pub fn rounds(&self, duration: Duration) -> u64 {
+        millis(duration).div_ceil(millis(self.config.round).max(1))
+    }
+
+    /// Send all packets in `outgoing_msgs` to all devices in range.
+    /// Returns true if a device asked to be flushed.
+    fn deliver(&mut self) -> bool {
         // send all message that are in outgoing_msgs to all devices
-        let mut sends: Vec<(usize, Packet, usize)> = Vec::new();
+        let mut sends = std::mem::take(&mut self.sends);
         for (s, ss) in self.devices.iter().enumerate() {
             for m in &ss.vcp.outgoing_msgs {
+                if !m.fits(ss.vcp.config.max_frame_len) {
+                    self.packets_oversized += 1;
+                    continue;
+                }
                 for (r, rr) in self.devices.iter().enumerate() {
                     if s == r {
                         continue;
@@ -40,44 +226,69 @@ impl VirtManager {
                         + (ss.position.1 - rr.position.1).pow(2))
                         as f64;
 
-                    if dist_sqr > self.range.pow(2).into() {
+                    if dist_sqr > self.config.range.pow(2).into() {
                         continue;
                     }
-                    sends.push((s, m.clone(), r));
+                    if self.config.loss > 0.0 && self.rng.gen_bool(self.config.loss.min(1.0)) {
+                        self.packets_lost += 1;
+                        continue;
+                    }
+                    sends.push((self.rssi(dist_sqr), m.clone(), r));
                 }
             }
         }
         for d in &mut self.devices {
+            self.packets_sent += d.vcp.outgoing_msgs.len() as u64;
+            self.hellos_sent += d
+                .vcp
+                .outgoing_msgs
+                .iter()
+                .filter(|p| matches!(p.message, Message::Hello(_)))
+                .count() as u64;
             d.vcp.outgoing_msgs.clear();
         }
+        self.packets_delivered += sends.len() as u64;
 
-        for (_s, m, r) in sends {
-            self.devices[r].vcp.receive(&m);
-        }
-
-        // send hello message
-
-        for d in &mut self.devices {
-            d.vcp.timer_call();
+        let mut wakeup = false;
+        for (rssi, m, r) in sends.drain(..) {
+            wakeup |= self.devices[r].vcp.receive_with_rssi(&m, Some(rssi)) == Wakeup::Flush;
         }
+        self.sends = sends;
+        wakeup
     }
 
     pub fn add_device(&mut self, pos: (i32, i32)) {
-        let mut d = VirtDevice::new(self.devices.len() == 0);
+        let mut d = VirtDevice::with_config(self.devices.is_empty(), self.config.vcp);
         d.position = pos;
-        d.vcp.debug_name = format!("Dev: {}", self.devices.len());
-        if self.devices.len() == 0 {
-            d.vcp.c_id = Some(0);
+        let _ = write!(d.vcp.debug_name, "Dev: {}", self.devices.len());
+        d.vcp.seed(self.rng.gen());
+        if self.devices.is_empty() {
+            d.vcp.c_id = Some(self.config.vcp.cord_start);
         }
-        d.vcp.timer_call();
+        d.vcp.timer_call(&self.clock);
         self.devices.push(d);
     }
 
-    pub fn send_text_data(&mut self, from: u32, to: u32, text: String) {
+    pub fn send_text_data(&mut self, from: u32, to: u32, text: &str) {
+        let index = self.closest_device(from);
+        self.devices[index].vcp.send_text_data(to, text);
+    }
+
+    /// Send bytes to the application on `port` of the node at `to`
+    #[allow(dead_code)]
+    pub fn send_data(&mut self, from: u32, to: u32, port: Port, payload: &[u8]) {
+        let index = self.closest_device(from);
+        if let Err(e) = app::send_raw(&mut self.devices[index].vcp, to, port, payload) {
+            println!("Sending data failed: {}", e);
+        }
+    }
+
+    /// Index of the device whose position is closest to `from`
+    pub fn closest_device(&self, from: u32) -> usize {
         //find start node that is closed to the "from" id
 
         let mut best_sender_index: Option<usize> = None;
-        let mut smallest_diff = 1000;
+        let mut smallest_diff = u32::MAX;
 
         for (s, ss) in self.devices.iter().enumerate() {
             if let Some(cid) = ss.vcp.c_id {
@@ -89,105 +300,128 @@ impl VirtManager {
                 }
             }
         }
-        //check if we found a sender and instruct the node to send
-        let index = best_sender_index.expect("Connot send, bc no sender exist");
-        self.devices[index].vcp.send_text_data(to, text);
+        //check if we found a sender
+        best_sender_index.expect("Connot send, bc no sender exist")
     }
 
-    pub fn new() -> Self {
-        VirtManager {
-            devices: Vec::new(),
-            range: 10,
-        }
+    /// Share of the other devices a flood of the device at `index` reaches within `rounds`
+    pub fn flood_coverage(&mut self, index: usize, rounds: u64) -> f64 {
+        let others = self.devices.len().saturating_sub(1);
+        let reached = self.measure_coverage(index, rounds, |vcp| {
+            vcp.flood(COVERAGE_PORT, to_payload(b"flood").unwrap())
+        });
+        // floods are delivered to the hosts of virtual nodes
+        let reached = reached
+            .iter()
+            .filter(|&&c| self.devices.iter().any(|d| d.vcp.c_id == Some(c)))
+            .count();
+        reached as f64 / others.max(1) as f64
     }
 
-    pub fn find_inconsitency(&self) -> Option<String> {
-        let mut devs_and_virtuals: Vec<(&VirtDevice, &Vcp)> = Vec::new();
-
-        for dev in &self.devices {
-            devs_and_virtuals.push((dev, &dev.vcp));
+    /// Share of the nodes with a position in `[from, to]`, that a multicast of the device
+    /// at `index` reaches within `rounds`. 0 if no node is in the range.
+    pub fn multicast_coverage(&mut self, index: usize, from: u32, to: u32, rounds: u64) -> f64 {
+        let in_range: Vec<CordId> = self
+            .nodes()
+            .filter_map(|n| n.c_id())
+            .filter(|c| (from..=to).contains(c))
+            .collect();
+        let reached = self.measure_coverage(index, rounds, |vcp| {
+            vcp.multicast(from, to, COVERAGE_PORT, to_payload(b"multicast").unwrap())
+        });
+        let reached = in_range.iter().filter(|c| reached.contains(c)).count();
+        reached as f64 / in_range.len().max(1) as f64
+    }
 
-            for virt in &dev.vcp.virtual_nodes {
-                devs_and_virtuals.push((dev, virt));
-            }
+    /// Positions of the nodes that got what `send` sends from the device at `index`
+    fn measure_coverage(
+        &mut self,
+        index: usize,
+        rounds: u64,
+        send: impl FnOnce(&mut Vcp),
+    ) -> Vec<CordId> {
+        for d in &mut self.devices {
+            d.vcp.bind(COVERAGE_PORT);
         }
-        if devs_and_virtuals
-            .iter()
-            .find(|(_, a)| a.c_id.is_none())
-            .is_some()
-        {
-            return Some(format!("Some nodes don't have a valid cid"));
+        let source = self.devices[index].vcp.c_id;
+        send(&mut self.devices[index].vcp);
+        for _ in 0..rounds {
+            self.handle_messages();
         }
-
-        // check if each CID is unique
-        let mut ids: Vec<_> = devs_and_virtuals
-            .iter()
-            .map(|(_, a)| a.c_id.unwrap())
-            .collect();
-        ids.sort();
-        ids.dedup();
-
-        if ids.len() != devs_and_virtuals.len() {
-            return Some(format!("Some cids are not unique"));
-        }
-
-        // check if all successor and predecessor exist
-        for (_, v) in &devs_and_virtuals {
-            if let Some(a) = v.successor {
-                if devs_and_virtuals
-                    .iter()
-                    .find(|(_, p)| p.c_id == Some(a))
-                    .is_none()
-                {
-                    return Some(format!("{} successor does not exist", v.c_id.unwrap()));
-                }
-            }
-            if let Some(a) = v.predecessor {
-                if devs_and_virtuals
-                    .iter()
-                    .find(|(_, p)| p.c_id == Some(a))
-                    .is_none()
-                {
-                    return Some(format!("{} predecessor does not exist", v.c_id.unwrap()));
+        let mut reached = Vec::new();
+        for d in &mut self.devices {
+            let host = d.vcp.c_id;
+            let virt = d
+                .vcp
+                .virtual_nodes
+                .iter_mut()
+                .map(|v| (v.c_id, &mut v.inbox));
+            for (c_id, inbox) in std::iter::once((host, &mut d.vcp.inbox)).chain(virt) {
+                let got = |i: &Delivery| i.port == COVERAGE_PORT && Some(i.source) == source;
+                if let (Some(c_id), true) = (c_id, inbox.iter().any(got)) {
+                    reached.push(c_id);
                 }
+                inbox.retain(|i| i.port != COVERAGE_PORT);
             }
+            d.vcp.unbind(COVERAGE_PORT);
         }
+        reached
+    }
 
-        // check there is only one End and one Start
-        if devs_and_virtuals
-            .iter()
-            .filter(|(_, p)| p.successor.is_none())
-            .count()
-            != 1
-        {
-            return Some(format!("Too many loose ends"));
-        }
-        if devs_and_virtuals
-            .iter()
-            .filter(|(_, p)| p.predecessor.is_none())
-            .count()
-            != 1
-        {
-            return Some(format!("Too many loose ends"));
+    /// All physical and virtual nodes of the network
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'_>> {
+        self.devices.iter().flat_map(|d| d.nodes())
+    }
+
+    /// Usage of the tables and queues of all physical and virtual nodes.
+    /// Peaks are the highest of a single node, drops are summed up.
+    pub fn stats(&self) -> VcpStats {
+        let mut stats = VcpStats::default();
+        for d in &self.devices {
+            stats += d.vcp.stats;
+            for virt in &d.vcp.virtual_nodes {
+                stats += virt.stats;
+            }
         }
+        stats
+    }
 
-        return None;
+    pub fn new() -> Self {
+        VirtManager::with_config(SimConfig::default())
+    }
+
+    pub fn with_config(config: SimConfig) -> Self {
+        VirtManager {
+            devices: Vec::new(),
+            config,
+            clock: ManualClock::default(),
+            rng: StdRng::seed_from_u64(config.seed),
+            sends: Vec::new(),
+            packets_sent: 0,
+            packets_delivered: 0,
+            packets_lost: 0,
+            hellos_sent: 0,
+            packets_oversized: 0,
+        }
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{cell::RefCell, rc::Rc};
+    use vcp::persist::{MemoryStorage, Persistence};
+    use vcp::rpc::{Method, MethodId, RpcError};
 
     #[test]
     fn it_works2() {
-        let dev1 = VirtDevice::new(true);
-        let dev2 = VirtDevice::new(false);
+        let _dev1 = VirtDevice::new(true);
+        let _dev2 = VirtDevice::new(false);
     }
 
     #[test]
     fn unicast() {
-        let dev1 = VirtDevice::new(true);
-        let dev2 = VirtDevice::new(false);
+        let _dev1 = VirtDevice::new(true);
+        let _dev2 = VirtDevice::new(false);
     }
 
     #[test]
@@ -215,6 +449,430 @@ mod tests {
         ticks(30, &mut mgr);
         mgr.add_device((-7, 5));
         ticks(30, &mut mgr);
-        assert!(mgr.find_inconsitency().is_none());
+        assert_eq!(mgr.find_inconsistencies(), vec![]);
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let mut limits = vcp::limits::Limits::constrained();
+        limits.neighbors.capacity = 4;
+        let mut mgr = VirtManager::with_config(SimConfig {
+            vcp: VcpConfig {
+                verbose: false,
+                limits,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        // everyone hears everyone, more neighbors than the table can hold
+        for i in 0..10 {
+            mgr.add_device((i % 5, i / 5));
+            for _ in 0..10 {
+                mgr.handle_messages();
+            }
+        }
+        let stats = mgr.stats();
+        let limits = mgr.config.vcp.limits;
+        assert!(stats.neighbors.peak <= limits.neighbors.capacity);
+        assert!(stats.outgoing_msgs.peak <= limits.outgoing_msgs.capacity);
+        assert!(stats.neighbors.dropped() > 0);
+        assert!(mgr
+            .devices
+            .iter()
+            .all(|d| d.vcp.neighbors.len() <= limits.neighbors.capacity));
+    }
+
+    /// Devices in a row, each only hears its direct neighbors
+    fn row(devices: i32, hello_interval: u64, event_driven: bool) -> VirtManager {
+        let mut mgr = VirtManager::with_config(SimConfig {
+            vcp: VcpConfig {
+                hello_interval: Duration::from_secs(hello_interval),
+                neighbor_timeout: Duration::from_secs(5 * hello_interval),
+                verbose: false,
+                ..Default::default()
+            },
+            event_driven,
+            ..Default::default()
+        });
+        for x in 0..devices {
+            mgr.add_device((x * 8, 0));
+            for _ in 0..30 * hello_interval {
+                mgr.handle_messages();
+            }
+        }
+        mgr
+    }
+
+    /// Rounds until a text from one end of the row is stored at the other end
+    fn rounds_to_deliver(mgr: &mut VirtManager) -> u32 {
+        let first = mgr.devices[0].vcp.c_id.unwrap();
+        let last = mgr.devices.last().unwrap().vcp.c_id.unwrap();
+        mgr.send_text_data(first, last, "ping");
+        for round in 1..100 {
+            mgr.handle_messages();
+            if mgr
+                .nodes()
+                .any(|n| n.data_storage().iter().any(|d| d.text == "ping"))
+            {
+                return round;
+            }
+        }
+        panic!("text was not delivered");
+    }
+
+    #[test]
+    fn long_texts_survive_lossy_links() {
+        let mut mgr = row(3, 1, false);
+        mgr.config.loss = 0.05;
+        for d in &mut mgr.devices {
+            d.vcp.config.max_frame_len = 220;
+            for virt in &mut d.vcp.virtual_nodes {
+                virt.config.max_frame_len = 220;
+            }
+        }
+        let first = mgr.devices[0].vcp.c_id.unwrap();
+        let last = mgr.devices.last().unwrap().vcp.c_id.unwrap();
+        let text = "\"long text\" ".repeat(9);
+        for _ in 0..20 {
+            mgr.send_text_data(first, last, &text);
+            mgr.handle_messages();
+        }
+        for _ in 0..mgr.rounds(mgr.config.vcp.reassembly_timeout) + 5 {
+            mgr.handle_messages();
+        }
+
+        let delivered: Vec<_> = mgr.nodes().flat_map(|n| n.data_storage()).collect();
+        // lost fragments are not sent again, but what arrives is whole
+        assert!(!delivered.is_empty() && delivered.len() < 20);
+        assert!(delivered.iter().all(|d| d.text.as_str() == text));
+        assert!(mgr.stats().incomplete > 0);
+        assert_eq!(mgr.stats().oversized, 0);
+        assert_eq!(mgr.packets_oversized, 0);
+    }
+
+    #[test]
+    fn applications_share_the_cord() {
+        let mut mgr = row(5, 1, true);
+        for d in &mut mgr.devices {
+            d.vcp.bind(1);
+            d.vcp.bind(2);
+        }
+        let first = mgr.devices[0].vcp.c_id.unwrap();
+        let last = mgr.devices.last().unwrap().vcp.c_id.unwrap();
+        mgr.send_data(first, last, 1, &[0xde, 0xad]);
+        mgr.send_data(last, first, 2, b"back");
+        mgr.handle_messages();
+
+        let inbox = |c_id| {
+            let node = mgr.nodes().find(|n| n.c_id() == Some(c_id)).unwrap();
+            node.inbox().to_vec()
+        };
+        let [to_last] = &inbox(last)[..] else {
+            panic!("one delivery expected");
+        };
+        assert_eq!((to_last.port, to_last.source), (1, first));
+        assert_eq!(to_last.payload[..], [0xde, 0xad]);
+        let [to_first] = &inbox(first)[..] else {
+            panic!("one delivery expected");
+        };
+        assert_eq!((to_first.port, to_first.source), (2, last));
+    }
+
+    #[test]
+    fn publications_reach_the_subscribers() {
+        let mut mgr = row(5, 1, true);
+        let topic = topic_id("temperature");
+        for i in [1, 4] {
+            assert!(mgr.devices[i].vcp.subscribe("temperature"));
+        }
+        mgr.handle_messages();
+        let rendezvous = mgr.devices[0].vcp.rendezvous(topic);
+        let closest = mgr
+            .nodes()
+            .filter_map(|n| n.c_id())
+            .min_by_key(|c| c.abs_diff(rendezvous))
+            .unwrap();
+        let node = mgr.nodes().find(|n| n.c_id() == Some(closest)).unwrap();
+        assert_eq!(node.subscribers(topic).len(), 2);
+
+        mgr.devices[2]
+            .vcp
+            .publish("temperature", to_payload(b"21").unwrap());
+        mgr.devices[2]
+            .vcp
+            .publish("humidity", to_payload(b"40").unwrap());
+        mgr.handle_messages();
+        let source = mgr.devices[2].vcp.c_id.unwrap();
+        for (i, d) in mgr.devices.iter().enumerate() {
+            let expected = if [1, 4].contains(&i) { 1 } else { 0 };
+            assert_eq!(d.vcp.publications.len(), expected);
+            assert!(d.vcp.publications.iter().all(|p| p.source == source));
+        }
+
+        // without refreshes the rendezvous node forgets the subscribers
+        mgr.devices[1].vcp.unsubscribe("temperature");
+        for _ in 0..mgr.rounds(mgr.config.vcp.subscription_lease) + 1 {
+            mgr.handle_messages();
+        }
+        let node = mgr.nodes().find(|n| n.c_id() == Some(closest)).unwrap();
+        assert_eq!(node.subscribers(topic).len(), 1);
+    }
+
+    #[test]
+    fn floods_and_multicasts_cover_the_network() {
+        let mut mgr = row(6, 1, true);
+        assert_eq!(mgr.flood_coverage(0, 2), 1.0);
+        // every device is one hop further
+        mgr.config.vcp.flood_ttl = 3;
+        for d in &mut mgr.devices {
+            d.vcp.config.flood_ttl = 3;
+        }
+        assert_eq!(mgr.flood_coverage(0, 2), 3.0 / 5.0);
+
+        let mut positions: Vec<CordId> = mgr.nodes().filter_map(|n| n.c_id()).collect();
+        positions.sort();
+        let (from, to) = (positions[1], positions[positions.len() - 2]);
+        assert_eq!(mgr.multicast_coverage(0, from, to, 2), 1.0);
+        assert_eq!(mgr.multicast_coverage(5, from + 1, to, 2), 1.0);
+        // the sender is in the range as well
+        assert_eq!(mgr.multicast_coverage(0, 0, 1000, 2), 1.0);
+        assert_eq!(mgr.multicast_coverage(0, 1001, 2000, 2), 0.0);
+        assert!(mgr.nodes().all(|n| n.inbox().is_empty()));
+    }
+
+    #[test]
+    fn a_walk_maps_the_cord() {
+        // one of them hosts a virtual node
+        let mut mgr = row(1, 1, true);
+        for pos in [(3, 5), (0, 14), (4, 20), (10, 8)] {
+            mgr.add_device(pos);
+            for _ in 0..30 {
+                mgr.handle_messages();
+            }
+        }
+        assert_eq!(mgr.nodes().count(), mgr.devices.len() + 1);
+        mgr.devices[2].vcp.walk();
+        for _ in 0..3 {
+            mgr.handle_messages();
+        }
+        let topology = &mgr.devices[2].vcp.topology;
+        let mut positions: Vec<CordId> = mgr.nodes().filter_map(|n| n.c_id()).collect();
+        let mut reported: Vec<CordId> = topology.iter().map(|e| e.c_id).collect();
+        positions.sort();
+        reported.sort();
+        assert_eq!(reported, positions);
+        for node in mgr.nodes() {
+            let entry = topology
+                .iter()
+                .find(|e| Some(e.c_id) == node.c_id())
+                .unwrap();
+            assert_eq!(
+                (entry.predecessor, entry.successor),
+                (node.predecessor(), node.successor())
+            );
+        }
+        for d in &mgr.devices {
+            let entry = topology
+                .iter()
+                .find(|e| Some(e.c_id) == d.vcp.c_id)
+                .unwrap();
+            let hosted: Vec<CordId> = d.vcp.virtual_nodes.iter().filter_map(|v| v.c_id).collect();
+            assert_eq!(entry.virtual_nodes[..], hosted[..]);
+            assert_eq!(entry.neighbors.len(), d.vcp.neighbors.len());
+        }
+
+        // the virtual node is drawn with its host
+        let dot = crate::graphing::GraphViz::generate_walk_graph(topology);
+        assert_eq!(dot.matches("pos = ").count(), mgr.devices.len());
+        assert!(dot.contains("virt{"));
+
+        // the node at the start reports to its own walk right away, the old reports are gone
+        let start = mgr.closest_device(0);
+        mgr.devices[start].vcp.walk();
+        assert_eq!(mgr.devices[start].vcp.topology.len(), 1);
+    }
+
+    #[test]
+    fn anycasts_reach_the_closest_provider() {
+        let mut mgr = row(6, 1, true);
+        for i in [1, 5] {
+            mgr.devices[i].vcp.services = 1;
+        }
+        for d in &mut mgr.devices {
+            d.vcp.bind(4);
+        }
+        // the hops travel one Hello per neighbor
+        for _ in 0..6 {
+            mgr.handle_messages();
+        }
+        assert_eq!(mgr.devices[3].vcp.service_hops(0), Some(2));
+        for i in [0, 4, 5] {
+            mgr.devices[i]
+                .vcp
+                .anycast(0, 4, to_payload(&[i as u8]).unwrap());
+        }
+        mgr.handle_messages();
+
+        let inbox = |i: usize| -> Vec<u8> {
+            mgr.devices[i]
+                .nodes()
+                .flat_map(|n| n.inbox())
+                .map(|d| d.payload[0])
+                .collect()
+        };
+        assert_eq!(inbox(1), [0]);
+        // a provider takes its own anycast right away
+        assert_eq!(inbox(5), [5, 4]);
+        assert_eq!(mgr.stats().unreachable, 0);
+    }
+
+    struct Position;
+
+    impl Method for Position {
+        const ID: MethodId = 1;
+        type Request = ();
+        type Response = (i32, i32);
+    }
+
+    #[test]
+    fn calls_are_answered_along_the_cord() {
+        let mut mgr = row(5, 1, true);
+        for d in &mut mgr.devices {
+            let position = d.position;
+            d.rpc
+                .serve::<Position>(&mut d.vcp, move |_, ()| position)
+                .unwrap();
+        }
+        let last = mgr.devices.last().unwrap().vcp.c_id.unwrap();
+        let replies = Rc::new(RefCell::new(vec![]));
+        let call = |mgr: &mut VirtManager| {
+            let replies = replies.clone();
+            let d = &mut mgr.devices[0];
+            d.rpc
+                .call::<Position>(&mut d.vcp, last, &(), Duration::from_secs(3), move |r| {
+                    replies.borrow_mut().push(r)
+                })
+                .unwrap();
+        };
+        call(&mut mgr);
+        // the request is answered in the round it arrives, the response in the next
+        mgr.handle_messages();
+        mgr.handle_messages();
+        assert_eq!(replies.borrow()[..], [Ok((32, 0))]);
+
+        // the called device is gone, its neighbors still route to it for a while
+        mgr.devices.pop();
+        call(&mut mgr);
+        for _ in 0..mgr.rounds(Duration::from_secs(3)) {
+            mgr.handle_messages();
+        }
+        assert_eq!(replies.borrow()[1..], [Err(RpcError::Timeout)]);
+        assert_eq!(mgr.devices[0].rpc.pending(), 0);
+        assert!(mgr.nodes().all(|n| n.inbox().is_empty()));
+    }
+
+    #[test]
+    fn forwarding_does_not_wait_for_the_timer() {
+        for hello_interval in [1, 3] {
+            let mut polled = row(6, hello_interval, false);
+            let mut event_driven = row(6, hello_interval, true);
+            assert_eq!(polled.find_inconsistencies(), vec![]);
+            // polled, every hop waits for the next round
+            assert_eq!(rounds_to_deliver(&mut polled), 5);
+            // event-driven, all hops happen in the round the text is sent
+            assert_eq!(rounds_to_deliver(&mut event_driven), 1);
+        }
+    }
+
+    #[test]
+    fn rebooted_device_keeps_its_position() {
+        let mut mgr = row(3, 1, false);
+        let mut persistence = Persistence::open(MemoryStorage::default(), || [0; 32]).unwrap();
+        persistence.sync(&mgr.devices[1].vcp).unwrap();
+        let (position, before) = (mgr.devices[1].position, mgr.devices[1].vcp.c_id);
+
+        // switched off until its neighbors forgot it
+        mgr.devices.remove(1);
+        for _ in 0..10 {
+            mgr.handle_messages();
+        }
+        let rebooted = Persistence::open(persistence.storage().clone(), || [1; 32]).unwrap();
+        mgr.add_device(position);
+        rebooted.restore(&mut mgr.devices[2].vcp);
+        for _ in 0..20 {
+            mgr.handle_messages();
+        }
+
+        assert_eq!(mgr.devices[2].vcp.c_id, before);
+        assert!(mgr.devices[2].vcp.is_confirmed());
+        assert_eq!(mgr.find_inconsistencies(), vec![]);
+    }
+
+    /// With `heapless` the protocol state lives in fixed-size tables,
+    /// so a running network must not touch the heap at all.
+    #[cfg(feature = "heapless")]
+    mod allocation_free {
+        use super::*;
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        /// Counts the allocations of the threads that asked for it
+        struct CountingAllocator;
+
+        #[global_allocator]
+        static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+        thread_local! {
+            static ALLOCATIONS: Cell<Option<u64>> = const { Cell::new(None) };
+        }
+
+        unsafe impl GlobalAlloc for CountingAllocator {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = ALLOCATIONS.try_with(|a| a.set(a.get().map(|n| n + 1)));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        /// Run a round, with a text every 100 rounds
+        fn round(mgr: &mut VirtManager, i: u32) {
+            if i.is_multiple_of(100) {
+                mgr.send_text_data(0, 300 + i % 700, "Hello");
+            }
+            mgr.handle_messages();
+        }
+
+        #[test]
+        fn no_allocations_after_warm_up() {
+            let mut mgr = VirtManager::with_config(SimConfig {
+                vcp: VcpConfig {
+                    verbose: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            for pos in [(2, -2), (3, 5), (0, 14), (4, 20), (3, -8), (10, 8)] {
+                mgr.add_device(pos);
+                for _ in 0..30 {
+                    mgr.handle_messages();
+                }
+            }
+            for i in 0..2_000 {
+                round(&mut mgr, i);
+            }
+
+            ALLOCATIONS.with(|a| a.set(Some(0)));
+            for i in 0..100_000 {
+                round(&mut mgr, i);
+            }
+            let allocations = ALLOCATIONS.with(|a| a.take());
+
+            assert_eq!(allocations, Some(0));
+            assert_eq!(mgr.find_inconsistencies(), vec![]);
+            assert!(mgr.nodes().any(|n| !n.data_storage().is_empty()));
+        }
     }
 }
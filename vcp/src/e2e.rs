@@ -0,0 +1,299 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::StaticSecret;
+
+use crate::collections::to_payload;
+use crate::vcp::{CordId, PublicKey, Sealed, Vcp};
+
+/// Bytes a sealed payload is longer than the plaintext
+pub const OVERHEAD: usize = 16;
+
+/// SHA-256 of a public key, it names the receiver of a sealed payload
+pub type Fingerprint = [u8; 32];
+
+/// The fingerprint of the public key
+pub fn fingerprint(public_key: &PublicKey) -> Fingerprint {
+    Sha256::digest(public_key).into()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a payload could not be sealed or opened
+pub enum E2eError {
+    /// The public key of the receiver is not known yet, it was requested
+    UnknownKey,
+    /// Too long for a packet
+    TooLong,
+    /// Not sealed for this node, or changed on the way
+    BadCiphertext,
+    /// The key of the other node is a weak point of the curve
+    WeakKey,
+    /// The key of the receiver does not have its fingerprint, it was requested again
+    WrongKey,
+}
+
+impl fmt::Display for E2eError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            E2eError::UnknownKey => write!(f, "public key of the receiver unknown"),
+            E2eError::TooLong => write!(f, "payload too long"),
+            E2eError::BadCiphertext => write!(f, "payload can't be decrypted"),
+            E2eError::WeakKey => write!(f, "weak public key"),
+            E2eError::WrongKey => write!(f, "public key of another node"),
+        }
+    }
+}
+
+/// Seals payloads for the public key of the final receiver, so the relays along the cord
+/// can't read them. Sender and receiver agree on a key with X25519, one per direction,
+/// and encrypt with ChaCha20-Poly1305. A sealed payload also proves who sealed it.
+///
+/// Anyone on the route can answer a key request, a relay could answer with its own key.
+/// So payloads are only sealed for a key with the `fingerprint` of the receiver. Replayed
+/// payloads are not detected here, `auth` drops them per hop.
+pub struct E2e {
+    secret: StaticSecret,
+    public_key: PublicKey,
+    next_nonce: u64,
+}
+
+impl E2e {
+    /// `secret` is the persistent secret of the node, like `NodeState::secret`.
+    /// The nonces start at `boot << 32`, so count the boots to never use one twice.
+    pub fn new(secret: [u8; 32], boot: u32) -> Self {
+        let secret = StaticSecret::from(secret);
+        E2e {
+            public_key: x25519_dalek::PublicKey::from(&secret).to_bytes(),
+            secret,
+            next_nonce: u64::from(boot) << 32,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Encrypt the plaintext for the node with the public key `to`
+    pub fn seal(&mut self, to: &PublicKey, plaintext: &[u8]) -> Result<Sealed, E2eError> {
+        let cipher = self.cipher(to, &self.public_key, to)?;
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        let ciphertext = cipher
+            .encrypt(&nonce_bytes(nonce), plaintext)
+            .map_err(|_| E2eError::TooLong)?;
+        Ok(Sealed {
+            sender_key: self.public_key,
+            nonce,
+            ciphertext: to_payload(&ciphertext).ok_or(E2eError::TooLong)?,
+        })
+    }
+
+    /// Decrypt a payload sealed for this node
+    pub fn open(&self, sealed: &Sealed) -> Result<Vec<u8>, E2eError> {
+        let sender = &sealed.sender_key;
+        self.cipher(sender, sender, &self.public_key)?
+            .decrypt(&nonce_bytes(sealed.nonce), &sealed.ciphertext[..])
+            .map_err(|_| E2eError::BadCiphertext)
+    }
+
+    /// Seal the plaintext for the node at `final_cid` whose key has the fingerprint
+    /// `receiver` and send it. If its key is not known, or the known one has another
+    /// fingerprint, it is requested, try again once the answer arrived.
+    pub fn send(
+        &mut self,
+        vcp: &mut Vcp,
+        final_cid: CordId,
+        receiver: &Fingerprint,
+        plaintext: &[u8],
+    ) -> Result<(), E2eError> {
+        let Some(&key) = vcp.keys.get(&final_cid) else {
+            vcp.request_key(final_cid);
+            return Err(E2eError::UnknownKey);
+        };
+        if fingerprint(&key) != *receiver {
+            vcp.keys.remove(&final_cid);
+            vcp.request_key(final_cid);
+            return Err(E2eError::WrongKey);
+        }
+        let sealed = self.seal(&key, plaintext)?;
+        vcp.send_sealed(final_cid, sealed);
+        Ok(())
+    }
+
+    /// Take the payloads the node and its virtual nodes received, and decrypt them.
+    /// Each comes with the public key of its sender.
+    pub fn receive(&self, vcp: &mut Vcp) -> Vec<Result<(PublicKey, Vec<u8>), E2eError>> {
+        let virt = vcp.virtual_nodes.iter_mut().map(|v| &mut v.sealed_storage);
+        core::iter::once(&mut vcp.sealed_storage)
+            .chain(virt)
+            .flat_map(core::mem::take)
+            .map(|sealed| Ok((sealed.sender_key, self.open(&sealed)?)))
+            .collect()
+    }
+
+    /// The cipher for payloads from `sender` to `receiver`, one of them is this node
+    fn cipher(
+        &self,
+        other: &PublicKey,
+        sender: &PublicKey,
+        receiver: &PublicKey,
+    ) -> Result<ChaCha20Poly1305, E2eError> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(*other));
+        if !shared.was_contributory() {
+            return Err(E2eError::WeakKey);
+        }
+        let key = Sha256::new()
+            .chain_update(b"vcp e2e")
+            .chain_update(shared.as_bytes())
+            .chain_update(sender)
+            .chain_update(receiver)
+            .finalize();
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+}
+
+fn nonce_bytes(nonce: u64) -> Nonce {
+    let mut bytes = [0; 12];
+    bytes[..8].copy_from_slice(&nonce.to_le_bytes());
+    bytes.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::vcp::{Message, NeighborInfo, Packet};
+
+    #[test]
+    fn only_the_receiver_can_open() {
+        let mut alice = E2e::new([1; 32], 0);
+        let bob = E2e::new([2; 32], 0);
+        let mut relay = E2e::new([3; 32], 0);
+
+        let sealed = alice.seal(&bob.public_key(), b"secret").unwrap();
+        assert_eq!(sealed.ciphertext.len(), b"secret".len() + OVERHEAD);
+        assert_eq!(bob.open(&sealed).unwrap(), b"secret");
+        assert_eq!(relay.open(&sealed).unwrap_err(), E2eError::BadCiphertext);
+
+        // the same plaintext looks different every time
+        let again = alice.seal(&bob.public_key(), b"secret").unwrap();
+        assert_ne!(again.ciphertext, sealed.ciphertext);
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(bob.open(&tampered).unwrap_err(), E2eError::BadCiphertext);
+        // the relay can't claim to be someone else
+        let mut forged = relay.seal(&bob.public_key(), b"secret").unwrap();
+        forged.sender_key = alice.public_key();
+        assert_eq!(bob.open(&forged).unwrap_err(), E2eError::BadCiphertext);
+
+        assert_eq!(
+            alice.seal(&[0; 32], b"secret").unwrap_err(),
+            E2eError::WeakKey
+        );
+    }
+
+    /// Deliver the queued packets of every node to its neighbors in the chain
+    fn exchange(nodes: &mut [Vcp]) {
+        for i in 0..nodes.len() {
+            for packet in core::mem::take(&mut nodes[i].outgoing_msgs) {
+                for j in [i.wrapping_sub(1), i + 1] {
+                    if let Some(node) = nodes.get_mut(j) {
+                        node.receive(&packet);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sealed_payloads_travel_along_the_cord() {
+        let mut e2e: Vec<_> = (0..3).map(|i| E2e::new([i + 1; 32], 0)).collect();
+        // 0 - 500 - 1000, only direct neighbors hear each other
+        let mut nodes: Vec<_> = [0, 500, 1000]
+            .iter()
+            .zip(&e2e)
+            .map(|(&c_id, e2e)| {
+                let mut vcp = Vcp::new(false);
+                vcp.config.verbose = false;
+                vcp.c_id = Some(c_id);
+                vcp.public_key = Some(e2e.public_key());
+                vcp
+            })
+            .collect();
+        let clock = ManualClock::new(0);
+        for node in &mut nodes {
+            node.timer_call(&clock);
+        }
+        exchange(&mut nodes);
+        assert!(nodes[0].keys.is_empty());
+
+        // the Hellos don't carry the keys, they have to be asked for
+        let (first, rest) = e2e.split_at_mut(1);
+        let receiver = fingerprint(&rest[1].public_key());
+        assert_eq!(
+            first[0].send(&mut nodes[0], 1000, &receiver, b"for 1000 only"),
+            Err(E2eError::UnknownKey)
+        );
+        for _ in 0..4 {
+            exchange(&mut nodes);
+        }
+        assert_eq!(nodes[0].keys.get(&1000), Some(&rest[1].public_key()));
+
+        first[0]
+            .send(&mut nodes[0], 1000, &receiver, b"for 1000 only")
+            .unwrap();
+        for _ in 0..2 {
+            exchange(&mut nodes);
+        }
+        assert!(rest[0].receive(&mut nodes[1]).is_empty());
+        let received = rest[1].receive(&mut nodes[2]);
+        assert_eq!(
+            received,
+            [Ok((first[0].public_key(), b"for 1000 only".to_vec()))]
+        );
+    }
+
+    #[test]
+    fn keys_of_relays_are_rejected() {
+        let mut alice = E2e::new([1; 32], 0);
+        let bob = E2e::new([2; 32], 0);
+        let relay = E2e::new([3; 32], 0);
+        // boxed, a heapless `Vcp` is big for the stack of a test
+        let node = |c_id| {
+            let mut vcp = Box::new(Vcp::new(false));
+            vcp.config.verbose = false;
+            vcp.c_id = Some(c_id);
+            vcp
+        };
+        let (mut node, relay_node) = (node(0), node(500));
+        node.receive(&Packet::new(
+            &relay_node,
+            Message::Hello(NeighborInfo::default()),
+        ));
+
+        // the relay at 500 answers the request for the key of 1000 with its own
+        let forged = Message::KeyReply {
+            c_id: 1000,
+            key: relay.public_key(),
+        };
+        node.receive(&Packet::new_unicast_data(&relay_node, 0, 0, forged));
+        assert_eq!(node.keys.get(&1000), Some(&relay.public_key()));
+        let bob = fingerprint(&bob.public_key());
+        assert_eq!(
+            alice.send(&mut node, 1000, &bob, b"for bob only"),
+            Err(E2eError::WrongKey)
+        );
+        assert!(!node.keys.contains_key(&1000));
+        let request = node.outgoing_msgs.pop().unwrap();
+        assert!(matches!(
+            request.message,
+            Message::KeyRequest { reply_to: 0 }
+        ));
+        assert!(node.outgoing_msgs.is_empty());
+    }
+}
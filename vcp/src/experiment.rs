@@ -0,0 +1,456 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use vcp::{
+    limits::Limits,
+    vcp::{HelloSchedule, VcpConfig},
+};
+
+use crate::dummy::{SimConfig, VirtManager};
+
+#[derive(Clone, Debug)]
+/// All parameter values that should be combined with each other.
+/// Every combination is one point of the sweep.
+pub struct SweepGrid {
+    pub node_count: Vec<usize>,
+    /// Side length of the square the devices are placed in
+    pub area: Vec<i32>,
+    pub range: Vec<i32>,
+    pub loss: Vec<f64>,
+    pub hello_interval: Vec<Duration>,
+    pub neighbor_timeout: Vec<Duration>,
+    pub hello_schedule: Vec<HelloSchedule>,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// One combination of parameters out of a `SweepGrid`
+pub struct SweepPoint {
+    pub node_count: usize,
+    pub area: i32,
+    pub range: i32,
+    pub loss: f64,
+    pub hello_interval: Duration,
+    pub neighbor_timeout: Duration,
+    pub hello_schedule: HelloSchedule,
+}
+
+impl SweepGrid {
+    /// All points of the grid, the last parameter changes fastest
+    pub fn points(&self) -> Vec<SweepPoint> {
+        let mut points = Vec::new();
+        for &node_count in &self.node_count {
+            for &area in &self.area {
+                for &range in &self.range {
+                    for &loss in &self.loss {
+                        for &hello_interval in &self.hello_interval {
+                            for &neighbor_timeout in &self.neighbor_timeout {
+                                for &hello_schedule in &self.hello_schedule {
+                                    points.push(SweepPoint {
+                                        node_count,
+                                        area,
+                                        range,
+                                        loss,
+                                        hello_interval,
+                                        neighbor_timeout,
+                                        hello_schedule,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        points
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Metrics of a single simulation run
+pub struct RunMetrics {
+    /// `find_inconsistencies` found nothing at the end of the run
+    pub consistent: bool,
+    /// Number of violations found at the end of the run
+    pub inconsistencies: usize,
+    /// Number of rounds after the last join until the network was consistent
+    pub converged_after: Option<u32>,
+    /// Devices that got a cord position
+    pub assigned: usize,
+    pub virtual_nodes: usize,
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+    pub hellos_sent: u64,
+    /// Most packets a single node had queued at once
+    pub peak_outgoing: usize,
+    /// Most neighbors a single node knew at once
+    pub peak_neighbors: usize,
+    /// Entries of all tables and queues dropped because of the limits
+    pub dropped: u64,
+    /// Share of the other devices a flood of the first device reached
+    pub flood_coverage: f64,
+    /// Share of the nodes in the middle half of the cord a multicast of the first device reached
+    pub multicast_coverage: f64,
+}
+
+#[derive(Clone, Debug)]
+/// Metrics of all seeds of one `SweepPoint`, averaged
+pub struct PointSummary {
+    pub point: SweepPoint,
+    pub runs: usize,
+    /// Share of runs that ended consistent
+    pub consistent: f64,
+    pub inconsistencies: f64,
+    /// Mean over the runs that converged
+    pub converged_after: Option<f64>,
+    pub assigned: f64,
+    pub virtual_nodes: f64,
+    pub packets_sent: f64,
+    pub packets_lost: f64,
+    pub hellos_sent: f64,
+    /// Highest over all runs, to size the limits
+    pub peak_outgoing: usize,
+    /// Highest over all runs, to size the limits
+    pub peak_neighbors: usize,
+    pub dropped: f64,
+    pub flood_coverage: f64,
+    pub multicast_coverage: f64,
+}
+
+/// Runs every point of a `SweepGrid` with several seeds in parallel
+pub struct Experiment {
+    pub grid: SweepGrid,
+    /// Number of runs per point, with the seeds `0..seeds`
+    pub seeds: u64,
+    /// Rounds to wait after every join
+    pub join_rounds: u32,
+    /// Rounds to wait after the last join
+    pub settle_rounds: u32,
+    /// Rounds a flood and a multicast get to spread, after the network settled
+    pub coverage_rounds: u64,
+    /// Number of worker threads, defaults to the number of cpu cores
+    pub threads: Option<usize>,
+    /// Limits of every simulated node, unbounded by default
+    pub limits: Limits,
+}
+
+impl Experiment {
+    pub fn new(grid: SweepGrid) -> Self {
+        Experiment {
+            grid,
+            seeds: 10,
+            join_rounds: 10,
+            settle_rounds: 30,
+            coverage_rounds: 20,
+            threads: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Simulate a single point with a single seed.
+    /// Devices join one after the other at random positions in the area.
+    pub fn run_once(&self, point: &SweepPoint, seed: u64) -> RunMetrics {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut mgr = VirtManager::with_config(SimConfig {
+            range: point.range,
+            loss: point.loss,
+            seed,
+            vcp: VcpConfig {
+                hello_interval: point.hello_interval,
+                neighbor_timeout: point.neighbor_timeout,
+                hello_schedule: point.hello_schedule,
+                verbose: false,
+                limits: self.limits,
+                ..VcpConfig::default()
+            },
+            ..SimConfig::default()
+        });
+
+        for _ in 0..point.node_count {
+            let area = point.area.max(1);
+            mgr.add_device((rng.gen_range(0..area), rng.gen_range(0..area)));
+            for _ in 0..self.join_rounds {
+                mgr.handle_messages();
+            }
+        }
+
+        let mut converged_after = None;
+        for round in 1..=self.settle_rounds {
+            mgr.handle_messages();
+            let consistent = mgr.find_inconsistencies().is_empty();
+            if !consistent {
+                converged_after = None;
+            } else if converged_after.is_none() {
+                converged_after = Some(round);
+            }
+        }
+
+        let inconsistencies = mgr.find_inconsistencies().len();
+        let stats = mgr.stats();
+        let assigned = mgr.devices.iter().filter(|d| d.vcp.c_id.is_some()).count();
+        let virtual_nodes = mgr.devices.iter().map(|d| d.vcp.virtual_nodes.len()).sum();
+        let packets_sent = mgr.packets_sent;
+        let packets_lost = mgr.packets_lost;
+        let hellos_sent = mgr.hellos_sent;
+
+        let flood_coverage = mgr.flood_coverage(0, self.coverage_rounds);
+        let (start, end) = (mgr.config.vcp.cord_start, mgr.config.vcp.cord_end);
+        let quarter = (end - start) / 4;
+        let multicast_coverage =
+            mgr.multicast_coverage(0, start + quarter, end - quarter, self.coverage_rounds);
+        RunMetrics {
+            consistent: inconsistencies == 0,
+            inconsistencies,
+            converged_after,
+            assigned,
+            virtual_nodes,
+            packets_sent,
+            packets_lost,
+            hellos_sent,
+            peak_outgoing: stats.outgoing_msgs.peak,
+            peak_neighbors: stats.neighbors.peak,
+            dropped: stats.dropped(),
+            flood_coverage,
+            multicast_coverage,
+        }
+    }
+
+    /// Run all points and seeds, spread over all cpu cores
+    pub fn run(&self) -> Vec<PointSummary> {
+        let points = self.grid.points();
+        let jobs: Vec<(usize, u64)> = (0..points.len())
+            .flat_map(|p| (0..self.seeds).map(move |seed| (p, seed)))
+            .collect();
+        let threads = self
+            .threads
+            .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+
+        let next_job = AtomicUsize::new(0);
+        let results: Mutex<Vec<Vec<RunMetrics>>> = Mutex::new(vec![Vec::new(); points.len()]);
+        thread::scope(|scope| {
+            for _ in 0..threads.min(jobs.len()) {
+                scope.spawn(|| loop {
+                    let job = next_job.fetch_add(1, Ordering::Relaxed);
+                    let Some(&(p, seed)) = jobs.get(job) else {
+                        break;
+                    };
+                    let metrics = self.run_once(&points[p], seed);
+                    results.lock().unwrap()[p].push(metrics);
+                });
+            }
+        });
+
+        let results = results.into_inner().unwrap();
+        points
+            .iter()
+            .zip(results)
+            .map(|(point, runs)| PointSummary::new(*point, &runs))
+            .collect()
+    }
+}
+
+impl PointSummary {
+    fn new(point: SweepPoint, runs: &[RunMetrics]) -> Self {
+        let n = runs.len().max(1) as f64;
+        let mean = |f: &dyn Fn(&RunMetrics) -> f64| runs.iter().map(f).sum::<f64>() / n;
+        let converged: Vec<f64> = runs
+            .iter()
+            .filter_map(|r| r.converged_after.map(f64::from))
+            .collect();
+        PointSummary {
+            point,
+            runs: runs.len(),
+            consistent: mean(&|r| if r.consistent { 1.0 } else { 0.0 }),
+            inconsistencies: mean(&|r| r.inconsistencies as f64),
+            converged_after: if converged.is_empty() {
+                None
+            } else {
+                Some(converged.iter().sum::<f64>() / converged.len() as f64)
+            },
+            assigned: mean(&|r| r.assigned as f64),
+            virtual_nodes: mean(&|r| r.virtual_nodes as f64),
+            packets_sent: mean(&|r| r.packets_sent as f64),
+            packets_lost: mean(&|r| r.packets_lost as f64),
+            hellos_sent: mean(&|r| r.hellos_sent as f64),
+            peak_outgoing: runs.iter().map(|r| r.peak_outgoing).max().unwrap_or(0),
+            peak_neighbors: runs.iter().map(|r| r.peak_neighbors).max().unwrap_or(0),
+            dropped: mean(&|r| r.dropped as f64),
+            flood_coverage: mean(&|r| r.flood_coverage),
+            multicast_coverage: mean(&|r| r.multicast_coverage),
+        }
+    }
+}
+
+/// Short name of a schedule for the table, e.g. `ad8` for adaptive with a max interval of 8 s
+fn schedule_name(schedule: HelloSchedule) -> String {
+    match schedule {
+        HelloSchedule::Fixed => "fixed".into(),
+        HelloSchedule::Adaptive { max_interval } => format!("ad{}", max_interval.as_secs_f64()),
+    }
+}
+
+/// Format the summaries as a plain text table, one line per point
+pub fn format_table(summaries: &[PointSummary]) -> String {
+    let mut res = String::new();
+    writeln!(
+        res,
+        "{:>5} {:>5} {:>5} {:>5} {:>5} {:>7} {:>5} | {:>4} {:>10} {:>6} {:>9} {:>8} {:>7} {:>9} {:>8} {:>9} {:>5} {:>5} {:>7} {:>6} {:>6}",
+        "nodes",
+        "area",
+        "range",
+        "loss",
+        "hello",
+        "timeout",
+        "sched",
+        "runs",
+        "consistent",
+        "errors",
+        "converged",
+        "assigned",
+        "virtual",
+        "sent",
+        "lost",
+        "hellos",
+        "queue",
+        "neigh",
+        "dropped",
+        "flood",
+        "mcast"
+    )
+    .unwrap();
+    for s in summaries {
+        let p = &s.point;
+        let converged = s
+            .converged_after
+            .map(|c| format!("{:.1}", c))
+            .unwrap_or("-".into());
+        writeln!(
+            res,
+            "{:>5} {:>5} {:>5} {:>5.2} {:>5.1} {:>7.1} {:>5} | {:>4} {:>9.0}% {:>6.1} {:>9} {:>8.1} {:>7.1} {:>9.0} {:>8.0} {:>9.0} {:>5} {:>5} {:>7.1} {:>5.0}% {:>5.0}%",
+            p.node_count,
+            p.area,
+            p.range,
+            p.loss,
+            p.hello_interval.as_secs_f64(),
+            p.neighbor_timeout.as_secs_f64(),
+            schedule_name(p.hello_schedule),
+            s.runs,
+            s.consistent * 100.0,
+            s.inconsistencies,
+            converged,
+            s.assigned,
+            s.virtual_nodes,
+            s.packets_sent,
+            s.packets_lost,
+            s.hellos_sent,
+            s.peak_outgoing,
+            s.peak_neighbors,
+            s.dropped,
+            s.flood_coverage * 100.0,
+            s.multicast_coverage * 100.0
+        )
+        .unwrap();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_grid() -> SweepGrid {
+        SweepGrid {
+            node_count: vec![1, 3],
+            area: vec![5],
+            range: vec![10],
+            loss: vec![0.0, 0.2],
+            hello_interval: vec![Duration::from_secs(1)],
+            neighbor_timeout: vec![Duration::from_secs(5)],
+            hello_schedule: vec![HelloSchedule::Fixed],
+        }
+    }
+
+    #[test]
+    fn grid_points() {
+        let points = small_grid().points();
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[1].node_count, 1);
+        assert_eq!(points[1].loss, 0.2);
+    }
+
+    #[test]
+    fn runs_are_reproducible() {
+        let exp = Experiment::new(small_grid());
+        let point = exp.grid.points()[3];
+        let a = exp.run_once(&point, 7);
+        let b = exp.run_once(&point, 7);
+        assert_eq!(a.packets_sent, b.packets_sent);
+        assert_eq!(a.packets_lost, b.packets_lost);
+        assert_eq!(a.assigned, b.assigned);
+        assert_eq!(a.flood_coverage, b.flood_coverage);
+    }
+
+    #[test]
+    fn floods_reach_a_connected_network() {
+        let exp = Experiment::new(small_grid());
+        // three devices close together, no loss
+        let metrics = exp.run_once(&exp.grid.points()[2], 1);
+        assert!(metrics.consistent);
+        assert_eq!(metrics.flood_coverage, 1.0);
+        assert_eq!(metrics.multicast_coverage, 1.0);
+    }
+
+    #[test]
+    fn limits_bound_the_peaks() {
+        let mut exp = Experiment::new(small_grid());
+        exp.limits = Limits::constrained();
+        exp.limits.neighbors.capacity = 1;
+        let metrics = exp.run_once(&exp.grid.points()[2], 1);
+        assert!(metrics.peak_neighbors <= 1);
+        assert!(metrics.dropped > 0);
+    }
+
+    #[test]
+    fn adaptive_hellos_save_airtime() {
+        let mut grid = small_grid();
+        grid.node_count = vec![5];
+        grid.loss = vec![0.0];
+        grid.neighbor_timeout = vec![Duration::from_secs(20)];
+        grid.hello_schedule = vec![
+            HelloSchedule::Fixed,
+            HelloSchedule::Adaptive {
+                max_interval: Duration::from_secs(8),
+            },
+        ];
+        let mut exp = Experiment::new(grid);
+        exp.settle_rounds = 100;
+        let points = exp.grid.points();
+        let fixed = exp.run_once(&points[0], 3);
+        let adaptive = exp.run_once(&points[1], 3);
+        assert_eq!(fixed.consistent, adaptive.consistent);
+        assert_eq!(fixed.assigned, adaptive.assigned);
+        assert!(adaptive.hellos_sent * 3 < fixed.hellos_sent);
+    }
+
+    #[test]
+    fn sweep_aggregates_all_seeds() {
+        let mut exp = Experiment::new(small_grid());
+        exp.seeds = 3;
+        exp.threads = Some(2);
+        let summaries = exp.run();
+        assert_eq!(summaries.len(), 4);
+        assert!(summaries.iter().all(|s| s.runs == 3));
+        // a single device is always consistent and never loses anything
+        assert_eq!(summaries[0].consistent, 1.0);
+        assert_eq!(summaries[0].packets_lost, 0.0);
+        assert!(format_table(&summaries).lines().count() == 5);
+    }
+}
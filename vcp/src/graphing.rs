@@ -4,6 +4,7 @@ use petgraph::{
     stable_graph::NodeIndex,
 };
 use std::{
+    f64::consts::TAU,
     fmt::Write,
     fs::{self, remove_file},
     io::Error,
@@ -11,17 +12,26 @@ use std::{
     process::Command,
 };
 
-use crate::{
-    dummy::*,
-    vcp::{Data, Message, Packet, Vcp},
-};
+use crate::dummy::*;
+use vcp::vcp::{CordEntry, CordId, Data, Message, Packet};
 
 pub struct GraphViz {}
 
 const SCALE: f64 = 3.0;
 const DISPLAY_VIRTUAL_NODES: bool = false;
+/// Radius of the circle the nodes of a walk are laid out on, per node
+const WALK_SPACING: f64 = 3.0;
 impl GraphViz {
     pub fn save_to_png(dot_g: &String, path: &Path) -> Result<(), Error> {
+        GraphViz::render(dot_g, path, "png")
+    }
+
+    pub fn save_to_svg(dot_g: &String, path: &Path) -> Result<(), Error> {
+        GraphViz::render(dot_g, path, "svg")
+    }
+
+    /// Write the graph next to `path` and let GraphViz draw it in `format` to `path`
+    fn render(dot_g: &String, path: &Path, format: &str) -> Result<(), Error> {
         let dotfile = format!(
             "{}/{}.{}",
             path.parent().unwrap().to_str().unwrap(),
@@ -30,11 +40,11 @@ impl GraphViz {
         );
         println!("{}", dotfile);
 
-        let _ = fs::write(dotfile.clone(), dot_g).expect("msg");
-        let cmd = Command::new("dot")
+        fs::write(dotfile.clone(), dot_g).expect("msg");
+        Command::new("dot")
             .arg("-Kfdp")
             .arg("-n")
-            .arg("-Tpng")
+            .arg(format!("-T{}", format))
             .arg(dotfile.clone())
             .args(["-o", path.to_str().unwrap()])
             .status()
@@ -46,8 +56,7 @@ impl GraphViz {
     /// Get GraphViz Nodes for displaying the data that is stored in a node
     fn get_data_storage(virt: &VirtManager) -> String {
         let mut res = String::new();
-        let mut i = 0;
-        for dev in &virt.devices {
+        for (i, dev) in virt.devices.iter().enumerate() {
             let mut message_node = String::new();
             write!(
                 &mut message_node,
@@ -63,9 +72,8 @@ impl GraphViz {
                 dev.position.1 as f64 / SCALE
             )
             .unwrap();
-            i += 1;
 
-            write!(&mut message_node, "label = \"Data: \n").unwrap();
+            writeln!(&mut message_node, "label = \"Data: ").unwrap();
             let mut count = 0;
             let mut datas: Vec<&Data> = Vec::new();
             datas.extend(dev.vcp.data_storage.iter());
@@ -74,7 +82,7 @@ impl GraphViz {
             }
             for m in &datas {
                 count += 1;
-                write!(&mut message_node, "{}: {}\n", m.sender_cid, m.text,).unwrap();
+                writeln!(&mut message_node, "{}: {}", m.sender_cid, m.text,).unwrap();
             }
             write!(&mut message_node, "\"").unwrap();
 
@@ -90,8 +98,7 @@ impl GraphViz {
     /// Get GraphViz Nodes for visualizing messages that are send
     fn get_data_messages(virt: &VirtManager) -> String {
         let mut res = String::new();
-        let mut i = 0;
-        for dev in &virt.devices {
+        for (i, dev) in virt.devices.iter().enumerate() {
             let mut message_node = String::new();
             write!(
                 &mut message_node,
@@ -107,31 +114,26 @@ impl GraphViz {
                 dev.position.1 as f64 / SCALE
             )
             .unwrap();
-            i += 1;
 
             let mut outgoing: Vec<&Packet> = Vec::new();
 
             outgoing.extend(dev.vcp.outgoing_msgs.iter());
             for virt in &dev.vcp.virtual_nodes {
-                if DISPLAY_VIRTUAL_NODES {}
                 outgoing.extend(virt.outgoing_msgs.iter());
             }
 
             write!(&mut message_node, "label = \"").unwrap();
             let mut count = 0;
             for m in outgoing {
-                match m.message {
-                    Message::Text(ref s) => {
-                        count += 1;
-                        write!(
-                            &mut message_node,
-                            "[{} -> {}] {s}\n",
-                            m.sender_cid.unwrap(),
-                            m.final_cid.unwrap()
-                        )
-                        .unwrap();
-                    }
-                    _ => {}
+                if let Message::Text(ref s) = m.message {
+                    count += 1;
+                    writeln!(
+                        &mut message_node,
+                        "[{} -> {}] {s}",
+                        m.sender_cid.unwrap(),
+                        m.final_cid.unwrap()
+                    )
+                    .unwrap();
                 }
             }
             write!(&mut message_node, "\"").unwrap();
@@ -146,40 +148,41 @@ impl GraphViz {
     }
     /// Generates a GraphViz Diagraph in .dot Filefromat
     pub fn generate_graph(virt: &VirtManager) -> String {
-        /// Should Virtual Nodes be displayed in the graph or only included in the 'parrent' node.
         let mut g = Graph::<String, String>::new();
 
         // All Nodes and its Virtual Nodes, stored with Index and the Virtual Device Information
-        let mut devs_and_virtuals: Vec<(usize, &VirtDevice, &Vcp)> = Vec::new();
+        let mut devs_and_virtuals: Vec<(usize, &VirtDevice, Node)> = Vec::new();
 
         let mut i = 0;
         for dev in &virt.devices {
             g.add_node(format!("{}", dev.vcp));
-            devs_and_virtuals.push((i, dev, &dev.vcp));
+            devs_and_virtuals.push((i, dev, Node::Physical(&dev.vcp)));
 
             for virt in &dev.vcp.virtual_nodes {
                 if DISPLAY_VIRTUAL_NODES {
                     i += 1;
                     g.add_node(format!("{}", virt));
                 }
-                devs_and_virtuals.push((i, dev, virt));
+                devs_and_virtuals.push((i, dev, Node::Virtual(virt)));
             }
             i += 1;
         }
 
         for (i, _, v) in &devs_and_virtuals {
             // add edges by searching the right NodeIndex of successor
-            if let Some(a) = v.successor {
-                if let Some((b, _, _)) =
-                    devs_and_virtuals.iter().find(|(_, _, p)| p.c_id == Some(a))
+            if let Some(a) = v.successor() {
+                if let Some((b, _, _)) = devs_and_virtuals
+                    .iter()
+                    .find(|(_, _, p)| p.c_id() == Some(a))
                 {
                     g.add_edge(NodeIndex::new(*i), NodeIndex::new(*b), String::from("s"));
                 }
             }
             // same with predeccesor
-            if let Some(a) = v.predecessor {
-                if let Some((b, _, _)) =
-                    devs_and_virtuals.iter().find(|(_, _, p)| p.c_id == Some(a))
+            if let Some(a) = v.predecessor() {
+                if let Some((b, _, _)) = devs_and_virtuals
+                    .iter()
+                    .find(|(_, _, p)| p.c_id() == Some(a))
                 {
                     g.add_edge(NodeIndex::new(*i), NodeIndex::new(*b), String::from("p"));
                 }
@@ -208,8 +211,111 @@ impl GraphViz {
             &get_node,
         );
         let mut extras = String::new();
-        extras += &GraphViz::get_data_messages(&virt);
-        extras += &GraphViz::get_data_storage(&virt);
-        format!("digraph {{\n {} \n{extras}\n }}", dot_g.to_string())
+        extras += &GraphViz::get_data_messages(virt);
+        extras += &GraphViz::get_data_storage(virt);
+        format!("digraph {{\n {} \n{extras}\n }}", dot_g)
+    }
+
+    /// Label of a node that reported to a walk, like the `Display` of `Vcp`
+    fn walk_label(entry: &CordEntry, is_virtual: bool) -> String {
+        let mut label = String::new();
+        if is_virtual {
+            label += "v";
+        }
+        write!(label, "{}", entry.c_id).unwrap();
+        if !entry.virtual_nodes.is_empty() {
+            label += "\nvirt{";
+            for v in &entry.virtual_nodes {
+                write!(label, "{},", v).unwrap();
+            }
+            label += "}";
+        }
+        let show = |c: Option<CordId>| c.map(|c| c.to_string()).unwrap_or("?".into());
+        write!(
+            label,
+            ":\np{} s{}",
+            show(entry.predecessor),
+            show(entry.successor)
+        )
+        .unwrap();
+        label
+    }
+
+    /// Generates a GraphViz Digraph in .dot Fileformat of the nodes that reported to a walk
+    /// of the cord, see `Vcp::walk`. A walk knows no coordinates, so the devices are laid
+    /// out on a circle in the order of the cord. Dashed lines are the links of the devices.
+    pub fn generate_walk_graph(entries: &[CordEntry]) -> String {
+        let mut g = Graph::<String, String>::new();
+        let hosted: Vec<CordId> = entries
+            .iter()
+            .flat_map(|e| e.virtual_nodes.iter().copied())
+            .collect();
+        let mut devices: Vec<&CordEntry> = entries
+            .iter()
+            .filter(|e| !hosted.contains(&e.c_id))
+            .collect();
+        devices.sort_by_key(|e| e.c_id);
+
+        // the node of every position with its coordinates, virtual nodes are drawn with
+        // their host unless they are displayed
+        let mut nodes: Vec<(CordId, NodeIndex, (f64, f64))> = Vec::new();
+        let radius = WALK_SPACING * devices.len() as f64 / TAU;
+        for (n, dev) in devices.iter().enumerate() {
+            let angle = TAU * n as f64 / devices.len() as f64;
+            let pos = (radius * angle.cos(), radius * angle.sin());
+            let i = g.add_node(GraphViz::walk_label(dev, false));
+            nodes.push((dev.c_id, i, pos));
+            for (k, &v) in dev.virtual_nodes.iter().enumerate() {
+                let mut i = i;
+                if DISPLAY_VIRTUAL_NODES {
+                    let entry = entries.iter().find(|e| e.c_id == v);
+                    let label = entry.map_or(format!("v{}", v), |e| GraphViz::walk_label(e, true));
+                    i = g.add_node(label);
+                }
+                nodes.push((v, i, (pos.0 + 0.5 * (k + 1) as f64, pos.1 - 0.5)));
+            }
+        }
+        let index_of = |c: CordId| nodes.iter().find(|(p, _, _)| *p == c).map(|n| n.1);
+
+        let mut links: Vec<(CordId, CordId)> = Vec::new();
+        for e in entries {
+            let Some(a) = index_of(e.c_id) else {
+                continue;
+            };
+            if let Some(b) = e.successor.and_then(index_of) {
+                g.add_edge(a, b, String::from("s"));
+            }
+            if let Some(b) = e.predecessor.and_then(index_of) {
+                g.add_edge(a, b, String::from("p"));
+            }
+            if hosted.contains(&e.c_id) {
+                continue;
+            }
+            // the Hellos of virtual nodes are heard as well, they are no devices
+            for &n in e.neighbors.iter().filter(|n| !hosted.contains(n)) {
+                links.push((e.c_id.min(n), e.c_id.max(n)));
+            }
+        }
+        links.sort();
+        links.dedup();
+        for (a, b) in links {
+            if let (Some(a), Some(b)) = (index_of(a), index_of(b)) {
+                g.add_edge(a, b, String::from("n"));
+            }
+        }
+
+        let get_edge = |_, b: petgraph::graph::EdgeReference<'_, String, _>| {
+            if b.weight() == "n" {
+                String::from("style=dashed dir=none")
+            } else {
+                String::from("")
+            }
+        };
+        let get_node = |_, b: (NodeIndex, &String)| match nodes.iter().find(|n| n.1 == b.0) {
+            Some((_, _, pos)) => format!("pos = \"{},{}!\"", pos.0, pos.1),
+            None => String::from("\npos = \"0,0!\""),
+        };
+        let dot_g = Dot::with_attr_getters(&g, &[Config::GraphContentOnly], &get_edge, &get_node);
+        format!("digraph {{\n {} \n }}", dot_g)
     }
 }
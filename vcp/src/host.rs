@@ -0,0 +1,556 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+#[cfg(feature = "auth")]
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "auth")]
+use crate::manage::{Command, ManageError, Operator, Reply, MANAGE_PORT, MANAGE_REPLY_PORT};
+use crate::serial::{encode_message, Downlink, FrameDecoder, Uplink};
+use crate::vcp::CordId;
+
+/// Baud rate of the USB-serial console of the ESP32
+pub const BAUD: libc::speed_t = libc::B115200;
+
+/// Pass the bytes of the terminal through unchanged, no echo, no line editing
+fn make_raw(fd: RawFd) -> io::Result<()> {
+    // SAFETY: termios is plain data, and tcgetattr fills it before it is used
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut tio) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut tio);
+        if libc::cfsetspeed(&mut tio, BAUD) != 0 || libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Open the serial port of a gateway node, like `/dev/ttyUSB0`
+pub fn open_serial(path: &Path) -> io::Result<File> {
+    let serial = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    make_raw(serial.as_raw_fd())?;
+    Ok(serial)
+}
+
+/// A pseudo-terminal pair, (master, slave). The slave behaves like the serial port of a
+/// gateway, so the daemon can be run against a simulated one.
+pub fn pty_pair() -> io::Result<(File, File)> {
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: the pointers are valid or null, which openpty accepts
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: openpty opened both and nothing else owns them
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    make_raw(slave.as_raw_fd())?;
+    Ok((master, slave))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    // an odd length leaves half a byte at the end, `get` has none
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Default)]
+/// What the host knows about the mesh, from the uplinks of the gateway
+pub struct Mesh {
+    /// Position of the gateway
+    pub gateway: Option<CordId>,
+    /// The nodes the gateway heard or has as neighbors, and when that was last the case
+    pub nodes: BTreeMap<CordId, Instant>,
+}
+
+impl Mesh {
+    /// Learn from an uplink. Returns the line for the streams if it carries received data.
+    pub fn apply(&mut self, uplink: &Uplink) -> Option<String> {
+        let now = Instant::now();
+        match uplink {
+            Uplink::Packet(packet) => {
+                if let Some(cid) = packet.sender_cid {
+                    self.nodes.insert(cid, now);
+                }
+                None
+            }
+            Uplink::Status { c_id, neighbors } => {
+                self.gateway = *c_id;
+                for &cid in neighbors {
+                    self.nodes.insert(cid, now);
+                }
+                None
+            }
+            Uplink::Text { sender_cid, text } => Some(format!("text {} {:?}", sender_cid, text)),
+            Uplink::Data {
+                port,
+                source,
+                payload,
+            } => Some(format!("data {} {} {}", source, port, to_hex(payload))),
+        }
+    }
+
+    /// One line per node: its position and the seconds since it was last heard of.
+    /// The gateway is marked with a `*`.
+    pub fn list(&self) -> String {
+        let mut list = String::new();
+        if let Some(cid) = self.gateway {
+            writeln!(list, "{} 0 *", cid).unwrap();
+        }
+        for (&cid, heard) in &self.nodes {
+            if Some(cid) != self.gateway {
+                writeln!(list, "{} {}", cid, heard.elapsed().as_secs()).unwrap();
+            }
+        }
+        list
+    }
+}
+
+/// State of the daemon, shared by its threads
+struct Shared {
+    mesh: Mutex<Mesh>,
+    /// Clients that asked for the received data
+    streams: Mutex<Vec<UnixStream>>,
+    /// Like `streams`, inside the daemon, see `Host::subscribe`
+    taps: Mutex<Vec<Sender<Uplink>>>,
+    serial: Mutex<File>,
+}
+
+#[derive(Clone)]
+/// The host end of the serial line of a gateway. Clones share the line and what was
+/// learned from it.
+pub struct Host {
+    shared: Arc<Shared>,
+}
+
+impl Host {
+    /// Writes the commands for the gateway to `serial`, read it with `Host::read`
+    pub fn new(serial: &File) -> io::Result<Self> {
+        Ok(Host {
+            shared: Arc::new(Shared {
+                mesh: Mutex::new(Mesh::default()),
+                streams: Mutex::new(Vec::new()),
+                taps: Mutex::new(Vec::new()),
+                serial: Mutex::new(serial.try_clone()?),
+            }),
+        })
+    }
+
+    /// Ask the gateway to do something
+    pub fn send(&self, downlink: &Downlink) -> io::Result<()> {
+        self.shared
+            .serial
+            .lock()
+            .unwrap()
+            .write_all(&encode_message(downlink))
+    }
+
+    /// The texts and data that arrive at the gateway from now on, as `Uplink::Text` and
+    /// `Uplink::Data`
+    pub fn subscribe(&self) -> Receiver<Uplink> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.taps.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// What is known about the mesh
+    pub fn mesh(&self) -> MutexGuard<'_, Mesh> {
+        self.shared.mesh.lock().unwrap()
+    }
+
+    /// Answer the clients of `listener`, each on its own thread. A client sends one command
+    /// per line and gets the answer, ending with a line `ok` or `error <why>`:
+    ///
+    /// - `send <cid> <text>` sends the text to the node at `cid`
+    /// - `data <cid> <port> <hex>` sends the bytes to the application on `port` at `cid`
+    /// - `bind <port>` lets the data arriving at the gateway on `port` through to the streams
+    /// - `nodes` lists the known nodes, see `Mesh::list`
+    /// - `stream` turns the connection into a stream of the received data, one line each:
+    ///   `text <sender> "<text>"` or `data <source> <port> <hex>`
+    pub fn serve(&self, listener: UnixListener) {
+        let host = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let host = host.clone();
+                thread::spawn(move || host.serve_client(client));
+            }
+        });
+    }
+
+    /// Decode the uplinks of the gateway on `serial`, and pass the received data on,
+    /// until the serial line fails
+    pub fn read(&self, mut serial: File) -> io::Result<()> {
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 512];
+        loop {
+            let len = serial.read(&mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            decoder.push(&buf[..len]);
+            while let Some(uplink) = decoder.next_message::<Uplink>() {
+                let Some(line) = self.mesh().apply(&uplink) else {
+                    continue;
+                };
+                // clients that went away are dropped
+                self.shared
+                    .streams
+                    .lock()
+                    .unwrap()
+                    .retain_mut(|stream| writeln!(stream, "{}", line).is_ok());
+                self.shared
+                    .taps
+                    .lock()
+                    .unwrap()
+                    .retain(|tap| tap.send(uplink.clone()).is_ok());
+            }
+        }
+    }
+
+    /// Answer the commands of a client, see `serve`
+    fn serve_client(&self, client: UnixStream) -> io::Result<()> {
+        let mut out = client.try_clone()?;
+        for line in BufReader::new(client).lines() {
+            let line = line?;
+            let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+            let downlink = match command {
+                "send" => match args.split_once(' ').map(|(cid, text)| (cid.parse(), text)) {
+                    Some((Ok(final_cid), text)) => Downlink::Text {
+                        final_cid,
+                        text: text.into(),
+                    },
+                    _ => {
+                        writeln!(out, "error usage: send <cid> <text>")?;
+                        continue;
+                    }
+                },
+                "data" => {
+                    let mut args = args.split(' ');
+                    match (args.next(), args.next(), args.next(), args.next()) {
+                        (Some(cid), Some(port), Some(hex), None) => {
+                            match (cid.parse(), port.parse(), from_hex(hex)) {
+                                (Ok(final_cid), Ok(port), Some(payload)) => Downlink::Data {
+                                    final_cid,
+                                    port,
+                                    payload,
+                                },
+                                _ => {
+                                    writeln!(out, "error usage: data <cid> <port> <hex>")?;
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => {
+                            writeln!(out, "error usage: data <cid> <port> <hex>")?;
+                            continue;
+                        }
+                    }
+                }
+                "bind" => match args.parse() {
+                    Ok(port) => Downlink::Bind { port },
+                    Err(_) => {
+                        writeln!(out, "error usage: bind <port>")?;
+                        continue;
+                    }
+                },
+                "nodes" => {
+                    let list = self.mesh().list();
+                    writeln!(out, "{}ok", list)?;
+                    continue;
+                }
+                "stream" => {
+                    // registered before the answer, so the client gets everything after it
+                    let mut streams = self.shared.streams.lock().unwrap();
+                    writeln!(out, "ok")?;
+                    streams.push(out);
+                    return Ok(());
+                }
+                _ => {
+                    writeln!(out, "error unknown command {:?}", command)?;
+                    continue;
+                }
+            };
+            match self.send(&downlink) {
+                Ok(()) => writeln!(out, "ok")?,
+                Err(e) => writeln!(out, "error {}", e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bridge the gateway on `serial` to the clients of `listener`, see `Host::serve`, until
+/// the serial line fails
+pub fn run(serial: File, listener: UnixListener) -> io::Result<()> {
+    let host = Host::new(&serial)?;
+    host.serve(listener);
+    host.read(serial)
+}
+
+/// Write a command to the daemon and wait for its `ok`
+#[cfg(feature = "auth")]
+fn ask(client: &mut BufReader<UnixStream>, command: &str) -> Result<(), ManageError> {
+    writeln!(client.get_mut(), "{}", command).map_err(|_| ManageError::NotSent)?;
+    let mut answer = String::new();
+    match client.read_line(&mut answer) {
+        Ok(_) if answer.trim_end() == "ok" => Ok(()),
+        _ => Err(ManageError::NotSent),
+    }
+}
+
+/// The next response of the node at `node` on a stream of the daemon
+#[cfg(feature = "auth")]
+fn response(stream: &mut BufReader<UnixStream>, node: CordId) -> Result<Vec<u8>, ManageError> {
+    let expected = format!("data {} {} ", node, MANAGE_REPLY_PORT);
+    loop {
+        let mut line = String::new();
+        match stream.read_line(&mut line) {
+            Ok(len) if len > 0 => {}
+            // the read timed out, or the daemon went away
+            _ => return Err(ManageError::Timeout),
+        }
+        if let Some(hex) = line.trim_end().strip_prefix(&expected) {
+            return from_hex(hex).ok_or(ManageError::Malformed);
+        }
+    }
+}
+
+/// Have the node at `node` carry out a command, through the gateway of the daemon listening
+/// on `socket`. Waits up to `timeout` for each of the two responses, see `Operator`.
+#[cfg(feature = "auth")]
+pub fn manage(
+    socket: &Path,
+    operator: &Operator,
+    node: CordId,
+    command: &Command,
+    timeout: Duration,
+) -> Result<Reply, ManageError> {
+    let connect = || {
+        let client = UnixStream::connect(socket)?;
+        client.set_read_timeout(Some(timeout))?;
+        Ok::<_, io::Error>(BufReader::new(client))
+    };
+    let (mut stream, mut control) = match (connect(), connect()) {
+        (Ok(stream), Ok(control)) => (stream, control),
+        _ => return Err(ManageError::NotSent),
+    };
+    ask(&mut stream, "stream")?;
+    ask(&mut control, &format!("bind {}", MANAGE_REPLY_PORT))?;
+    let send = |control: &mut BufReader<UnixStream>, payload: &[u8]| {
+        let command = format!("data {} {} {}", node, MANAGE_PORT, to_hex(payload));
+        ask(control, &command)
+    };
+    send(&mut control, &Operator::challenge())?;
+    let nonce = Operator::nonce(&response(&mut stream, node)?)?;
+    send(&mut control, &operator.command(node, nonce, command))?;
+    operator.reply(node, nonce, &response(&mut stream, node)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::vcp::Vcp;
+
+    fn command(client: &mut BufReader<UnixStream>, command: &str) -> Vec<String> {
+        writeln!(client.get_mut(), "{}", command).unwrap();
+        let mut answer = vec![];
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            if line == "ok" || line.starts_with("error") {
+                answer.push(line);
+                return answer;
+            }
+            answer.push(line);
+        }
+    }
+
+    #[test]
+    fn the_daemon_talks_to_a_gateway_on_a_pty() {
+        let (master, mut gateway) = pty_pair().unwrap();
+        let socket = std::env::temp_dir().join(format!("vcp-host-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || run(master, listener));
+
+        let mut far = Vcp::new(false);
+        far.config.verbose = false;
+        far.c_id = Some(1000);
+        far.timer_call(&ManualClock::new(0));
+        let hello = far.outgoing_msgs.pop().unwrap();
+        // the gateway logs on the same console
+        gateway.write_all(b"I (42) vcp: booted\n").unwrap();
+        for uplink in [
+            Uplink::Status {
+                c_id: Some(0),
+                neighbors: vec![500],
+            },
+            Uplink::Packet(Box::new(hello)),
+        ] {
+            gateway.write_all(&encode_message(&uplink)).unwrap();
+        }
+
+        let mut client = BufReader::new(UnixStream::connect(&socket).unwrap());
+        let mut nodes = vec![];
+        for _ in 0..100 {
+            nodes = command(&mut client, "nodes");
+            if nodes.len() == 4 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(nodes, ["0 0 *", "500 0", "1000 0", "ok"]);
+        assert_eq!(
+            command(&mut client, "send x hi"),
+            ["error usage: send <cid> <text>"]
+        );
+
+        assert_eq!(command(&mut client, "send 1000 hi there"), ["ok"]);
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 256];
+        let downlink = loop {
+            let len = gateway.read(&mut buf).unwrap();
+            decoder.push(&buf[..len]);
+            if let Some(downlink) = decoder.next_message::<Downlink>() {
+                break downlink;
+            }
+        };
+        assert_eq!(
+            downlink,
+            Downlink::Text {
+                final_cid: 1000,
+                text: "hi there".into()
+            }
+        );
+
+        let mut stream = BufReader::new(UnixStream::connect(&socket).unwrap());
+        assert_eq!(command(&mut stream, "stream"), ["ok"]);
+        for uplink in [
+            Uplink::Text {
+                sender_cid: 1000,
+                text: "hello\nhost".into(),
+            },
+            Uplink::Data {
+                port: 3,
+                source: 1000,
+                payload: vec![0, 0xAB],
+            },
+        ] {
+            gateway.write_all(&encode_message(&uplink)).unwrap();
+        }
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "text 1000 \"hello\\nhost\"\n");
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "data 1000 3 00ab\n");
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn the_cli_manages_a_node_through_the_gateway() {
+        use crate::manage::Manager;
+        use crate::serial::{apply, take_uplinks};
+
+        let (master, slave) = pty_pair().unwrap();
+        let socket = std::env::temp_dir().join(format!("vcp-manage-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || run(master, listener));
+
+        // the gateway at 0 and the managed node at 1000, next to each other
+        let (downlinks, received) = mpsc::channel();
+        let mut console = slave.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut buf = [0; 512];
+            while let Ok(len) = console.read(&mut buf) {
+                decoder.push(&buf[..len]);
+                while let Some(downlink) = decoder.next_message::<Downlink>() {
+                    downlinks.send(downlink).unwrap();
+                }
+            }
+        });
+        thread::spawn(move || {
+            let mut slave = slave;
+            let (mut gateway, mut managed) = (Vcp::new(false), Vcp::new(false));
+            for (vcp, c_id) in [(&mut gateway, 0), (&mut managed, 1000)] {
+                vcp.config.verbose = false;
+                vcp.c_id = Some(c_id);
+                vcp.timer_call(&ManualClock::new(0));
+            }
+            managed.receive(&gateway.outgoing_msgs.pop().unwrap());
+            gateway.receive(&managed.outgoing_msgs.pop().unwrap());
+            let mut manager = Manager::new(b"key", 0);
+            manager.serve(&mut managed);
+            for downlink in received {
+                apply(&mut gateway, &downlink);
+                for packet in core::mem::take(&mut gateway.outgoing_msgs) {
+                    managed.receive(&packet);
+                }
+                manager.poll(&mut managed, None);
+                for packet in core::mem::take(&mut managed.outgoing_msgs) {
+                    gateway.receive(&packet);
+                }
+                for uplink in take_uplinks(&mut gateway) {
+                    slave.write_all(&encode_message(&uplink)).unwrap();
+                }
+            }
+        });
+
+        let timeout = std::time::Duration::from_secs(5);
+        let reply = manage(
+            &socket,
+            &Operator::new(b"key"),
+            1000,
+            &Command::Status,
+            timeout,
+        );
+        let Ok(Reply::Status(status)) = reply else {
+            panic!("no status: {:?}", reply);
+        };
+        assert_eq!((status.c_id, status.neighbors), (Some(1000), 1));
+        let forged = Operator::new(b"guessed key");
+        assert_eq!(
+            manage(&socket, &forged, 1000, &Command::Reboot, timeout),
+            Err(ManageError::Denied)
+        );
+        std::fs::remove_file(&socket).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+use std::{collections::BTreeMap, fmt};
+
+use vcp::vcp::CordId;
+
+use crate::dummy::{Node, VirtManager};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A violation of the cord structure, found by `VirtManager::find_inconsistencies`
+pub enum Inconsistency {
+    /// A device has no cord position yet
+    MissingCid {
+        device: usize,
+    },
+    /// More than one node claims the same position
+    DuplicateCid {
+        cid: CordId,
+        count: usize,
+    },
+    MissingSuccessor {
+        cid: CordId,
+        successor: CordId,
+    },
+    MissingPredecessor {
+        cid: CordId,
+        predecessor: CordId,
+    },
+    /// There has to be exactly one node without a predecessor
+    StartCount {
+        count: usize,
+    },
+    /// There has to be exactly one node without a successor
+    EndCount {
+        count: usize,
+    },
+    /// predecessor < self < successor does not hold
+    Unordered {
+        cid: CordId,
+        predecessor: Option<CordId>,
+        successor: Option<CordId>,
+    },
+    /// `cid` points to `successor`, but its predecessor is `back`
+    AsymmetricSuccessor {
+        cid: CordId,
+        successor: CordId,
+        back: Option<CordId>,
+    },
+    /// `cid` points to `predecessor`, but its successor is `back`
+    AsymmetricPredecessor {
+        cid: CordId,
+        predecessor: CordId,
+        back: Option<CordId>,
+    },
+    /// Following the successors from the start does not visit every node
+    Disconnected {
+        reached: usize,
+        total: usize,
+    },
+    /// A virtual node has to be hosted by exactly one physical device
+    VirtualHosts {
+        cid: CordId,
+        hosts: usize,
+    },
+    /// The position is outside of `cord_start..=cord_end`
+    OutOfRange {
+        cid: CordId,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn opt(c: &Option<CordId>) -> String {
+            c.map(|a| a.to_string()).unwrap_or("?".into())
+        }
+        match self {
+            Inconsistency::MissingCid { device } => {
+                write!(f, "device {} has no valid cid", device)
+            }
+            Inconsistency::DuplicateCid { cid, count } => {
+                write!(f, "cid {} is used by {} nodes", cid, count)
+            }
+            Inconsistency::MissingSuccessor { cid, successor } => {
+                write!(f, "{} successor {} does not exist", cid, successor)
+            }
+            Inconsistency::MissingPredecessor { cid, predecessor } => {
+                write!(f, "{} predecessor {} does not exist", cid, predecessor)
+            }
+            Inconsistency::StartCount { count } => {
+                write!(f, "{} nodes without predecessor", count)
+            }
+            Inconsistency::EndCount { count } => write!(f, "{} nodes without successor", count),
+            Inconsistency::Unordered {
+                cid,
+                predecessor,
+                successor,
+            } => write!(
+                f,
+                "{} is not between predecessor {} and successor {}",
+                cid,
+                opt(predecessor),
+                opt(successor)
+            ),
+            Inconsistency::AsymmetricSuccessor {
+                cid,
+                successor,
+                back,
+            } => write!(
+                f,
+                "{} has successor {}, but its predecessor is {}",
+                cid,
+                successor,
+                opt(back)
+            ),
+            Inconsistency::AsymmetricPredecessor {
+                cid,
+                predecessor,
+                back,
+            } => write!(
+                f,
+                "{} has predecessor {}, but its successor is {}",
+                cid,
+                predecessor,
+                opt(back)
+            ),
+            Inconsistency::Disconnected { reached, total } => {
+                write!(f, "cord only reaches {} of {} nodes", reached, total)
+            }
+            Inconsistency::VirtualHosts { cid, hosts } => {
+                write!(f, "virtual node {} is hosted by {} devices", cid, hosts)
+            }
+            Inconsistency::OutOfRange { cid } => write!(f, "{} is outside of the cord", cid),
+        }
+    }
+}
+
+impl VirtManager {
+    /// Check the whole network and collect every violation of the cord structure.
+    /// An empty result means the network is consistent.
+    pub fn find_inconsistencies(&self) -> Vec<Inconsistency> {
+        let mut res = Vec::new();
+
+        // All physical and virtual nodes
+        for (i, dev) in self.devices.iter().enumerate() {
+            if dev.vcp.c_id.is_none() {
+                res.push(Inconsistency::MissingCid { device: i });
+            }
+        }
+        // the structure is checked between the nodes that have a position
+        let nodes: Vec<Node> = self.nodes().filter(|v| v.c_id().is_some()).collect();
+
+        // check if each CID is unique
+        let mut by_cid: BTreeMap<CordId, Vec<Node>> = BTreeMap::new();
+        for v in &nodes {
+            if let Some(cid) = v.c_id() {
+                by_cid.entry(cid).or_default().push(*v);
+            }
+        }
+        for (&cid, all) in &by_cid {
+            if all.len() > 1 {
+                res.push(Inconsistency::DuplicateCid {
+                    cid,
+                    count: all.len(),
+                });
+            }
+        }
+        let find = |cid: CordId| by_cid.get(&cid).map(|all| all[0]);
+
+        let (start, end) = (self.config.vcp.cord_start, self.config.vcp.cord_end);
+        for v in &nodes {
+            let Some(cid) = v.c_id() else { continue };
+            if cid < start || cid > end {
+                res.push(Inconsistency::OutOfRange { cid });
+            }
+            if v.predecessor().is_some_and(|p| p >= cid) || v.successor().is_some_and(|s| s <= cid)
+            {
+                res.push(Inconsistency::Unordered {
+                    cid,
+                    predecessor: v.predecessor(),
+                    successor: v.successor(),
+                });
+            }
+
+            // check if all successor and predecessor exist and point back
+            if let Some(successor) = v.successor() {
+                match find(successor) {
+                    None => res.push(Inconsistency::MissingSuccessor { cid, successor }),
+                    Some(s) if s.predecessor() != Some(cid) => {
+                        res.push(Inconsistency::AsymmetricSuccessor {
+                            cid,
+                            successor,
+                            back: s.predecessor(),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+            if let Some(predecessor) = v.predecessor() {
+                match find(predecessor) {
+                    None => res.push(Inconsistency::MissingPredecessor { cid, predecessor }),
+                    Some(p) if p.successor() != Some(cid) => {
+                        res.push(Inconsistency::AsymmetricPredecessor {
+                            cid,
+                            predecessor,
+                            back: p.successor(),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        // check there is only one End and one Start
+        let starts: Vec<Node> = nodes
+            .iter()
+            .filter(|p| p.predecessor().is_none())
+            .copied()
+            .collect();
+        if starts.len() != 1 {
+            res.push(Inconsistency::StartCount {
+                count: starts.len(),
+            });
+        }
+        let ends = nodes.iter().filter(|p| p.successor().is_none()).count();
+        if ends != 1 {
+            res.push(Inconsistency::EndCount { count: ends });
+        }
+
+        // walk the cord from the start, it has to visit every node once
+        if let Some(first) = starts.first() {
+            let mut visited = vec![first.c_id()];
+            let mut cur = *first;
+            while let Some(next) = cur.successor().and_then(find) {
+                if visited.contains(&next.c_id()) {
+                    break;
+                }
+                visited.push(next.c_id());
+                cur = next;
+            }
+            if visited.len() != nodes.len() {
+                res.push(Inconsistency::Disconnected {
+                    reached: visited.len(),
+                    total: nodes.len(),
+                });
+            }
+        }
+
+        // every virtual node is hosted by exactly one physical device
+        let mut hosts: BTreeMap<CordId, usize> = BTreeMap::new();
+        for dev in &self.devices {
+            for virt in &dev.vcp.virtual_nodes {
+                if let Some(cid) = virt.c_id {
+                    *hosts.entry(cid).or_default() += 1;
+                }
+            }
+        }
+        for (cid, hosts) in hosts {
+            if hosts != 1 {
+                res.push(Inconsistency::VirtualHosts { cid, hosts });
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vcp::vcp::VirtualNode;
+
+    /// Build a network of devices with fixed positions and links
+    fn manager(nodes: &[(CordId, Option<CordId>, Option<CordId>)]) -> VirtManager {
+        let mut mgr = VirtManager::new();
+        for &(cid, predecessor, successor) in nodes {
+            mgr.add_device((0, 0));
+            let vcp = &mut mgr.devices.last_mut().unwrap().vcp;
+            vcp.c_id = Some(cid);
+            vcp.predecessor = predecessor;
+            vcp.successor = successor;
+        }
+        mgr
+    }
+
+    #[test]
+    fn consistent_chain() {
+        let mgr = manager(&[
+            (0, None, Some(500)),
+            (500, Some(0), Some(1000)),
+            (1000, Some(500), None),
+        ]);
+        assert_eq!(mgr.find_inconsistencies(), vec![]);
+    }
+
+    #[test]
+    fn collects_all_violations() {
+        let mut mgr = manager(&[
+            (0, None, Some(500)),
+            (500, Some(0), Some(400)),
+            (400, Some(500), None),
+            (1200, None, None),
+        ]);
+        let virt = VirtualNode::new_virtual(1200, Default::default());
+        mgr.devices[0].vcp.virtual_nodes.extend([virt]);
+
+        let found = mgr.find_inconsistencies();
+        assert!(found.contains(&Inconsistency::DuplicateCid {
+            cid: 1200,
+            count: 2
+        }));
+        assert!(found.contains(&Inconsistency::OutOfRange { cid: 1200 }));
+        assert!(found.contains(&Inconsistency::Unordered {
+            cid: 500,
+            predecessor: Some(0),
+            successor: Some(400)
+        }));
+        assert!(found.contains(&Inconsistency::StartCount { count: 3 }));
+        assert!(found.contains(&Inconsistency::EndCount { count: 3 }));
+        assert!(found.contains(&Inconsistency::Disconnected {
+            reached: 3,
+            total: 5
+        }));
+    }
+
+    #[test]
+    fn missing_cid_does_not_hide_the_rest() {
+        let mut mgr = manager(&[(0, None, Some(500)), (500, Some(0), Some(400))]);
+        mgr.add_device((0, 0));
+        mgr.devices[2].vcp.c_id = None;
+
+        let found = mgr.find_inconsistencies();
+        assert!(found.contains(&Inconsistency::MissingCid { device: 2 }));
+        assert!(found.contains(&Inconsistency::MissingSuccessor {
+            cid: 500,
+            successor: 400
+        }));
+        assert!(found.contains(&Inconsistency::EndCount { count: 0 }));
+    }
+
+    #[test]
+    fn asymmetric_links() {
+        let mgr = manager(&[
+            (0, None, Some(500)),
+            (300, Some(0), Some(500)),
+            (500, Some(300), None),
+        ]);
+        let found = mgr.find_inconsistencies();
+        assert!(found.contains(&Inconsistency::AsymmetricSuccessor {
+            cid: 0,
+            successor: 500,
+            back: Some(300)
+        }));
+        assert!(found.contains(&Inconsistency::AsymmetricPredecessor {
+            cid: 300,
+            predecessor: 0,
+            back: Some(500)
+        }));
+        assert!(found.contains(&Inconsistency::Disconnected {
+            reached: 2,
+            total: 3
+        }));
+    }
+
+    #[test]
+    fn virtual_node_on_two_devices() {
+        let mut mgr = manager(&[(0, None, Some(1000)), (1000, Some(0), None)]);
+        for dev in mgr.devices.iter_mut() {
+            let virt = VirtualNode::new_virtual(500, Default::default());
+            dev.vcp.virtual_nodes.extend([virt]);
+        }
+        let found = mgr.find_inconsistencies();
+        assert!(found.contains(&Inconsistency::VirtualHosts { cid: 500, hosts: 2 }));
+    }
+}
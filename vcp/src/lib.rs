@@ -1,7 +1,33 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 use petgraph::algo::dijkstra;
+#[cfg(feature = "std")]
 use petgraph::prelude::Graph;
 
+pub mod app;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod clock;
+pub mod collections;
+#[cfg(feature = "e2e")]
+pub mod e2e;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod host;
+pub mod limits;
+pub mod link;
+#[cfg(feature = "auth")]
+pub mod manage;
+#[cfg(all(feature = "mqtt", target_os = "linux"))]
+pub mod mqtt;
+pub mod persist;
+pub mod rpc;
+pub mod serial;
 pub mod vcp;
+
+#[cfg(feature = "std")]
 pub fn complex_example_func() {
     // Create the same graph as in the main function
     let mut graph = Graph::<&str, u32>::new();
@@ -15,11 +41,10 @@ pub fn complex_example_func() {
     assert_eq!(graph.node_count(), 3);
     assert_eq!(graph.edge_count(), 2);
 
-    // Test the neighbors of a node
-    assert_eq!(
-        graph.neighbors(origin).collect::<Vec<_>>(),
-        vec![destination_1, destination_2]
-    );
+    // Test the neighbors of a node, petgraph does not guarantee their order
+    let mut neighbors = graph.neighbors(origin).collect::<Vec<_>>();
+    neighbors.sort();
+    assert_eq!(neighbors, vec![destination_1, destination_2]);
 
     // Test the shortest path from origin to destination_2
     let node_map = dijkstra(&graph, origin, Some(destination_2), |e| *e.weight());
@@ -41,6 +66,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn complex_example() {
         complex_example_func();
     }
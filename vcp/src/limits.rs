@@ -0,0 +1,277 @@
+use core::ops::AddAssign;
+
+use crate::collections::Queue;
+
+/// Capacities of `Limits::constrained`.
+/// With the `heapless` feature they are also the compile-time sizes of the tables.
+pub const MAX_OUTGOING_MSGS: usize = 32;
+pub const MAX_DATA_STORAGE: usize = 8;
+pub const MAX_NEIGHBORS: usize = 16;
+pub const MAX_VIRTUAL_NODES: usize = 4;
+pub const MAX_KEYS: usize = 16;
+/// Ports a node can accept data on, see `Vcp::bind`
+pub const MAX_PORTS: usize = 8;
+/// Fragmented messages a node reassembles at once
+pub const MAX_REASSEMBLY: usize = 2;
+/// Fragments of a message, with the `heapless` feature. Without it up to 255.
+/// The longest text or sealed payload takes about 10 in a signed ESP-NOW frame.
+pub const MAX_FRAGMENTS: usize = 16;
+/// Topics a node can subscribe to, see `Vcp::subscribe`
+pub const MAX_TOPICS: usize = 8;
+/// Subscriptions a rendezvous node keeps, of all its topics
+pub const MAX_SUBSCRIBERS: usize = 16;
+/// Floods a node remembers, to forward each of them once
+pub const MAX_FLOODS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What happens when a full table or queue gets a new entry
+pub enum DropPolicy {
+    /// Evict the oldest entry to make room
+    DropOldest,
+    /// Evict the entry with the lowest priority, unless the new one is even lower
+    DropLowestPriority,
+    /// Keep the table as it is and drop the new entry
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Capacity of a single table or queue
+pub struct Limit {
+    pub capacity: usize,
+    pub policy: DropPolicy,
+}
+
+impl Limit {
+    pub const fn new(capacity: usize, policy: DropPolicy) -> Self {
+        Limit { capacity, policy }
+    }
+
+    pub const fn unbounded() -> Self {
+        Limit::new(usize::MAX, DropPolicy::Reject)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Capacities of everything a `Vcp` keeps in memory
+pub struct Limits {
+    pub outgoing_msgs: Limit,
+    pub data_storage: Limit,
+    pub neighbors: Limit,
+    pub virtual_nodes: Limit,
+    /// Public keys of other nodes, see `Vcp::keys`
+    pub keys: Limit,
+    /// Fragmented messages that are reassembled at once
+    pub reassembly: Limit,
+    /// Subscriptions kept for the topics this node is the rendezvous of
+    pub subscribers: Limit,
+    /// Floods remembered to suppress their duplicates
+    pub floods: Limit,
+}
+
+impl Default for Limits {
+    /// Everything unbounded, like on a PC
+    #[cfg(not(feature = "heapless"))]
+    fn default() -> Self {
+        Limits {
+            outgoing_msgs: Limit::unbounded(),
+            data_storage: Limit::unbounded(),
+            neighbors: Limit::unbounded(),
+            virtual_nodes: Limit::unbounded(),
+            keys: Limit::unbounded(),
+            reassembly: Limit::unbounded(),
+            subscribers: Limit::unbounded(),
+            floods: Limit::unbounded(),
+        }
+    }
+
+    /// The tables have fixed sizes, use them
+    #[cfg(feature = "heapless")]
+    fn default() -> Self {
+        Limits::constrained()
+    }
+}
+
+impl Limits {
+    /// Limits for a microcontroller like the ESP32-S3.
+    /// Control packets are kept over texts and Hellos, far neighbors and keys of far nodes
+    /// are forgotten first.
+    pub const fn constrained() -> Self {
+        Limits {
+            outgoing_msgs: Limit::new(MAX_OUTGOING_MSGS, DropPolicy::DropLowestPriority),
+            data_storage: Limit::new(MAX_DATA_STORAGE, DropPolicy::DropOldest),
+            neighbors: Limit::new(MAX_NEIGHBORS, DropPolicy::DropLowestPriority),
+            virtual_nodes: Limit::new(MAX_VIRTUAL_NODES, DropPolicy::Reject),
+            keys: Limit::new(MAX_KEYS, DropPolicy::DropLowestPriority),
+            reassembly: Limit::new(MAX_REASSEMBLY, DropPolicy::DropOldest),
+            subscribers: Limit::new(MAX_SUBSCRIBERS, DropPolicy::DropOldest),
+            floods: Limit::new(MAX_FLOODS, DropPolicy::DropOldest),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Usage counters of a single table or queue
+pub struct QueueStats {
+    /// Old entries removed to make room for new ones
+    pub evicted: u64,
+    /// New entries that were not taken
+    pub rejected: u64,
+    /// The most entries the table had at once
+    pub peak: usize,
+}
+
+impl QueueStats {
+    pub fn dropped(&self) -> u64 {
+        self.evicted + self.rejected
+    }
+}
+
+impl AddAssign for QueueStats {
+    /// Sum up the drops, keep the highest peak
+    fn add_assign(&mut self, other: Self) {
+        self.evicted += other.evicted;
+        self.rejected += other.rejected;
+        self.peak = self.peak.max(other.peak);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Usage counters of everything a `Vcp` keeps in memory
+pub struct VcpStats {
+    pub outgoing_msgs: QueueStats,
+    pub data_storage: QueueStats,
+    pub neighbors: QueueStats,
+    pub virtual_nodes: QueueStats,
+    pub keys: QueueStats,
+    pub reassembly: QueueStats,
+    pub subscribers: QueueStats,
+    pub floods: QueueStats,
+    /// Messages not sent, because they need too many fragments
+    pub oversized: u64,
+    /// Fragmented messages given up, because fragments did not arrive in time
+    pub incomplete: u64,
+    /// Anycasts dropped, because no provider of their service was known
+    pub unreachable: u64,
+}
+
+impl VcpStats {
+    pub fn dropped(&self) -> u64 {
+        self.outgoing_msgs.dropped()
+            + self.data_storage.dropped()
+            + self.neighbors.dropped()
+            + self.virtual_nodes.dropped()
+            + self.keys.dropped()
+            + self.reassembly.dropped()
+            + self.subscribers.dropped()
+            + self.floods.dropped()
+            + self.oversized
+            + self.incomplete
+            + self.unreachable
+    }
+}
+
+impl AddAssign for VcpStats {
+    fn add_assign(&mut self, other: Self) {
+        self.outgoing_msgs += other.outgoing_msgs;
+        self.data_storage += other.data_storage;
+        self.neighbors += other.neighbors;
+        self.virtual_nodes += other.virtual_nodes;
+        self.keys += other.keys;
+        self.reassembly += other.reassembly;
+        self.subscribers += other.subscribers;
+        self.floods += other.floods;
+        self.oversized += other.oversized;
+        self.incomplete += other.incomplete;
+        self.unreachable += other.unreachable;
+    }
+}
+
+/// Find the entry that has to make room for a new one with `new_priority`.
+/// `None` means the new entry is rejected.
+pub fn victim<T>(
+    entries: impl Iterator<Item = T>,
+    limit: &Limit,
+    new_priority: u32,
+    priority: impl Fn(&T) -> u32,
+) -> Option<T> {
+    match limit.policy {
+        DropPolicy::Reject => None,
+        DropPolicy::DropOldest => entries.into_iter().next(),
+        DropPolicy::DropLowestPriority => entries
+            // the first (oldest) of the lowest
+            .fold(None, |low: Option<T>, e| match low {
+                Some(l) if priority(&l) <= priority(&e) => Some(l),
+                _ => Some(e),
+            })
+            .filter(|e| priority(e) <= new_priority),
+    }
+}
+
+/// Append to a queue that is ordered from old to new, respecting the limit
+/// and the capacity of the queue. Returns false if the item was rejected.
+pub fn push_limited<T>(
+    queue: &mut impl Queue<T>,
+    item: T,
+    limit: &Limit,
+    stats: &mut QueueStats,
+    priority: impl Fn(&T) -> u32,
+) -> bool {
+    if queue.len() >= limit.capacity.min(queue.max_len()) {
+        let new_priority = priority(&item);
+        let Some(i) = victim(queue.iter().enumerate(), limit, new_priority, |(_, e)| {
+            priority(e)
+        })
+        .map(|(i, _)| i) else {
+            stats.rejected += 1;
+            return false;
+        };
+        queue.remove(i);
+        stats.evicted += 1;
+    }
+    if queue.try_push(item).is_err() {
+        stats.rejected += 1;
+        return false;
+    }
+    stats.peak = stats.peak.max(queue.len());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn push_all(policy: DropPolicy, items: &[u32]) -> (Vec<u32>, QueueStats) {
+        let mut queue = Vec::new();
+        let mut stats = QueueStats::default();
+        for &i in items {
+            push_limited(&mut queue, i, &Limit::new(3, policy), &mut stats, |&p| p);
+        }
+        (queue, stats)
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (queue, stats) = push_all(DropPolicy::DropOldest, &[1, 2, 3, 4, 5]);
+        assert_eq!(queue, vec![3, 4, 5]);
+        assert_eq!(stats.evicted, 2);
+        assert_eq!(stats.peak, 3);
+    }
+
+    #[test]
+    fn drop_lowest_priority() {
+        let (queue, stats) = push_all(DropPolicy::DropLowestPriority, &[2, 1, 3, 0, 4, 1]);
+        assert_eq!(queue, vec![2, 3, 4]);
+        assert_eq!(stats.evicted, 1);
+        // 0 and the last 1 are lower than everything in the queue
+        assert_eq!(stats.rejected, 2);
+    }
+
+    #[test]
+    fn reject() {
+        let (queue, stats) = push_all(DropPolicy::Reject, &[1, 2, 3, 4]);
+        assert_eq!(queue, vec![1, 2, 3]);
+        assert_eq!(stats.dropped(), 1);
+        assert_eq!(push_all(DropPolicy::Reject, &[]).1.peak, 0);
+    }
+}
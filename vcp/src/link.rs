@@ -0,0 +1,193 @@
+/// Number of Hellos the reception ratio is measured over
+pub const LINK_WINDOW: u16 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// When a link is good enough to be used.
+/// A link is taken at the `add_` thresholds and given up below the `drop_` thresholds,
+/// so a link close to a threshold does not flap.
+pub struct LinkConfig {
+    /// Share of the Hellos in the window that have to arrive, to start using a link
+    pub add_ratio: f32,
+    /// A used link is given up below this share
+    pub drop_ratio: f32,
+    /// Hellos heard from a neighbor before it is used
+    pub min_hellos: u16,
+    /// Weakest average signal in dBm to start using a link, if the transport reports it
+    pub add_rssi: f32,
+    /// A used link is given up below this average signal in dBm
+    pub drop_rssi: f32,
+    /// Weight of a new sample in the average signal, between 0.0 and 1.0
+    pub rssi_alpha: f32,
+    /// Texts are not forwarded over links with a lower share of arrived Hellos
+    pub route_ratio: f32,
+}
+
+impl Default for LinkConfig {
+    /// Every link is used until it loses most of its Hellos
+    fn default() -> Self {
+        LinkConfig {
+            add_ratio: 0.5,
+            drop_ratio: 0.3,
+            min_hellos: 1,
+            add_rssi: -90.0,
+            drop_rssi: -95.0,
+            rssi_alpha: 0.25,
+            route_ratio: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+/// How well the Hellos of a neighbor arrive, measured by the receiver
+pub struct LinkQuality {
+    /// Sequence number of the last Hello heard
+    last_seq: Option<u16>,
+    /// One bit per Hello of the window, set if it arrived. The lowest bit is the last Hello.
+    received: u16,
+    /// Hellos the window covers, it is not full until `LINK_WINDOW` Hellos were sent
+    expected: u16,
+    /// Hellos heard in total, up to `u16::MAX`
+    heard: u16,
+    /// Moving average of the signal strength in dBm
+    rssi: Option<f32>,
+    /// The link fell below the drop thresholds, or did not reach the add thresholds yet
+    weak: bool,
+}
+
+impl LinkQuality {
+    /// Account for a Hello with the sequence number `seq`
+    pub fn on_hello(&mut self, seq: u16, rssi: Option<i8>, config: &LinkConfig) {
+        let mut gap = self
+            .last_seq
+            .map(|last| seq.wrapping_sub(last))
+            .unwrap_or(1);
+        if gap == 0 {
+            // a duplicate, it says nothing about the link
+            return;
+        }
+        if gap > LINK_WINDOW {
+            // far ahead or behind, the neighbor restarted: start over
+            *self = LinkQuality {
+                rssi: self.rssi,
+                ..LinkQuality::default()
+            };
+            gap = 1;
+        }
+        self.received = self.received.checked_shl(gap.into()).unwrap_or(0) | 1;
+        self.expected = (self.expected + gap).min(LINK_WINDOW);
+        self.heard = self.heard.saturating_add(1);
+        self.last_seq = Some(seq);
+        if let Some(sample) = rssi.map(f32::from) {
+            let alpha = config.rssi_alpha;
+            self.rssi = Some(match self.rssi {
+                Some(avg) => avg + alpha * (sample - avg),
+                None => sample,
+            });
+        }
+        self.update_weak(config);
+    }
+
+    fn update_weak(&mut self, config: &LinkConfig) {
+        let ratio = self.ratio();
+        // without a measurement the signal counts as good enough
+        let rssi = self.rssi.unwrap_or(0.0);
+        if self.weak {
+            self.weak = self.heard < config.min_hellos
+                || ratio < config.add_ratio
+                || rssi < config.add_rssi;
+        } else {
+            self.weak = self.heard < config.min_hellos
+                || ratio < config.drop_ratio
+                || rssi < config.drop_rssi;
+        }
+    }
+
+    /// Share of the Hellos of the window that arrived, 1.0 for a link without Hellos yet
+    pub fn ratio(&self) -> f32 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+        let window = self.received & (u16::MAX >> (LINK_WINDOW - self.expected));
+        window.count_ones() as f32 / self.expected as f32
+    }
+
+    /// Moving average of the signal strength in dBm, if the transport reports it
+    pub fn rssi(&self) -> Option<f32> {
+        self.rssi
+    }
+
+    /// The link is good enough to count as neighbor
+    pub fn is_usable(&self) -> bool {
+        !self.weak
+    }
+
+    /// Texts can be forwarded over the link
+    pub fn is_routable(&self, config: &LinkConfig) -> bool {
+        self.is_usable() && self.ratio() >= config.route_ratio
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hear(link: &mut LinkQuality, seqs: impl IntoIterator<Item = u16>) {
+        for seq in seqs {
+            link.on_hello(seq, None, &LinkConfig::default());
+        }
+    }
+
+    #[test]
+    fn ratio_counts_missed_hellos() {
+        let mut link = LinkQuality::default();
+        hear(&mut link, [10, 11, 13]);
+        assert_eq!(link.ratio(), 0.75);
+        // duplicates don't count
+        hear(&mut link, [13]);
+        assert_eq!(link.ratio(), 0.75);
+        // the window slides, old losses are forgotten
+        hear(&mut link, 14..40);
+        assert_eq!(link.ratio(), 1.0);
+        // the neighbor restarted, then its sequence numbers wrap around
+        hear(&mut link, [u16::MAX - 1, u16::MAX, 1]);
+        assert_eq!(link.ratio(), 0.75);
+        assert_eq!(link.heard, 3);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let config = LinkConfig::default();
+        let mut link = LinkQuality::default();
+        hear(&mut link, [0]);
+        assert!(link.is_usable());
+        // every fourth Hello arrives: below the drop ratio
+        hear(&mut link, (1..5).map(|i| i * 4));
+        assert!(!link.is_usable());
+        // every second Hello arrives: above the drop ratio, but not back at the add ratio yet
+        hear(&mut link, (11..17).map(|i| i * 2));
+        assert!(link.ratio() >= config.drop_ratio && link.ratio() < config.add_ratio);
+        assert!(!link.is_usable());
+        hear(&mut link, 33..40);
+        assert!(link.is_usable());
+        assert!(link.is_routable(&config));
+    }
+
+    #[test]
+    fn weak_signal() {
+        let config = LinkConfig {
+            min_hellos: 2,
+            ..LinkConfig::default()
+        };
+        let mut link = LinkQuality::default();
+        link.on_hello(0, Some(-60), &config);
+        assert!(!link.is_usable());
+        link.on_hello(1, Some(-60), &config);
+        assert!(link.is_usable());
+        for seq in 2..20 {
+            link.on_hello(seq, Some(-100), &config);
+        }
+        assert!(link.rssi().unwrap() < config.drop_rssi);
+        assert!(!link.is_usable());
+    }
+}
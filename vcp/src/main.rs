@@ -1,11 +1,21 @@
+use crate::experiment::{format_table, Experiment, SweepGrid};
+use crate::graphing::GraphViz;
 use crate::playground::Playground;
+use crate::scenario::Scenario;
 use rand::Rng;
-use std::fs;
+use std::{path::Path, time::Duration};
+use vcp::{
+    limits::Limits,
+    rpc::{Method, MethodId},
+    vcp::HelloSchedule,
+};
 
 mod dummy;
+mod experiment;
 mod graphing;
+mod invariants;
 mod playground;
-mod vcp;
+mod scenario;
 
 fn example_fail(play: &mut Playground) {
     play.add_device(0, 0); // will try to send the Init message
@@ -51,6 +61,46 @@ fn example1_send_data(play: &mut Playground) {
     play.ticks(10);
 }
 
+/// Asks a device where it stands
+struct WhereAreYou;
+
+impl Method for WhereAreYou {
+    const ID: MethodId = 1;
+    type Request = ();
+    type Response = (i32, i32);
+}
+
+fn example_rpc(play: &mut Playground) {
+    play.add_device(2, -2);
+    play.add_device(3, 5);
+    play.add_device(0, 14);
+    play.add_device(4, 20);
+    play.add_device(10, 8);
+    play.ticks(10);
+
+    play.serve::<WhereAreYou>(|position, _, ()| position);
+    play.call::<WhereAreYou>(0, 1000, &());
+    play.call::<WhereAreYou>(1000, 0, &());
+}
+
+/// Map the network from one device and draw what the walk found
+fn example_walk(play: &mut Playground) {
+    play.add_device(2, -2);
+    play.add_device(3, 5);
+    play.add_device(0, 14);
+    play.add_device(4, 20);
+    play.add_device(10, 8);
+    play.add_device(-7, 5);
+    play.ticks(10);
+
+    play.mgr.devices[3].vcp.walk();
+    play.ticks(10);
+    let topology = &play.mgr.devices[3].vcp.topology;
+    println!("\nThe walk found {} nodes", topology.len());
+    let dot = GraphViz::generate_walk_graph(topology);
+    GraphViz::save_to_svg(&dot, Path::new("out/walk.svg")).expect("could not write graph");
+}
+
 fn example_rnd(play: &mut Playground) {
     let mut r = rand::thread_rng();
     for _ in 0..10 {
@@ -59,11 +109,170 @@ fn example_rnd(play: &mut Playground) {
     play.ticks(10);
 }
 
+/// What happens with more nodes, bigger areas and lossy links?
+fn example_sweep() {
+    let mut exp = Experiment::new(SweepGrid {
+        node_count: vec![10, 25, 50],
+        area: vec![20, 40],
+        range: vec![10],
+        loss: vec![0.0, 0.1],
+        hello_interval: vec![Duration::from_secs(1), Duration::from_secs(2)],
+        neighbor_timeout: vec![Duration::from_secs(5)],
+        hello_schedule: vec![HelloSchedule::Fixed],
+    });
+    exp.seeds = 8;
+    print!("{}", format_table(&exp.run()));
+}
+
+/// The sweep again, with the limits of a microcontroller.
+/// Compare the peaks with the unbounded sweep to size `Limits::constrained`.
+fn example_sweep_constrained() {
+    let mut exp = Experiment::new(SweepGrid {
+        node_count: vec![10, 25, 50],
+        area: vec![20, 40],
+        range: vec![10],
+        loss: vec![0.0, 0.1],
+        hello_interval: vec![Duration::from_secs(1)],
+        neighbor_timeout: vec![Duration::from_secs(5)],
+        hello_schedule: vec![HelloSchedule::Fixed],
+    });
+    exp.seeds = 8;
+    exp.limits = Limits::constrained();
+    print!("{}", format_table(&exp.run()));
+}
+
+/// How many Hellos does the adaptive schedule save, and does the cord still converge?
+fn example_sweep_beacon() {
+    let mut exp = Experiment::new(SweepGrid {
+        node_count: vec![10, 25, 50],
+        area: vec![20, 40],
+        range: vec![10],
+        loss: vec![0.0, 0.1],
+        hello_interval: vec![Duration::from_secs(1)],
+        neighbor_timeout: vec![Duration::from_secs(20)],
+        hello_schedule: vec![
+            HelloSchedule::Fixed,
+            HelloSchedule::Adaptive {
+                max_interval: Duration::from_secs(4),
+            },
+            HelloSchedule::Adaptive {
+                max_interval: Duration::from_secs(8),
+            },
+        ],
+    });
+    exp.seeds = 8;
+    exp.settle_rounds = 100;
+    print!("{}", format_table(&exp.run()));
+}
+
 fn main() {
+    // choose the example with `cargo run -- <name>`
+    let example = std::env::args().nth(1).unwrap_or("send_data".into());
+    if example == "sweep" {
+        example_sweep();
+        return;
+    }
+    if example == "sweep-constrained" {
+        example_sweep_constrained();
+        return;
+    }
+    if example == "sweep-beacon" {
+        example_sweep_beacon();
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    if example == "gateway" {
+        // bridge a gateway node to a local socket, e.g. `cargo run -- gateway /dev/ttyUSB0 /tmp/vcp.sock`,
+        // and with the `mqtt` feature to a broker: `... /tmp/vcp.sock localhost:1883`
+        let port = std::env::args().nth(2).expect("missing serial port");
+        let socket = std::env::args().nth(3).unwrap_or("/tmp/vcp.sock".into());
+        let serial = vcp::host::open_serial(Path::new(&port)).unwrap();
+        let _ = std::fs::remove_file(&socket);
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        println!("Bridging {} to {}", port, socket);
+        let host = vcp::host::Host::new(&serial).unwrap();
+        host.serve(listener);
+        #[cfg(feature = "mqtt")]
+        if let Some(broker) = std::env::args().nth(4) {
+            let (address, mqtt_port) = broker.split_once(':').unwrap_or((&broker, "1883"));
+            let options =
+                rumqttc::MqttOptions::new("vcp-gateway", address, mqtt_port.parse().unwrap());
+            println!("Bridging to the MQTT broker at {}", broker);
+            let host = host.clone();
+            std::thread::spawn(move || vcp::mqtt::bridge(&host, options));
+        }
+        if let Err(e) = host.read(serial) {
+            eprintln!("Serial line failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    #[cfg(all(target_os = "linux", feature = "auth"))]
+    if example == "manage" {
+        // manage a node through a running gateway, with the key in VCP_MANAGE_KEY, e.g.
+        // `cargo run --features auth -- manage /tmp/vcp.sock 1000 status`
+        use vcp::manage::{Command, Operator, Settings, MAX_LOG_LEVEL};
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        let usage =
+            "usage: manage <socket> <cid> status|reboot|rejoin|hello <ms>|timeout <ms>|log <0-5>";
+        let (Some(socket), Some(Ok(node)), Some(name)) = (
+            args.first(),
+            args.get(1).map(|cid| cid.parse()),
+            args.get(2),
+        ) else {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        };
+        let value = args.get(3).and_then(|v| v.parse::<u64>().ok());
+        let command = match (name.as_str(), value) {
+            ("status", None) => Command::Status,
+            ("reboot", None) => Command::Reboot,
+            ("rejoin", None) => Command::Rejoin,
+            ("hello", Some(ms)) => Command::Configure(Settings {
+                hello_interval: Some(ms),
+                ..Default::default()
+            }),
+            ("timeout", Some(ms)) => Command::Configure(Settings {
+                neighbor_timeout: Some(ms),
+                ..Default::default()
+            }),
+            ("log", Some(level)) if level <= MAX_LOG_LEVEL as u64 => Command::Configure(Settings {
+                log_level: Some(level as u8),
+                ..Default::default()
+            }),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        };
+        let key = std::env::var("VCP_MANAGE_KEY").expect("missing VCP_MANAGE_KEY");
+        let operator = Operator::new(key.as_bytes());
+        let timeout = Duration::from_secs(10);
+        match vcp::host::manage(Path::new(socket), &operator, node, &command, timeout) {
+            Ok(reply) => println!("{:#?}", reply),
+            Err(e) => {
+                eprintln!("{} failed: {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if example == "replay" {
+        // replay a stored scenario, e.g. `cargo run -- replay scenarios/open/four_in_one_spot.json`
+        let path = std::env::args().nth(2).expect("missing scenario file");
+        let outcome = Scenario::load(Path::new(&path)).unwrap().run();
+        println!("{:#?}", outcome);
+        std::process::exit(if outcome.is_ok() { 0 } else { 1 });
+    }
+
     let mut play = Playground::new();
-    //example_fail(&mut play);
-    //example_rnd(&mut play);
-    //example1(&mut play);
-    example1_send_data(&mut play);
-    assert!(play.mgr.find_inconsitency().is_none());
+    match example.as_str() {
+        "fail" => example_fail(&mut play),
+        "rnd" => example_rnd(&mut play),
+        "1" => example1(&mut play),
+        "rpc" => example_rpc(&mut play),
+        "walk" => example_walk(&mut play),
+        _ => example1_send_data(&mut play),
+    }
+    assert_eq!(play.mgr.find_inconsistencies(), vec![]);
 }
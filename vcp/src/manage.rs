@@ -0,0 +1,581 @@
+use alloc::{vec, vec::Vec};
+use core::{fmt, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::app;
+use crate::auth::TAG_LEN;
+use crate::clock::{millis, Millis};
+use crate::vcp::{CordId, Delivery, Port, Vcp};
+
+/// The port management requests travel on
+pub const MANAGE_PORT: Port = u16::MAX - 2;
+/// The port the responses travel on, the operator binds it
+pub const MANAGE_REPLY_PORT: Port = u16::MAX - 3;
+/// Longest neighbor timeout a `Configure` sets, an hour
+pub const MAX_NEIGHBOR_TIMEOUT: Millis = 60 * 60 * 1000;
+/// Highest `Settings::log_level`, trace
+pub const MAX_LOG_LEVEL: u8 = 5;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// first byte of the payload. A challenge response goes on with the nonce, a command or a
+// reply with the nonce, the tag and the JSON of the body. The nonces are little endian.
+// The tag covers the kind, so a signed command can't pass for a signed reply.
+const CHALLENGE: u8 = 0;
+const COMMAND: u8 = 1;
+const REPLY: u8 = 2;
+const DENIED: u8 = 3;
+const HEADER_LEN: usize = 1 + 8 + TAG_LEN;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What an operator can ask a node to do
+pub enum Command {
+    /// Report the `Status`
+    Status,
+    /// Change the given settings, keep the others
+    Configure(Settings),
+    /// Restart the node, after the reply was sent
+    Reboot,
+    /// Give up the position and join the cord again
+    Rejoin,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// Settings that can be changed at runtime, `None` keeps the current one
+pub struct Settings {
+    /// `VcpConfig::hello_interval` in milliseconds
+    pub hello_interval: Option<Millis>,
+    /// `VcpConfig::neighbor_timeout` in milliseconds
+    pub neighbor_timeout: Option<Millis>,
+    /// How much the platform logs, from 0 (off) to 5 (trace) like `log::LevelFilter`
+    pub log_level: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StatusFields", into = "StatusFields")]
+/// The state of a node. Sent as an array, with the names it would not fit into a payload.
+pub struct Status {
+    pub c_id: Option<CordId>,
+    pub predecessor: Option<CordId>,
+    pub successor: Option<CordId>,
+    pub neighbors: usize,
+    /// Time of the last `Vcp::timer_call`, the time since boot on the ESP32
+    pub uptime: Millis,
+    /// Reported by the platform, if it can tell
+    pub free_heap: Option<u32>,
+    /// Packets waiting to be sent
+    pub outgoing: usize,
+    /// Received texts and data not taken yet
+    pub stored: usize,
+    /// What the limits threw away, see `VcpStats::dropped`
+    pub dropped: u64,
+}
+
+type StatusFields = (
+    Option<CordId>,
+    Option<CordId>,
+    Option<CordId>,
+    usize,
+    Millis,
+    Option<u32>,
+    usize,
+    usize,
+    u64,
+);
+
+impl From<Status> for StatusFields {
+    fn from(s: Status) -> Self {
+        let Status {
+            c_id,
+            predecessor,
+            successor,
+            neighbors,
+            uptime,
+            free_heap,
+            outgoing,
+            stored,
+            dropped,
+        } = s;
+        (
+            c_id,
+            predecessor,
+            successor,
+            neighbors,
+            uptime,
+            free_heap,
+            outgoing,
+            stored,
+            dropped,
+        )
+    }
+}
+
+impl From<StatusFields> for Status {
+    fn from(fields: StatusFields) -> Self {
+        let (c_id, predecessor, successor, neighbors, uptime, free_heap, outgoing, stored, dropped) =
+            fields;
+        Status {
+            c_id,
+            predecessor,
+            successor,
+            neighbors,
+            uptime,
+            free_heap,
+            outgoing,
+            stored,
+            dropped,
+        }
+    }
+}
+
+impl Status {
+    pub fn of(vcp: &Vcp, free_heap: Option<u32>) -> Self {
+        Status {
+            c_id: vcp.c_id,
+            predecessor: vcp.predecessor,
+            successor: vcp.successor,
+            neighbors: vcp.neighbors.len(),
+            uptime: vcp.now(),
+            free_heap,
+            outgoing: vcp.outgoing_msgs.len(),
+            stored: vcp.data_storage.len() + vcp.inbox.len(),
+            dropped: vcp.stats.dropped(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What a node answers to a command it carried out
+pub enum Reply {
+    Status(Status),
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a command failed
+pub enum ManageError {
+    /// The node did not accept the command
+    Denied,
+    /// The reply is not signed with the management key, or not for the command
+    BadTag,
+    /// Not a response, or not the one that was waited for
+    Malformed,
+    /// No response in time
+    Timeout,
+    /// The request could not be sent
+    NotSent,
+}
+
+impl fmt::Display for ManageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManageError::Denied => write!(f, "command denied"),
+            ManageError::BadTag => write!(f, "bad authentication tag"),
+            ManageError::Malformed => write!(f, "malformed response"),
+            ManageError::Timeout => write!(f, "no response in time"),
+            ManageError::NotSent => write!(f, "request not sent"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What the platform has to do once the replies are sent
+pub enum Action {
+    Reboot,
+    /// See `Settings::log_level`
+    LogLevel(u8),
+}
+
+/// The MAC of a command or a reply for the node at `node`
+fn mac(key: &HmacSha256, kind: u8, node: CordId, nonce: u64, body: &[u8]) -> HmacSha256 {
+    let mut mac = key.clone();
+    mac.update(b"vcp manage");
+    mac.update(&[kind]);
+    mac.update(&node.to_le_bytes());
+    mac.update(&nonce.to_le_bytes());
+    mac.update(body);
+    mac
+}
+
+/// A signed command or reply
+fn encode(key: &HmacSha256, kind: u8, node: CordId, nonce: u64, body: &[u8]) -> Vec<u8> {
+    let tag = mac(key, kind, node, nonce, body).finalize().into_bytes();
+    let mut payload = Vec::with_capacity(HEADER_LEN + body.len());
+    payload.push(kind);
+    payload.extend_from_slice(&nonce.to_le_bytes());
+    payload.extend_from_slice(&tag[..TAG_LEN]);
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// The nonce and body of a command or reply of `kind`, if the tag is right
+fn decode<'a>(
+    key: &HmacSha256,
+    kind: u8,
+    node: CordId,
+    payload: &'a [u8],
+) -> Result<(u64, &'a [u8]), ManageError> {
+    let (header, body) = payload
+        .split_first_chunk::<HEADER_LEN>()
+        .filter(|(header, _)| header[0] == kind)
+        .ok_or(ManageError::Malformed)?;
+    let nonce = u64::from_le_bytes(header[1..9].try_into().unwrap());
+    mac(key, kind, node, nonce, body)
+        .verify_truncated_left(&header[9..])
+        .map_err(|_| ManageError::BadTag)?;
+    Ok((nonce, body))
+}
+
+fn new_key(management_key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(management_key).expect("HMAC takes keys of any length")
+}
+
+/// Carries out the commands an operator sends to a node. A command has to be signed with
+/// the management key and carry the nonce the node handed out last, for its position:
+/// a command recorded on the way can't be replayed, and relays can't forge one. Every
+/// accepted command uses up the nonce. The replies are signed the same way.
+pub struct Manager {
+    key: HmacSha256,
+    nonce: u64,
+}
+
+impl Manager {
+    /// The nonces start at `boot << 32`, so count the boots to never hand one out twice
+    pub fn new(management_key: &[u8], boot: u32) -> Self {
+        Manager {
+            key: new_key(management_key),
+            nonce: u64::from(boot) << 32,
+        }
+    }
+
+    /// Accept requests on `MANAGE_PORT`. Returns false if the port can't be bound.
+    pub fn serve(&self, vcp: &mut Vcp) -> bool {
+        vcp.bind(MANAGE_PORT)
+    }
+
+    /// Answer the requests that arrived at the position of the node, leaves the data of
+    /// other ports in the inbox. `free_heap` goes into the `Status`.
+    /// Returns what the platform has to do after the replies are sent.
+    pub fn poll(&mut self, vcp: &mut Vcp, free_heap: Option<u32>) -> Vec<Action> {
+        let received: Vec<Delivery> = vcp
+            .inbox
+            .iter()
+            .filter(|d| d.port == MANAGE_PORT)
+            .cloned()
+            .collect();
+        vcp.inbox.retain(|d| d.port != MANAGE_PORT);
+        let mut actions = Vec::new();
+        let mut rejoin = false;
+        for delivery in received {
+            let Some(node) = vcp.c_id else {
+                continue;
+            };
+            let response = if delivery.payload[..] == [CHALLENGE] {
+                let mut response = vec![CHALLENGE];
+                response.extend_from_slice(&self.nonce.to_le_bytes());
+                response
+            } else if let Some((nonce, command)) = self.accept(node, &delivery.payload) {
+                self.nonce += 1;
+                let reply = match command {
+                    Command::Status => Some(Reply::Status(Status::of(vcp, free_heap))),
+                    Command::Configure(settings) => {
+                        configure(vcp, &settings, &mut actions).then_some(Reply::Done)
+                    }
+                    Command::Reboot => {
+                        actions.push(Action::Reboot);
+                        Some(Reply::Done)
+                    }
+                    Command::Rejoin => {
+                        rejoin = true;
+                        Some(Reply::Done)
+                    }
+                };
+                match reply {
+                    Some(reply) => {
+                        let body =
+                            serde_json::to_vec(&reply).expect("replies can always be encoded");
+                        encode(&self.key, REPLY, node, nonce, &body)
+                    }
+                    None => vec![DENIED],
+                }
+            } else {
+                vec![DENIED]
+            };
+            let _ = app::send_raw(vcp, delivery.source, MANAGE_REPLY_PORT, &response);
+        }
+        // only now, the replies are sent from the old position
+        if rejoin {
+            vcp.rejoin();
+        }
+        actions
+    }
+
+    /// The command in the payload, if it is signed for the node at `node` and carries the
+    /// current nonce
+    fn accept(&self, node: CordId, payload: &[u8]) -> Option<(u64, Command)> {
+        let (nonce, body) = decode(&self.key, COMMAND, node, payload).ok()?;
+        if nonce != self.nonce {
+            return None;
+        }
+        Some((nonce, serde_json::from_slice(body).ok()?))
+    }
+}
+
+/// Change the settings, if they are sane. Returns false and keeps all settings otherwise.
+/// Neither time is zero, the timeout is at most `MAX_NEIGHBOR_TIMEOUT`, and two Hellos fit
+/// into it, so a neighbor that lost one Hello is not forgotten.
+fn configure(vcp: &mut Vcp, settings: &Settings, actions: &mut Vec<Action>) -> bool {
+    let hello_interval = settings
+        .hello_interval
+        .unwrap_or(millis(vcp.config.hello_interval));
+    let neighbor_timeout = settings
+        .neighbor_timeout
+        .unwrap_or(millis(vcp.config.neighbor_timeout));
+    let timing = settings.hello_interval.is_some() || settings.neighbor_timeout.is_some();
+    if timing
+        && (hello_interval == 0
+            || neighbor_timeout > MAX_NEIGHBOR_TIMEOUT
+            || hello_interval > neighbor_timeout / 2)
+    {
+        return false;
+    }
+    if settings
+        .log_level
+        .is_some_and(|level| level > MAX_LOG_LEVEL)
+    {
+        return false;
+    }
+    if timing {
+        vcp.set_timing(
+            Duration::from_millis(hello_interval),
+            Duration::from_millis(neighbor_timeout),
+        );
+    }
+    if let Some(level) = settings.log_level {
+        actions.push(Action::LogLevel(level));
+    }
+    true
+}
+
+/// The other end of a `Manager`: signs the commands of an operator and checks the replies.
+/// A command takes two round trips, one for the nonce and one for the command.
+pub struct Operator {
+    key: HmacSha256,
+}
+
+impl Operator {
+    pub fn new(management_key: &[u8]) -> Self {
+        Operator {
+            key: new_key(management_key),
+        }
+    }
+
+    /// The payload that asks a node for its nonce
+    pub fn challenge() -> Vec<u8> {
+        vec![CHALLENGE]
+    }
+
+    /// The nonce in the response to `challenge`
+    pub fn nonce(response: &[u8]) -> Result<u64, ManageError> {
+        match response.split_first() {
+            Some((&CHALLENGE, nonce)) => nonce
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| ManageError::Malformed),
+            Some((&DENIED, [])) => Err(ManageError::Denied),
+            _ => Err(ManageError::Malformed),
+        }
+    }
+
+    /// The payload of the command for the node at `node`, with the nonce it handed out
+    pub fn command(&self, node: CordId, nonce: u64, command: &Command) -> Vec<u8> {
+        let body = serde_json::to_vec(command).expect("commands can always be encoded");
+        encode(&self.key, COMMAND, node, nonce, &body)
+    }
+
+    /// The reply to the command with `nonce`, if the node at `node` signed it
+    pub fn reply(&self, node: CordId, nonce: u64, response: &[u8]) -> Result<Reply, ManageError> {
+        if response == [DENIED] {
+            return Err(ManageError::Denied);
+        }
+        let (n, body) = decode(&self.key, REPLY, node, response)?;
+        if n != nonce {
+            return Err(ManageError::Malformed);
+        }
+        serde_json::from_slice(body).map_err(|_| ManageError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const KEY: &[u8] = b"management key";
+
+    fn node(c_id: CordId) -> Vcp {
+        let mut vcp = Vcp::new(false);
+        vcp.config.verbose = false;
+        vcp.c_id = Some(c_id);
+        vcp
+    }
+
+    /// The operator at 0 sends the payload to the managed node at 1000, returns the response
+    fn round_trip(
+        operator: &mut Vcp,
+        managed: &mut Vcp,
+        manager: &mut Manager,
+        payload: &[u8],
+    ) -> (Vec<u8>, Vec<Action>) {
+        app::send_raw(operator, 1000, MANAGE_PORT, payload).unwrap();
+        for packet in core::mem::take(&mut operator.outgoing_msgs) {
+            managed.receive(&packet);
+        }
+        let actions = manager.poll(managed, Some(40_000));
+        for packet in core::mem::take(&mut managed.outgoing_msgs) {
+            operator.receive(&packet);
+        }
+        let delivery = operator.inbox.pop().expect("no response");
+        assert_eq!((delivery.port, delivery.source), (MANAGE_REPLY_PORT, 1000));
+        (delivery.payload.to_vec(), actions)
+    }
+
+    /// Boxed, a heapless `Vcp` is big for the stack of a test
+    fn neighbors() -> (Box<Vcp>, Box<Vcp>) {
+        let (mut operator, mut managed) = (Box::new(node(0)), Box::new(node(1000)));
+        operator.bind(MANAGE_REPLY_PORT);
+        operator.timer_call(&ManualClock::new(0));
+        managed.timer_call(&ManualClock::new(0));
+        managed.receive(&operator.outgoing_msgs.pop().unwrap());
+        operator.receive(&managed.outgoing_msgs.pop().unwrap());
+        (operator, managed)
+    }
+
+    #[test]
+    fn signed_commands_are_carried_out_once() {
+        let (mut operator, mut managed) = neighbors();
+        let mut manager = Manager::new(KEY, 3);
+        assert!(manager.serve(&mut managed));
+        let op = Operator::new(KEY);
+
+        let (response, _) = round_trip(
+            &mut operator,
+            &mut managed,
+            &mut manager,
+            &Operator::challenge(),
+        );
+        let nonce = Operator::nonce(&response).unwrap();
+        assert_eq!(nonce, 3 << 32);
+        let status = op.command(1000, nonce, &Command::Status);
+        let (response, actions) = round_trip(&mut operator, &mut managed, &mut manager, &status);
+        let Ok(Reply::Status(status_reply)) = op.reply(1000, nonce, &response) else {
+            panic!("no status in {:?}", response);
+        };
+        assert_eq!(status_reply.c_id, Some(1000));
+        assert_eq!(status_reply.neighbors, 1);
+        assert_eq!(status_reply.free_heap, Some(40_000));
+        assert!(actions.is_empty());
+        // signed for another node
+        assert_eq!(op.reply(500, nonce, &response), Err(ManageError::BadTag));
+
+        // the recorded command is replayed
+        let (response, _) = round_trip(&mut operator, &mut managed, &mut manager, &status);
+        assert_eq!(op.reply(1000, nonce, &response), Err(ManageError::Denied));
+        // signed with another key
+        let forged = Operator::new(b"guessed key").command(1000, nonce + 1, &Command::Reboot);
+        let (response, actions) = round_trip(&mut operator, &mut managed, &mut manager, &forged);
+        assert_eq!(
+            op.reply(1000, nonce + 1, &response),
+            Err(ManageError::Denied)
+        );
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn commands_change_the_node() {
+        let (mut operator, mut managed) = neighbors();
+        let mut manager = Manager::new(KEY, 0);
+        manager.serve(&mut managed);
+        let op = Operator::new(KEY);
+        let mut command = |command: Command| {
+            let (response, _) = round_trip(
+                &mut operator,
+                &mut managed,
+                &mut manager,
+                &Operator::challenge(),
+            );
+            let nonce = Operator::nonce(&response).unwrap();
+            let request = op.command(1000, nonce, &command);
+            let (response, actions) =
+                round_trip(&mut operator, &mut managed, &mut manager, &request);
+            assert_eq!(op.reply(1000, nonce, &response), Ok(Reply::Done));
+            actions
+        };
+
+        let settings = Settings {
+            hello_interval: Some(2000),
+            log_level: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(command(Command::Configure(settings)), [Action::LogLevel(2)]);
+        assert_eq!(command(Command::Reboot), [Action::Reboot]);
+        assert!(command(Command::Rejoin).is_empty());
+        assert_eq!(managed.config.hello_interval, Duration::from_secs(2));
+        assert_eq!(managed.c_id, None);
+    }
+
+    #[test]
+    fn insane_settings_are_denied() {
+        let (mut operator, mut managed) = neighbors();
+        let mut manager = Manager::new(KEY, 0);
+        manager.serve(&mut managed);
+        let op = Operator::new(KEY);
+        let mut configure = |settings: Settings| {
+            let (response, _) = round_trip(
+                &mut operator,
+                &mut managed,
+                &mut manager,
+                &Operator::challenge(),
+            );
+            let nonce = Operator::nonce(&response).unwrap();
+            let request = op.command(1000, nonce, &Command::Configure(settings));
+            let (response, actions) =
+                round_trip(&mut operator, &mut managed, &mut manager, &request);
+            let reply = op.reply(1000, nonce, &response);
+            (reply, actions, managed.config.hello_interval)
+        };
+        let timing = |hello, timeout| Settings {
+            hello_interval: Some(hello),
+            neighbor_timeout: Some(timeout),
+            log_level: Some(2),
+        };
+
+        for settings in [
+            timing(0, 10_000),
+            timing(6_000, 10_000),
+            timing(1_000, MAX_NEIGHBOR_TIMEOUT + 1),
+            timing(Millis::MAX, Millis::MAX),
+            Settings {
+                log_level: Some(MAX_LOG_LEVEL + 1),
+                ..Default::default()
+            },
+        ] {
+            let unchanged = Duration::from_secs(1);
+            assert_eq!(
+                configure(settings),
+                (Err(ManageError::Denied), vec![], unchanged)
+            );
+        }
+        assert_eq!(
+            configure(timing(5_000, 10_000)),
+            (
+                Ok(Reply::Done),
+                vec![Action::LogLevel(2)],
+                Duration::from_secs(5)
+            )
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, Incoming, MqttOptions, QoS};
+
+use crate::host::Host;
+use crate::serial::{Downlink, Uplink};
+use crate::vcp::{CordId, Port};
+
+/// First level of every topic of the bridge
+pub const PREFIX: &str = "vcp";
+/// Publications on `vcp/+/send` are sent into the cord
+pub const SEND_FILTER: &str = "vcp/+/send";
+/// How long the bridge waits before it connects again to a broker that went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Topic of the data that arrived at the gateway from the node at `source` on `port`
+pub fn data_topic(source: CordId, port: Port) -> String {
+    format!("{}/{}/{}", PREFIX, source, port)
+}
+
+/// Topic of the texts that arrived at the gateway from the node at `source`
+pub fn text_topic(source: CordId) -> String {
+    format!("{}/{}/text", PREFIX, source)
+}
+
+/// The node a publication on `topic` is for, `None` if it is not a send topic
+pub fn send_target(topic: &str) -> Option<CordId> {
+    let mut levels = topic.split('/');
+    match (levels.next(), levels.next(), levels.next(), levels.next()) {
+        (Some(PREFIX), Some(cid), Some("send"), None) => cid.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The topic and payload an uplink is published with, `None` if it carries no data
+pub fn publication(uplink: &Uplink) -> Option<(String, Vec<u8>)> {
+    match uplink {
+        Uplink::Text { sender_cid, text } => Some((text_topic(*sender_cid), text.clone().into())),
+        Uplink::Data {
+            port,
+            source,
+            payload,
+        } => Some((data_topic(*source, *port), payload.clone())),
+        Uplink::Packet(_) | Uplink::Status { .. } => None,
+    }
+}
+
+/// Bridge the gateway of `host` to the broker of `options`, forever. The texts and data that
+/// arrive at the gateway are published on `vcp/<cid>/text` and `vcp/<cid>/<port>`, with the
+/// position of the sender. What is published on `vcp/<cid>/send` is sent to the node at
+/// `cid` as text. A lost broker is connected again, what arrived meanwhile is lost.
+pub fn bridge(host: &Host, options: MqttOptions) -> ! {
+    let (client, mut connection) = Client::new(options, 16);
+    let uplinks = host.subscribe();
+    let publisher = client.clone();
+    thread::spawn(move || {
+        for (topic, payload) in uplinks.iter().filter_map(|u| publication(&u)) {
+            if let Err(e) = publisher.publish(topic, QoS::AtLeastOnce, false, payload) {
+                eprintln!("Publishing failed: {}", e);
+            }
+        }
+    });
+    loop {
+        match connection.recv() {
+            // the broker forgets the subscription with the connection
+            Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => {
+                if let Err(e) = client.subscribe(SEND_FILTER, QoS::AtLeastOnce) {
+                    eprintln!("Subscribing failed: {}", e);
+                }
+            }
+            Ok(Ok(Event::Incoming(Incoming::Publish(publish)))) => {
+                let Some(final_cid) = send_target(&publish.topic) else {
+                    continue;
+                };
+                let downlink = Downlink::Text {
+                    final_cid,
+                    text: String::from_utf8_lossy(&publish.payload).into(),
+                };
+                if let Err(e) = host.send(&downlink) {
+                    eprintln!("Sending to the gateway failed: {}", e);
+                }
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                eprintln!("Broker connection failed: {}", e);
+                thread::sleep(RECONNECT_DELAY);
+            }
+            Err(_) => unreachable!("the bridge keeps a client"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::pty_pair;
+    use crate::serial::{encode_message, FrameDecoder};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+
+    type Subscriptions = Arc<Mutex<Vec<(TcpStream, String)>>>;
+
+    /// Whether the topic filter of a subscription matches the topic
+    fn matches(filter: &str, topic: &str) -> bool {
+        let (mut filter, mut topic) = (filter.split('/'), topic.split('/'));
+        loop {
+            match (filter.next(), topic.next()) {
+                (Some("#"), _) | (None, None) => return true,
+                (Some(f), Some(t)) if f == "+" || f == t => {}
+                _ => return false,
+            }
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        let header = byte[0];
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7F) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body)?;
+        Ok((header, body))
+    }
+
+    fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = vec![header];
+        let mut len = body.len();
+        loop {
+            let byte = (len & 0x7F) as u8;
+            len >>= 7;
+            packet.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        stream.write_all(&packet)
+    }
+
+    fn string(body: &[u8], at: usize) -> (String, usize) {
+        let len = usize::from(u16::from_be_bytes([body[at], body[at + 1]]));
+        let end = at + 2 + len;
+        (String::from_utf8_lossy(&body[at + 2..end]).into(), end)
+    }
+
+    /// Just enough of an MQTT 3.1.1 broker for the tests: publications are passed on
+    /// with QoS 0, no sessions, no retained messages
+    fn serve(mut stream: TcpStream, subscriptions: Subscriptions) -> io::Result<()> {
+        loop {
+            let (header, body) = read_packet(&mut stream)?;
+            match header >> 4 {
+                // CONNECT
+                1 => write_packet(&mut stream, 0x20, &[0, 0])?,
+                // PUBLISH
+                3 => {
+                    let (topic, mut at) = string(&body, 0);
+                    if (header >> 1) & 3 > 0 {
+                        write_packet(&mut stream, 0x40, &body[at..at + 2])?;
+                        at += 2;
+                    }
+                    let mut forward = body[..2 + topic.len()].to_vec();
+                    forward.extend_from_slice(&body[at..]);
+                    for (subscriber, filter) in subscriptions.lock().unwrap().iter_mut() {
+                        if matches(filter, &topic) {
+                            let _ = write_packet(subscriber, 0x30, &forward);
+                        }
+                    }
+                }
+                // SUBSCRIBE, everything is granted with QoS 0
+                8 => {
+                    let mut suback = body[..2].to_vec();
+                    let mut at = 2;
+                    while at < body.len() {
+                        let (filter, end) = string(&body, at);
+                        at = end + 1;
+                        subscriptions
+                            .lock()
+                            .unwrap()
+                            .push((stream.try_clone()?, filter));
+                        suback.push(0);
+                    }
+                    write_packet(&mut stream, 0x90, &suback)?;
+                }
+                // PINGREQ
+                12 => write_packet(&mut stream, 0xD0, &[])?,
+                // DISCONNECT
+                14 => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn start_broker() -> (u16, Subscriptions) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let subscriptions = Subscriptions::default();
+        let shared = subscriptions.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = shared.clone();
+                thread::spawn(move || serve(stream, shared));
+            }
+        });
+        (port, subscriptions)
+    }
+
+    #[test]
+    fn topics_name_the_node() {
+        assert_eq!(data_topic(1000, 7), "vcp/1000/7");
+        assert_eq!(send_target("vcp/1000/send"), Some(1000));
+        assert_eq!(send_target("vcp/1000/send/x"), None);
+        assert_eq!(send_target("vcp/x/send"), None);
+        assert_eq!(send_target("other/1000/send"), None);
+        assert!(matches(SEND_FILTER, "vcp/1000/send"));
+        assert!(!matches(SEND_FILTER, "vcp/1000/7"));
+    }
+
+    #[test]
+    fn the_bridge_publishes_and_injects() {
+        let (port, subscriptions) = start_broker();
+        let (master, mut gateway) = pty_pair().unwrap();
+        let host = Host::new(&master).unwrap();
+        let reader = host.clone();
+        thread::spawn(move || reader.read(master));
+        let bridged = host.clone();
+        thread::spawn(move || bridge(&bridged, MqttOptions::new("bridge", "127.0.0.1", port)));
+
+        // the backend
+        let (backend, mut connection) =
+            Client::new(MqttOptions::new("backend", "127.0.0.1", port), 16);
+        backend.subscribe("vcp/+/7", QoS::AtMostOnce).unwrap();
+        let (received, publications) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                if let Ok(Event::Incoming(Incoming::Publish(p))) = event {
+                    received.send((p.topic, p.payload.to_vec())).unwrap();
+                }
+            }
+        });
+        for _ in 0..500 {
+            if subscriptions.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(subscriptions.lock().unwrap().len(), 2);
+
+        for uplink in [
+            Uplink::Data {
+                port: 3,
+                source: 1000,
+                payload: b"ignored".to_vec(),
+            },
+            Uplink::Data {
+                port: 7,
+                source: 1000,
+                payload: b"21.5".to_vec(),
+            },
+        ] {
+            gateway.write_all(&encode_message(&uplink)).unwrap();
+        }
+        let publication = publications.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(publication, ("vcp/1000/7".to_string(), b"21.5".to_vec()));
+
+        backend
+            .publish("vcp/500/send", QoS::AtLeastOnce, false, "hello 500")
+            .unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 256];
+        let downlink = loop {
+            let len = gateway.read(&mut buf).unwrap();
+            decoder.push(&buf[..len]);
+            if let Some(downlink) = decoder.next_message::<Downlink>() {
+                break downlink;
+            }
+        };
+        assert_eq!(
+            downlink,
+            Downlink::Text {
+                final_cid: 500,
+                text: "hello 500".into()
+            }
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::vcp::{CordId, Vcp};
+
+/// Name the `NodeState` is stored under, short enough for an NVS key
+pub const STATE_KEY: &str = "vcp_state";
+/// Longest encoded `NodeState`
+pub const MAX_STATE_LEN: usize = 256;
+
+/// Small blobs that survive a reboot, like the NVS of the ESP32
+pub trait Storage {
+    type Error: fmt::Debug;
+    /// Read the blob into `buf`, `None` if there is none. A blob longer than `buf` is an
+    /// error, not a shorter blob.
+    fn load<'a>(&mut self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Self::Error>;
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The stored blob is longer than the buffer it is read into
+pub struct TooLong;
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stored blob too long")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Keeps the blobs in memory, they survive a simulated reboot only
+pub struct MemoryStorage {
+    pub blobs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    type Error = TooLong;
+
+    fn load<'a>(&mut self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, TooLong> {
+        let Some(blob) = self.blobs.get(key) else {
+            return Ok(None);
+        };
+        let buf = buf.get_mut(..blob.len()).ok_or(TooLong)?;
+        buf.copy_from_slice(blob);
+        Ok(Some(buf))
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), TooLong> {
+        self.blobs.insert(key.into(), data.into());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+/// One file per blob in a directory, for nodes on Linux
+pub struct FileStorage {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileStorage {
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+        FileStorage { dir: dir.into() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Storage for FileStorage {
+    type Error = std::io::Error;
+
+    fn load<'a>(&mut self, key: &str, buf: &'a mut [u8]) -> std::io::Result<Option<&'a [u8]>> {
+        let blob = match std::fs::read(self.dir.join(key)) {
+            Ok(blob) => blob,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some(buf) = buf.get_mut(..blob.len()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                TooLong.to_string(),
+            ));
+        };
+        buf.copy_from_slice(&blob);
+        Ok(Some(buf))
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(key), data)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What a node keeps across reboots. A restored position is only kept once the neighbors
+/// confirm it, see `Vcp::restore`.
+pub struct NodeState {
+    /// The last position of the node
+    pub c_id: Option<CordId>,
+    /// The cord epoch, counts the positions the node took. A restored position keeps its
+    /// epoch, every new one increments it, so of two states of a node the one with the higher
+    /// epoch has the current position. States written without it start at 0.
+    #[serde(default)]
+    pub epoch: u32,
+    /// Secret key of the node, created on the first boot
+    pub secret: [u8; 32],
+    /// Counts the boots, so sequence numbers can start higher than before the reboot
+    #[serde(default)]
+    pub boots: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why the stored state could not be opened. Nothing was written, the caller decides whether
+/// to start over with `Persistence::reset`.
+pub enum PersistError<E> {
+    /// The storage could not be read, or the blob is longer than `MAX_STATE_LEN`
+    Storage(E),
+    /// The stored blob does not decode
+    Corrupt,
+}
+
+impl<E: fmt::Debug> fmt::Display for PersistError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Storage(e) => write!(f, "storage failed: {:?}", e),
+            PersistError::Corrupt => write!(f, "stored state corrupt"),
+        }
+    }
+}
+
+/// Keeps the `NodeState` in a `Storage` up to date
+pub struct Persistence<S> {
+    storage: S,
+    state: NodeState,
+}
+
+impl<S: Storage> Persistence<S> {
+    /// Load the stored state and count the boot. Without one a new state with a new secret
+    /// is started. A state that can't be read is left as it is and reported.
+    pub fn open(
+        mut storage: S,
+        new_secret: impl FnOnce() -> [u8; 32],
+    ) -> Result<Self, PersistError<S::Error>> {
+        let mut buf = [0; MAX_STATE_LEN];
+        let stored = match storage
+            .load(STATE_KEY, &mut buf)
+            .map_err(PersistError::Storage)?
+        {
+            Some(blob) => Some(serde_json::from_slice(blob).map_err(|_| PersistError::Corrupt)?),
+            None => None,
+        };
+        let mut state = stored.unwrap_or_else(|| NodeState {
+            c_id: None,
+            epoch: 0,
+            secret: new_secret(),
+            boots: 0,
+        });
+        state.boots = state.boots.wrapping_add(1);
+        Self::write(&mut storage, &state).map_err(PersistError::Storage)?;
+        Ok(Persistence { storage, state })
+    }
+
+    /// Start over with a new state and a new secret, whatever is stored. The node gets a
+    /// new key, the others have to ask for it again.
+    pub fn reset(mut storage: S, new_secret: impl FnOnce() -> [u8; 32]) -> Result<Self, S::Error> {
+        let state = NodeState {
+            c_id: None,
+            epoch: 0,
+            secret: new_secret(),
+            boots: 1,
+        };
+        Self::write(&mut storage, &state)?;
+        Ok(Persistence { storage, state })
+    }
+
+    pub fn state(&self) -> &NodeState {
+        &self.state
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Put the node back at its stored position, if it had one
+    pub fn restore(&self, vcp: &mut Vcp) {
+        if let Some(c_id) = self.state.c_id {
+            vcp.restore(c_id);
+        }
+    }
+
+    /// Store the position of the node if it changed, so calling it often does not wear out
+    /// the flash. A new position starts a new epoch. Returns true if it was written.
+    pub fn sync(&mut self, vcp: &Vcp) -> Result<bool, S::Error> {
+        if vcp.c_id == self.state.c_id {
+            return Ok(false);
+        }
+        let mut state = self.state;
+        state.c_id = vcp.c_id;
+        if vcp.c_id.is_some() {
+            state.epoch = state.epoch.wrapping_add(1);
+        }
+        Self::write(&mut self.storage, &state)?;
+        self.state = state;
+        Ok(true)
+    }
+
+    fn write(storage: &mut S, state: &NodeState) -> Result<(), S::Error> {
+        let blob = serde_json::to_vec(state).expect("the state can always be encoded");
+        storage.store(STATE_KEY, &blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(storage: MemoryStorage) -> Persistence<MemoryStorage> {
+        Persistence::open(storage, || [7; 32]).unwrap()
+    }
+
+    #[test]
+    fn state_survives_a_reboot() {
+        let mut persistence = open(MemoryStorage::default());
+        assert_eq!(persistence.state().c_id, None);
+        let mut vcp = Vcp::new(false);
+        assert!(!persistence.sync(&vcp).unwrap());
+        vcp.c_id = Some(250);
+        assert!(persistence.sync(&vcp).unwrap());
+        // written once, not on every call
+        assert!(!persistence.sync(&vcp).unwrap());
+
+        let rebooted = Persistence::open(persistence.storage().clone(), || [0; 32]).unwrap();
+        assert_eq!(rebooted.state().c_id, persistence.state().c_id);
+        assert_eq!(rebooted.state().boots, 2);
+        assert_eq!(rebooted.state().secret, [7; 32]);
+        let mut vcp = Vcp::new(false);
+        rebooted.restore(&mut vcp);
+        assert_eq!(vcp.c_id, Some(250));
+        assert!(!vcp.is_confirmed());
+    }
+
+    #[test]
+    fn epoch_counts_positions() {
+        let mut persistence = open(MemoryStorage::default());
+        assert_eq!(persistence.state().epoch, 0);
+        let mut vcp = Vcp::new(false);
+        vcp.c_id = Some(250);
+        persistence.sync(&vcp).unwrap();
+        assert_eq!(persistence.state().epoch, 1);
+        // losing the position while joining again is no new one
+        vcp.c_id = None;
+        persistence.sync(&vcp).unwrap();
+        assert_eq!(persistence.state().epoch, 1);
+        vcp.c_id = Some(300);
+        persistence.sync(&vcp).unwrap();
+        assert_eq!(persistence.state().epoch, 2);
+
+        // the restored position keeps its epoch
+        let mut rebooted = open(persistence.storage().clone());
+        let mut vcp = Vcp::new(false);
+        rebooted.restore(&mut vcp);
+        assert!(!rebooted.sync(&vcp).unwrap());
+        assert_eq!(rebooted.state().epoch, 2);
+    }
+
+    #[test]
+    fn state_without_an_epoch_is_read() {
+        let mut storage = MemoryStorage::default();
+        // written before the epoch was stored
+        let blob = alloc::format!(r#"{{"c_id":250,"secret":{:?},"boots":2}}"#, [7u8; 32]);
+        storage.store(STATE_KEY, blob.as_bytes()).unwrap();
+        let persistence = open(storage);
+        assert_eq!(persistence.state().c_id, Some(250));
+        assert_eq!(persistence.state().epoch, 0);
+        assert_eq!(persistence.state().boots, 3);
+    }
+
+    #[test]
+    fn broken_state_is_kept_until_reset() {
+        let mut storage = MemoryStorage::default();
+        storage.store(STATE_KEY, b"{\"c_id\":").unwrap();
+        let Err(e) = Persistence::open(storage.clone(), || [7; 32]) else {
+            panic!("a broken state is reported");
+        };
+        assert_eq!(e, PersistError::Corrupt);
+        assert_eq!(storage.blobs[STATE_KEY], b"{\"c_id\":");
+
+        storage
+            .store(STATE_KEY, &[b' '; MAX_STATE_LEN + 1])
+            .unwrap();
+        let Err(e) = Persistence::open(storage.clone(), || [7; 32]) else {
+            panic!("a state longer than the buffer is reported");
+        };
+        assert_eq!(e, PersistError::Storage(TooLong));
+        assert_eq!(storage.blobs[STATE_KEY].len(), MAX_STATE_LEN + 1);
+
+        let persistence = Persistence::reset(storage, || [7; 32]).unwrap();
+        assert_eq!(persistence.state().c_id, None);
+        assert_eq!(persistence.state().boots, 1);
+        assert_eq!(open(persistence.storage().clone()).state().secret, [7; 32]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_storage() {
+        let dir = std::env::temp_dir().join(alloc::format!("vcp-state-{}", std::process::id()));
+        let mut storage = FileStorage::new(&dir);
+        let mut buf = [0; 8];
+        assert_eq!(storage.load("missing", &mut buf).unwrap(), None);
+        storage.store("key", b"blob").unwrap();
+        assert_eq!(storage.load("key", &mut buf).unwrap(), Some(&b"blob"[..]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{dummy::VirtManager, graphing::GraphViz};
-use std::path::Path;
+use std::{fmt::Debug, path::Path, time::Duration};
+use vcp::rpc::Method;
 
 pub struct Playground {
     pub mgr: VirtManager,
@@ -12,9 +13,8 @@ impl Playground {
         for _ in 0..n {
             self.mgr.handle_messages();
             self.age += 1;
-            let err = self.mgr.find_inconsitency();
-            if err.is_some() {
-                println!("Inconsisten at {} {}", self.age, err.unwrap());
+            for err in self.mgr.find_inconsistencies() {
+                println!("Inconsisten at {} {}", self.age, err);
             }
 
             self.create_graph_if_new();
@@ -25,7 +25,7 @@ impl Playground {
         let name = format!("out/{:0>3}.png", self.age);
         let gr = GraphViz::generate_graph(&self.mgr);
         if Some(&gr) != self.old_graph.as_ref() {
-            GraphViz::save_to_png(&gr, Path::new(&name));
+            GraphViz::save_to_png(&gr, Path::new(&name)).expect("could not write graph");
         }
         self.old_graph = Some(gr);
     }
@@ -40,7 +40,45 @@ impl Playground {
             "\nNew data transmission order: From: {}, To: {}, Text: {}.",
             from, to, text
         );
-        self.mgr.send_text_data(from, to, text);
+        self.mgr.send_text_data(from, to, &text);
+        self.ticks(10);
+    }
+
+    /// Every device answers calls of the method with `handler`, which gets the position
+    /// of the device and the position of the caller
+    pub fn serve<M: Method>(
+        &mut self,
+        handler: impl Fn((i32, i32), u32, M::Request) -> M::Response + Clone + 'static,
+    ) {
+        for d in &mut self.mgr.devices {
+            let handler = handler.clone();
+            let position = d.position;
+            d.rpc
+                .serve::<M>(&mut d.vcp, move |source, request| {
+                    handler(position, source, request)
+                })
+                .expect("rpc port can't be bound");
+        }
+    }
+
+    /// Call the method at the node at `to` and print the response
+    pub fn call<M: Method>(&mut self, from: u32, to: u32, request: &M::Request)
+    where
+        M::Response: Debug,
+    {
+        println!("\nNew call: From: {}, To: {}.", from, to);
+        let index = self.mgr.closest_device(from);
+        let d = &mut self.mgr.devices[index];
+        let result = d.rpc.call::<M>(
+            &mut d.vcp,
+            to,
+            request,
+            Duration::from_secs(5),
+            move |reply| println!("Reply from {} to {}: {:?}", to, from, reply),
+        );
+        if let Err(e) = result {
+            println!("Call failed: {}", e);
+        }
         self.ticks(10);
     }
 
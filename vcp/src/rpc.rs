@@ -0,0 +1,406 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{fmt, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::app::{self, AppError};
+use crate::clock::{millis, Millis};
+use crate::vcp::{CordId, Delivery, Port, Vcp};
+
+/// The port requests and responses travel on
+pub const RPC_PORT: Port = u16::MAX;
+
+/// Identifies a method, the same on every node
+pub type MethodId = u16;
+/// Identifies a call among the open calls of a node
+pub type CallId = u16;
+
+/// A method a node can call on another node
+pub trait Method {
+    const ID: MethodId;
+    type Request: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a call failed
+pub enum RpcError {
+    /// The request or the response does not fit into a packet
+    TooLong,
+    /// The node has no position yet
+    NoPosition,
+    /// `RPC_PORT` could not be bound
+    NoPort,
+    /// No response within the timeout of the call
+    Timeout,
+    /// The called node does not serve the method
+    UnknownMethod,
+    /// The called node could not decode the request
+    BadRequest,
+    /// The response could not be decoded
+    BadResponse,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::TooLong => write!(f, "message too long"),
+            RpcError::NoPosition => write!(f, "no own position yet"),
+            RpcError::NoPort => write!(f, "rpc port can't be bound"),
+            RpcError::Timeout => write!(f, "no response in time"),
+            RpcError::UnknownMethod => write!(f, "method not served"),
+            RpcError::BadRequest => write!(f, "request can't be decoded"),
+            RpcError::BadResponse => write!(f, "response can't be decoded"),
+        }
+    }
+}
+
+impl From<AppError> for RpcError {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::TooLong => RpcError::TooLong,
+            AppError::NoPosition => RpcError::NoPosition,
+        }
+    }
+}
+
+// first byte of the payload, followed by the call id and the method id, little endian
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const UNKNOWN_METHOD: u8 = 2;
+const BAD_REQUEST: u8 = 3;
+const TOO_LONG: u8 = 4;
+const HEADER_LEN: usize = 5;
+
+fn encode(kind: u8, id: CallId, method: MethodId, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(HEADER_LEN + body.len());
+    payload.push(kind);
+    payload.extend_from_slice(&id.to_le_bytes());
+    payload.extend_from_slice(&method.to_le_bytes());
+    payload.extend_from_slice(body);
+    payload
+}
+
+fn decode(payload: &[u8]) -> Option<(u8, CallId, MethodId, &[u8])> {
+    let (header, body) = payload.split_first_chunk::<HEADER_LEN>()?;
+    let id = CallId::from_le_bytes([header[1], header[2]]);
+    let method = MethodId::from_le_bytes([header[3], header[4]]);
+    Some((header[0], id, method, body))
+}
+
+type Handler<'a> = Box<dyn FnMut(CordId, &[u8]) -> Result<Vec<u8>, RpcError> + 'a>;
+type OnReply<'a> = Box<dyn FnOnce(Result<&[u8], RpcError>) + 'a>;
+
+/// A call that waits for its response
+struct Call<'a> {
+    id: CallId,
+    deadline: Millis,
+    on_reply: OnReply<'a>,
+}
+
+/// Requests and responses between nodes, on top of the routed data of `Vcp`.
+/// The request carries a call id, the response goes back along the cord to the source of
+/// the request. Each call has a timeout: lost requests or responses are not sent again.
+pub struct Rpc<'a> {
+    handlers: Vec<(MethodId, Handler<'a>)>,
+    calls: Vec<Call<'a>>,
+    next_id: CallId,
+}
+
+impl Default for Rpc<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Rpc<'a> {
+    pub fn new() -> Self {
+        Rpc {
+            handlers: Vec::new(),
+            calls: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Answer calls of `method` with `handler`. Replaces an earlier handler of the method.
+    pub fn serve_raw(
+        &mut self,
+        vcp: &mut Vcp,
+        method: MethodId,
+        handler: impl FnMut(CordId, &[u8]) -> Result<Vec<u8>, RpcError> + 'a,
+    ) -> Result<(), RpcError> {
+        if !vcp.bind(RPC_PORT) {
+            return Err(RpcError::NoPort);
+        }
+        self.handlers.retain(|(m, _)| *m != method);
+        self.handlers.push((method, Box::new(handler)));
+        Ok(())
+    }
+
+    /// Like `serve_raw`, with requests and responses as JSON
+    pub fn serve<M: Method>(
+        &mut self,
+        vcp: &mut Vcp,
+        mut handler: impl FnMut(CordId, M::Request) -> M::Response + 'a,
+    ) -> Result<(), RpcError> {
+        self.serve_raw(vcp, M::ID, move |source, body| {
+            let request = serde_json::from_slice(body).map_err(|_| RpcError::BadRequest)?;
+            Ok(serde_json::to_vec(&handler(source, request))
+                .expect("responses can always be encoded"))
+        })
+    }
+
+    /// Call `method` at the node at `final_cid`. `on_reply` gets the response, or the
+    /// error, from the `poll` that sees it.
+    pub fn call_raw(
+        &mut self,
+        vcp: &mut Vcp,
+        final_cid: CordId,
+        method: MethodId,
+        body: &[u8],
+        timeout: Duration,
+        on_reply: impl FnOnce(Result<&[u8], RpcError>) + 'a,
+    ) -> Result<CallId, RpcError> {
+        if !vcp.bind(RPC_PORT) {
+            return Err(RpcError::NoPort);
+        }
+        let id = self.next_id;
+        app::send_raw(vcp, final_cid, RPC_PORT, &encode(REQUEST, id, method, body))?;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.calls.push(Call {
+            id,
+            deadline: vcp.now().saturating_add(millis(timeout)),
+            on_reply: Box::new(on_reply),
+        });
+        Ok(id)
+    }
+
+    /// Like `call_raw`, with requests and responses as JSON
+    pub fn call<M: Method>(
+        &mut self,
+        vcp: &mut Vcp,
+        final_cid: CordId,
+        request: &M::Request,
+        timeout: Duration,
+        on_reply: impl FnOnce(Result<M::Response, RpcError>) + 'a,
+    ) -> Result<CallId, RpcError> {
+        let body = serde_json::to_vec(request).expect("requests can always be encoded");
+        self.call_raw(vcp, final_cid, M::ID, &body, timeout, move |reply| {
+            on_reply(
+                reply.and_then(|body| {
+                    serde_json::from_slice(body).map_err(|_| RpcError::BadResponse)
+                }),
+            )
+        })
+    }
+
+    /// Calls that wait for their response
+    pub fn pending(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Answer the requests the node and its virtual nodes received, hand the responses to
+    /// the calls and time out the calls that waited too long. Leaves the data of other
+    /// ports in the inbox. The timeouts follow the clock of the last `Vcp::timer_call`.
+    /// Returns how many requests were answered and calls finished.
+    pub fn poll(&mut self, vcp: &mut Vcp) -> usize {
+        let mut received: Vec<Delivery> = Vec::new();
+        let virt = vcp.virtual_nodes.iter_mut().map(|v| &mut v.inbox);
+        for inbox in core::iter::once(&mut vcp.inbox).chain(virt) {
+            received.extend(inbox.iter().filter(|d| d.port == RPC_PORT).cloned());
+            inbox.retain(|d| d.port != RPC_PORT);
+        }
+        let mut handled = 0;
+        for delivery in &received {
+            let Some((kind, id, method, body)) = decode(&delivery.payload) else {
+                continue;
+            };
+            if kind == REQUEST {
+                self.answer(vcp, delivery.source, id, method, body);
+                handled += 1;
+                continue;
+            }
+            let reply = match kind {
+                RESPONSE => Ok(body),
+                UNKNOWN_METHOD => Err(RpcError::UnknownMethod),
+                BAD_REQUEST => Err(RpcError::BadRequest),
+                TOO_LONG => Err(RpcError::TooLong),
+                _ => continue,
+            };
+            // late responses of timed out calls are dropped
+            if let Some(i) = self.calls.iter().position(|c| c.id == id) {
+                (self.calls.swap_remove(i).on_reply)(reply);
+                handled += 1;
+            }
+        }
+
+        let now = vcp.now();
+        while let Some(i) = self.calls.iter().position(|c| c.deadline <= now) {
+            (self.calls.swap_remove(i).on_reply)(Err(RpcError::Timeout));
+            handled += 1;
+        }
+        handled
+    }
+
+    /// Call the handler of the method and send its response back to `source`
+    fn answer(&mut self, vcp: &mut Vcp, source: CordId, id: CallId, method: MethodId, body: &[u8]) {
+        let result = match self.handlers.iter_mut().find(|(m, _)| *m == method) {
+            Some((_, handler)) => handler(source, body),
+            None => Err(RpcError::UnknownMethod),
+        };
+        let mut response = match result {
+            Ok(body) => encode(RESPONSE, id, method, &body),
+            Err(RpcError::UnknownMethod) => encode(UNKNOWN_METHOD, id, method, &[]),
+            Err(RpcError::TooLong) => encode(TOO_LONG, id, method, &[]),
+            Err(_) => encode(BAD_REQUEST, id, method, &[]),
+        };
+        if let Err(AppError::TooLong) = app::send_raw(vcp, source, RPC_PORT, &response) {
+            response = encode(TOO_LONG, id, method, &[]);
+            let _ = app::send_raw(vcp, source, RPC_PORT, &response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::collections::{to_payload, Queue};
+    use alloc::{string::String, vec};
+    use core::cell::RefCell;
+    use serde::Deserialize;
+
+    struct ReadSensor;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: u8,
+        value: i32,
+    }
+
+    impl Method for ReadSensor {
+        const ID: MethodId = 1;
+        type Request = u8;
+        type Response = Reading;
+    }
+
+    struct Toggle;
+
+    impl Method for Toggle {
+        const ID: MethodId = 2;
+        type Request = bool;
+        type Response = String;
+    }
+
+    fn node(c_id: CordId) -> Vcp {
+        let mut vcp = Vcp::new(false);
+        vcp.config.verbose = false;
+        vcp.c_id = Some(c_id);
+        vcp
+    }
+
+    /// Deliver the queued packets of each node to the other
+    fn exchange(a: &mut Vcp, b: &mut Vcp) {
+        for packet in core::mem::take(&mut a.outgoing_msgs) {
+            b.receive(&packet);
+        }
+        for packet in core::mem::take(&mut b.outgoing_msgs) {
+            a.receive(&packet);
+        }
+    }
+
+    #[test]
+    fn calls_get_their_responses() {
+        let mut client = node(0);
+        let mut server = node(1000);
+        let clock = ManualClock::new(0);
+        server.timer_call(&clock);
+        client.receive(&server.outgoing_msgs.pop().unwrap());
+        client.timer_call(&clock);
+        server.receive(&client.outgoing_msgs.pop().unwrap());
+
+        let mut served = Rpc::new();
+        served
+            .serve::<ReadSensor>(&mut server, |_, sensor| Reading {
+                sensor,
+                value: 21500,
+            })
+            .unwrap();
+        let replies = RefCell::new(vec![]);
+        let mut calls = Rpc::new();
+        for sensor in [3, 4] {
+            calls
+                .call::<ReadSensor>(&mut client, 1000, &sensor, Duration::from_secs(5), |r| {
+                    replies.borrow_mut().push(r)
+                })
+                .unwrap();
+        }
+        calls
+            .call::<Toggle>(&mut client, 1000, &true, Duration::from_secs(5), |r| {
+                assert_eq!(r, Err(RpcError::UnknownMethod))
+            })
+            .unwrap();
+        // other applications keep their data
+        server.bind(7);
+        app::send_raw(&mut client, 1000, 7, b"not rpc").unwrap();
+        assert_eq!(calls.pending(), 3);
+
+        exchange(&mut client, &mut server);
+        assert_eq!(served.poll(&mut server), 3);
+        assert_eq!(server.inbox.len(), 1);
+        exchange(&mut client, &mut server);
+        assert_eq!(calls.poll(&mut client), 3);
+        assert_eq!(calls.pending(), 0);
+        drop(calls);
+        assert_eq!(
+            replies.into_inner(),
+            [
+                Ok(Reading {
+                    sensor: 3,
+                    value: 21500
+                }),
+                Ok(Reading {
+                    sensor: 4,
+                    value: 21500
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn unanswered_calls_time_out() {
+        let mut client = node(0);
+        let clock = ManualClock::new(0);
+        client.timer_call(&clock);
+        let timed_out = RefCell::new(0);
+        let mut calls = Rpc::new();
+        let id = calls
+            .call_raw(&mut client, 1000, 1, b"", Duration::from_secs(2), |r| {
+                assert_eq!(r.unwrap_err(), RpcError::Timeout);
+                *timed_out.borrow_mut() += 1;
+            })
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        client.timer_call(&clock);
+        assert_eq!(calls.poll(&mut client), 0);
+        clock.advance(Duration::from_secs(1));
+        client.timer_call(&clock);
+        assert_eq!(calls.poll(&mut client), 1);
+
+        // a late response finds no call
+        let late = Delivery {
+            port: RPC_PORT,
+            source: 1000,
+            payload: to_payload(&encode(RESPONSE, id, 1, b"1")).unwrap(),
+        };
+        Queue::try_push(&mut client.inbox, late).unwrap();
+        assert_eq!(calls.poll(&mut client), 0);
+        assert!(client.inbox.is_empty());
+        drop(calls);
+        assert_eq!(timed_out.into_inner(), 1);
+        assert_eq!(
+            Rpc::new().call_raw(&mut Vcp::new(false), 1000, 1, b"", Duration::ZERO, |_| ()),
+            Err(RpcError::NoPosition)
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app;
+use crate::collections::to_text;
+use crate::vcp::{CordId, Packet, Port, Vcp};
+
+/// First byte of every frame, the decoder looks for it after garbage
+pub const SYNC: u8 = 0xA5;
+/// Longest payload of a frame
+pub const MAX_FRAME_LEN: usize = 4096;
+/// Bytes a frame is longer than its payload: the sync byte, the length and the CRC
+pub const OVERHEAD: usize = 7;
+
+/// CRC-32 (IEEE 802.3) of the bytes
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Frame the payload for a byte stream like a UART: the sync byte, the length as a
+/// little-endian u16, the payload and the CRC-32 of length and payload.
+///
+/// # Panics
+/// If the payload is longer than `MAX_FRAME_LEN`
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= MAX_FRAME_LEN, "frame too long");
+    let mut frame = Vec::with_capacity(payload.len() + OVERHEAD);
+    frame.push(SYNC);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+    let crc = crc32(&frame[1..]);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Frame the JSON of a message
+pub fn encode_message(message: &impl Serialize) -> Vec<u8> {
+    encode_frame(&serde_json::to_vec(message).expect("messages can always be encoded"))
+}
+
+/// Finds the frames in a byte stream. Bytes between frames, like log lines on a shared
+/// console, are skipped. A frame with a wrong CRC is dropped and the search goes on after
+/// its sync byte, so a lost byte costs at most the frames it overlaps.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Frames dropped because of their length or CRC
+    pub corrupt: u32,
+    /// Bytes skipped outside of frames
+    pub skipped: u32,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add received bytes, take the frames with `next_frame`
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The payload of the next complete frame, `None` until more bytes arrived
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let start = self
+                .buf
+                .iter()
+                .position(|&b| b == SYNC)
+                .unwrap_or(self.buf.len());
+            self.skipped += start as u32;
+            self.buf.drain(..start);
+            if self.buf.len() < 3 {
+                return None;
+            }
+            let len = usize::from(u16::from_le_bytes([self.buf[1], self.buf[2]]));
+            if len > MAX_FRAME_LEN {
+                self.drop_sync();
+                continue;
+            }
+            let end = 3 + len;
+            if self.buf.len() < end + 4 {
+                return None;
+            }
+            let crc = u32::from_le_bytes(self.buf[end..end + 4].try_into().unwrap());
+            if crc != crc32(&self.buf[1..end]) {
+                self.drop_sync();
+                continue;
+            }
+            let payload = self.buf[3..end].to_vec();
+            self.buf.drain(..end + 4);
+            return Some(payload);
+        }
+    }
+
+    /// The next complete frame that holds a `T`. Frames that don't decode are corrupt.
+    pub fn next_message<T: for<'de> Deserialize<'de>>(&mut self) -> Option<T> {
+        while let Some(frame) = self.next_frame() {
+            match serde_json::from_slice(&frame) {
+                Ok(message) => return Some(message),
+                Err(_) => self.corrupt += 1,
+            }
+        }
+        None
+    }
+
+    /// Give up on the frame at the start, it was none
+    fn drop_sync(&mut self) {
+        self.corrupt += 1;
+        self.skipped += 1;
+        self.buf.remove(0);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// What a gateway node tells the host on the other end of its serial line
+pub enum Uplink {
+    /// A packet the gateway received over the air
+    Packet(Box<Packet>),
+    /// Position and neighbors of the gateway
+    Status {
+        c_id: Option<CordId>,
+        neighbors: Vec<CordId>,
+    },
+    /// A text that arrived for the gateway
+    Text { sender_cid: CordId, text: String },
+    /// Data that arrived on a port the gateway bound
+    Data {
+        port: Port,
+        source: CordId,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What the host asks a gateway node to do
+pub enum Downlink {
+    /// Send a text to the node at `final_cid`
+    Text { final_cid: CordId, text: String },
+    /// Send data to the application on `port` at the node at `final_cid`
+    Data {
+        final_cid: CordId,
+        port: Port,
+        payload: Vec<u8>,
+    },
+    /// Accept data on the port and pass it on to the host
+    Bind { port: Port },
+}
+
+/// The status of the gateway
+pub fn status(vcp: &Vcp) -> Uplink {
+    Uplink::Status {
+        c_id: vcp.c_id,
+        neighbors: vcp.neighbors.keys().copied().collect(),
+    }
+}
+
+/// Take the texts and data the gateway and its virtual nodes received, for the host
+pub fn take_uplinks(vcp: &mut Vcp) -> Vec<Uplink> {
+    let mut uplinks = Vec::new();
+    let virt = vcp.virtual_nodes.iter_mut();
+    for (texts, inbox) in core::iter::once((&mut vcp.data_storage, &mut vcp.inbox))
+        .chain(virt.map(|v| (&mut v.data_storage, &mut v.inbox)))
+    {
+        uplinks.extend(core::mem::take(texts).into_iter().map(|data| Uplink::Text {
+            sender_cid: data.sender_cid,
+            text: data.text.as_str().into(),
+        }));
+        uplinks.extend(core::mem::take(inbox).into_iter().map(|d| Uplink::Data {
+            port: d.port,
+            source: d.source,
+            payload: d.payload.to_vec(),
+        }));
+    }
+    uplinks
+}
+
+/// Do what the host asked. Returns false if it could not be done, like a text that does
+/// not fit into a packet.
+pub fn apply(vcp: &mut Vcp, downlink: &Downlink) -> bool {
+    match downlink {
+        Downlink::Text { final_cid, text } => {
+            if vcp.c_id.is_none() || to_text(text).is_none() {
+                return false;
+            }
+            vcp.send_text_data(*final_cid, text);
+            true
+        }
+        Downlink::Data {
+            final_cid,
+            port,
+            payload,
+        } => app::send_raw(vcp, *final_cid, *port, payload).is_ok(),
+        Downlink::Bind { port } => vcp.bind(*port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::collections::to_payload;
+    use crate::vcp::Message;
+
+    #[test]
+    fn frames_survive_garbage_and_corruption() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let first = encode_frame(b"first");
+        let mut second = encode_frame(b"second");
+        let third = encode_frame(&[SYNC; 3]);
+        assert_eq!(first.len(), 5 + OVERHEAD);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"I (312) boot: log line\n");
+        // arrives in pieces
+        decoder.push(&first[..4]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&first[4..]);
+        assert_eq!(decoder.next_frame().unwrap(), b"first");
+
+        second[5] ^= 0x20;
+        decoder.push(&second);
+        decoder.push(&third);
+        assert_eq!(decoder.next_frame().unwrap(), [SYNC; 3]);
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.corrupt, 1);
+
+        // a sync byte with an impossible length
+        decoder.push(&[SYNC, 0xFF, 0xFF]);
+        decoder.push(&first);
+        assert_eq!(decoder.next_frame().unwrap(), b"first");
+        assert_eq!(decoder.corrupt, 2);
+    }
+
+    #[test]
+    fn the_gateway_passes_texts_both_ways() {
+        let mut gateway = Vcp::new(false);
+        gateway.config.verbose = false;
+        gateway.c_id = Some(0);
+        let mut far = Vcp::new(false);
+        far.config.verbose = false;
+        far.c_id = Some(1000);
+        // they hear each other's Hello
+        far.timer_call(&ManualClock::new(0));
+        gateway.timer_call(&ManualClock::new(0));
+        gateway.receive(&far.outgoing_msgs.pop().unwrap());
+        far.receive(&gateway.outgoing_msgs.pop().unwrap());
+
+        let mut decoder = FrameDecoder::new();
+        let down = Downlink::Text {
+            final_cid: 1000,
+            text: "to 1000".into(),
+        };
+        decoder.push(&encode_message(&down));
+        let down: Downlink = decoder.next_message().unwrap();
+        assert!(apply(&mut gateway, &down));
+        assert!(apply(&mut gateway, &Downlink::Bind { port: 3 }));
+        let packet = gateway.outgoing_msgs.pop().unwrap();
+        assert!(matches!(packet.message, Message::Text(ref t) if t.as_str() == "to 1000"));
+
+        far.send_text_data(0, "to the host");
+        far.send_data(0, 3, to_payload(b"raw").unwrap());
+        for packet in core::mem::take(&mut far.outgoing_msgs) {
+            gateway.receive(&packet);
+        }
+        let uplinks = take_uplinks(&mut gateway);
+        for uplink in &uplinks {
+            decoder.push(&encode_message(uplink));
+        }
+        let Some(Uplink::Text { sender_cid, text }) = decoder.next_message() else {
+            panic!("no text in {:?}", uplinks);
+        };
+        assert_eq!((sender_cid, text.as_str()), (1000, "to the host"));
+        assert!(matches!(
+            decoder.next_message(),
+            Some(Uplink::Data { port: 3, source: 1000, ref payload }) if payload == b"raw"
+        ));
+        assert!(take_uplinks(&mut gateway).is_empty());
+    }
+}
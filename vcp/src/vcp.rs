@@ -1,34 +1,249 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    cmp::Reverse,
+    fmt::{self, Write},
+    time::Duration,
+};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::{clone, collections::BTreeMap, fmt};
+
+use crate::clock::{millis, Clock, Millis};
+use crate::collections::{
+    to_chunk, to_text, Bounded, Chunk, List, Map, Name, Payload, Queue, Text, CHUNK_CAPACITY,
+};
+use crate::limits::{
+    push_limited, victim, Limit, Limits, QueueStats, VcpStats, MAX_DATA_STORAGE, MAX_FLOODS,
+    MAX_FRAGMENTS, MAX_KEYS, MAX_NEIGHBORS, MAX_OUTGOING_MSGS, MAX_PORTS, MAX_REASSEMBLY,
+    MAX_SUBSCRIBERS, MAX_TOPICS, MAX_VIRTUAL_NODES,
+};
+use crate::link::{LinkConfig, LinkQuality};
+
+/// Print protocol debug output, unless it is disabled in the `VcpConfig`
+#[cfg(feature = "std")]
+macro_rules! trace {
+    ($vcp:expr, $($arg:tt)*) => {
+        if $vcp.config.verbose {
+            std::println!($($arg)*);
+        }
+    };
+}
+
+/// Without `std` there is nowhere to print to
+#[cfg(not(feature = "std"))]
+macro_rules! trace {
+    ($vcp:expr, $($arg:tt)*) => {
+        // only type check the arguments, never evaluate them
+        if false {
+            let _ = ($vcp.config.verbose, format_args!($($arg)*));
+        }
+    };
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Message {
     Hello(NeighborInfo),
-    SendUpdatePredecessor { new_position: CordId },
-    SendUpdateSuccessor { new_position: CordId },
-    CreateVirtualNode { virtual_position: CordId },
-    Text(String),
+    SendUpdatePredecessor {
+        new_position: CordId,
+        /// The join that asks for it, see `NeighborInfo::joined`
+        #[serde(default)]
+        token: JoinToken,
+    },
+    SendUpdateSuccessor {
+        new_position: CordId,
+        #[serde(default)]
+        token: JoinToken,
+    },
+    CreateVirtualNode {
+        virtual_position: CordId,
+        #[serde(default)]
+        token: JoinToken,
+    },
+    Text(Text),
+    /// Bytes for the application bound to `port` at the final receiver.
+    /// Forwarding replaces the `sender_cid`, so the data carries where it came from.
+    Data {
+        port: Port,
+        source: CordId,
+        payload: Payload,
+    },
+    /// A payload only the final receiver can read
+    Sealed(Sealed),
+    /// Ask the final receiver for its public key. Forwarding replaces the `sender_cid`,
+    /// so the request carries where the answer goes.
+    KeyRequest {
+        reply_to: CordId,
+    },
+    /// The public key of the node at `c_id`
+    KeyReply {
+        c_id: CordId,
+        key: PublicKey,
+    },
+    /// A piece of a routed message that is too long for a frame
+    Fragment(Fragment),
+    /// Register the subscriber at the rendezvous node of the topic, for one
+    /// `subscription_lease`
+    Subscribe {
+        topic: TopicId,
+        subscriber: CordId,
+    },
+    /// A publication on its way to the rendezvous node of the topic
+    Publish {
+        topic: TopicId,
+        source: CordId,
+        payload: Payload,
+    },
+    /// A publication on its way from the rendezvous node to a subscriber
+    Notify {
+        topic: TopicId,
+        source: CordId,
+        payload: Payload,
+    },
+    /// Data for `port` on every node, broadcast again by every node that hears it the
+    /// first time, for `ttl` more hops. `source` and `seq` tell the floods apart.
+    Flood {
+        source: CordId,
+        seq: u16,
+        ttl: u8,
+        port: Port,
+        payload: Payload,
+    },
+    /// Data for `port` on every node with a position in `[from, to]`. It is routed to
+    /// the node closest to `from`, then passed on from successor to successor.
+    Multicast {
+        from: CordId,
+        to: CordId,
+        source: CordId,
+        port: Port,
+        payload: Payload,
+    },
+    /// Data for `port` on the provider of `service` that is the fewest hops away.
+    /// `hops` counts the hops so far.
+    Anycast {
+        service: Service,
+        hops: u8,
+        source: CordId,
+        port: Port,
+        payload: Payload,
+    },
+    /// Walk the cord from its start to its end. It is routed to the node closest to the
+    /// start, then passed on from successor to successor. Every node on the way reports
+    /// to `origin`, `id` tells the walks apart.
+    Walk {
+        origin: CordId,
+        id: u16,
+    },
+    /// What a node reports to the walk `id`
+    WalkReport {
+        id: u16,
+        entry: CordEntry,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+/// A node of the cord, as it reports itself to a walk, see `Vcp::walk`
+pub struct CordEntry {
+    pub c_id: CordId,
+    pub predecessor: Option<CordId>,
+    pub successor: Option<CordId>,
+    /// Positions of the nodes it hears
+    pub neighbors: List<CordId, MAX_NEIGHBORS>,
+    /// Positions of the virtual nodes it hosts
+    pub virtual_nodes: List<CordId, MAX_VIRTUAL_NODES>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+/// The fragments of a message are routed on their own, and put back together at the
+/// final receiver. `source` and `id` tell the messages apart.
+pub struct Fragment {
+    /// Position of the node that split the message
+    pub source: CordId,
+    pub id: u16,
+    pub index: u8,
+    pub count: u8,
+    /// A piece of the encoded `Message`, the pieces in the order of their index make it whole
+    pub chunk: Chunk,
+}
+
+/// X25519 public key of a node
+pub type PublicKey = [u8; 32];
+
+/// Id of the node with the public key, the first bytes of its SHA-256. It is sent with
+/// every signed frame, see `auth`. Nothing of the secret key can be learned from it.
+#[cfg(feature = "auth")]
+pub fn node_id(public_key: &PublicKey) -> u32 {
+    use sha2::{Digest, Sha256};
+    let hash = Sha256::digest(public_key);
+    u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+/// Number of an application, a node can run several of them on one cord
+pub type Port = u16;
+
+/// Hash of a topic name, see `topic_id`
+pub type TopicId = u32;
+
+/// A service a node can offer, like being a gateway, below `SERVICE_COUNT`
+pub type Service = u8;
+/// Services a node offers, one bit per `Service`
+pub type Services = u8;
+/// Number of distinct services
+pub const SERVICE_COUNT: usize = 8;
+/// Providers further away are unreachable, and anycasts that took this many hops are dropped
+pub const MAX_SERVICE_HOPS: u8 = 16;
+
+/// Random number of a join, it tells joins at the same position apart. 0 is no join.
+pub type JoinToken = u32;
+
+/// The id of a topic, the same on every node (32 bit FNV-1a of the name)
+pub fn topic_id(name: &str) -> TopicId {
+    name.bytes().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ TopicId::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+/// A payload encrypted for the public key of the final receiver, see `e2e`.
+/// Relays forward it like a text, but can't read it.
+pub struct Sealed {
+    /// Who encrypted it, the receiver needs the key to decrypt it
+    pub sender_key: PublicKey,
+    /// Never used twice by the sender
+    pub nonce: u64,
+    pub ciphertext: Payload,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Receiver {
     Broadcast,
     Unicast(CordId),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 /// A Packet that will be send over the air
 pub struct Packet {
     pub receiver: Receiver,
-    pub sender_name: String,
+    #[serde(default, skip_serializing_if = "no_name")]
+    pub sender_name: Name,
     pub sender_cid: Option<CordId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub final_cid: Option<CordId>,
     pub message: Message,
 }
 
+fn no_name(name: &Name) -> bool {
+    name.is_empty()
+}
+
 impl Packet {
-    pub fn new(src: &Vcp, mesage: Message) -> Self {
+    pub fn new<V>(src: &Vcp<V>, mesage: Message) -> Self {
         Packet {
             receiver: Receiver::Broadcast,
             sender_name: src.debug_name.clone(),
@@ -37,7 +252,7 @@ impl Packet {
             message: mesage,
         }
     }
-    pub fn new_unicast(src: &Vcp, dst: CordId, mesage: Message) -> Self {
+    pub fn new_unicast<V>(src: &Vcp<V>, dst: CordId, mesage: Message) -> Self {
         Packet {
             receiver: Receiver::Unicast(dst),
             sender_name: src.debug_name.clone(),
@@ -46,7 +261,12 @@ impl Packet {
             message: mesage,
         }
     }
-    pub fn new_unicast_data(src: &Vcp, dst: CordId, final_dst: CordId, mesage: Message) -> Self {
+    pub fn new_unicast_data<V>(
+        src: &Vcp<V>,
+        dst: CordId,
+        final_dst: CordId,
+        mesage: Message,
+    ) -> Self {
         Packet {
             receiver: Receiver::Unicast(dst),
             sender_name: src.debug_name.clone(),
@@ -56,18 +276,111 @@ impl Packet {
         }
     }
 
+    /// Decode a packet received over the air. Anyone in range can send anything.
+    pub fn decode(bytes: &[u8]) -> Result<Packet, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+
+    /// Encode the packet to be sent over the air
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("packets can always be encoded")
+    }
+
+    /// The encoded packet is at most `len` bytes long. The packet is only encoded,
+    /// if an upper bound of its length is longer, so short packets don't allocate.
+    pub fn fits(&self, len: usize) -> bool {
+        self.max_encoded_len() <= len || self.encode().len() <= len
+    }
+
+    /// An upper bound of the length of `encode`, without encoding.
+    /// The constants are the longest the fields get, without the strings and lists.
+    fn max_encoded_len(&self) -> usize {
+        let message = match self.message {
+            Message::Hello(ref n) => {
+                let services = if n.services == 0 { 0 } else { 15 };
+                let hops = if no_hops(&n.service_hops) {
+                    0
+                } else {
+                    16 + json_bytes_len(&n.service_hops)
+                };
+                let lost = if n.lost_start { 18 } else { 0 } + if n.lost_end { 16 } else { 0 };
+                let joined = if n.joined == 0 { 0 } else { 20 };
+                90 + services + hops + lost + joined
+            }
+            Message::SendUpdatePredecessor { .. }
+            | Message::SendUpdateSuccessor { .. }
+            | Message::CreateVirtualNode { .. } => 72,
+            Message::KeyRequest { .. } => 53,
+            Message::Text(ref text) => 9 + json_str_len(text),
+            Message::Data { ref payload, .. } => 54 + json_bytes_len(payload),
+            Message::Sealed(ref s) => {
+                69 + json_bytes_len(&s.sender_key) + json_bytes_len(&s.ciphertext)
+            }
+            Message::KeyReply { ref key, .. } => 39 + json_bytes_len(key),
+            Message::Fragment(ref f) => 78 + json_str_len(&f.chunk),
+            Message::Subscribe { .. } => 58,
+            Message::Publish { ref payload, .. } => 63 + json_bytes_len(payload),
+            Message::Notify { ref payload, .. } => 62 + json_bytes_len(payload),
+            Message::Flood { ref payload, .. } => 77 + json_bytes_len(payload),
+            Message::Multicast { ref payload, .. } => 93 + json_bytes_len(payload),
+            Message::Anycast { ref payload, .. } => 82 + json_bytes_len(payload),
+            Message::Walk { .. } => 42,
+            Message::WalkReport { ref entry, .. } => {
+                133 + json_cids_len(&entry.neighbors) + json_cids_len(&entry.virtual_nodes)
+            }
+        };
+        108 + json_str_len(&self.sender_name) + message
+    }
+
     pub fn new_receiver(&self, new_dst: CordId) -> Packet {
         let mut new_pkt = self.clone();
-        new_pkt.receiver = Receiver::Unicast((new_dst));
-        let new = new_pkt; //make it immutable
-        new
+        new_pkt.receiver = Receiver::Unicast(new_dst);
+        new_pkt
     }
 
-    pub fn is_type_data(&self) -> bool {
-        if let Message::Text(ref l) = self.message {
-            return true;
+    /// How important the packet is, when the outgoing queue is full
+    pub fn priority(&self) -> u32 {
+        match self.message {
+            // sent again with the next tick anyway
+            Message::Hello(_) => 0,
+            Message::Text(_)
+            | Message::Data { .. }
+            | Message::Sealed(_)
+            | Message::KeyRequest { .. }
+            | Message::KeyReply { .. }
+            | Message::Fragment(_)
+            | Message::Subscribe { .. }
+            | Message::Publish { .. }
+            | Message::Notify { .. }
+            | Message::Flood { .. }
+            | Message::Multicast { .. }
+            | Message::Anycast { .. }
+            | Message::Walk { .. }
+            | Message::WalkReport { .. } => 1,
+            // changes of the cord
+            _ => 2,
         }
-        return false;
+    }
+
+    /// The packet is routed along the cord to its `final_cid`
+    pub fn is_type_data(&self) -> bool {
+        matches!(
+            self.message,
+            Message::Text(_)
+                | Message::Data { .. }
+                | Message::Sealed(_)
+                | Message::KeyRequest { .. }
+                | Message::KeyReply { .. }
+                | Message::Fragment(_)
+                | Message::Subscribe { .. }
+                | Message::Publish { .. }
+                | Message::Notify { .. }
+                | Message::Flood { .. }
+                | Message::Multicast { .. }
+                | Message::Anycast { .. }
+                | Message::Walk { .. }
+                | Message::WalkReport { .. }
+        )
     }
 
     /// check if self is the receiver of dst. Or if dst in broadcast
@@ -78,19 +391,19 @@ impl Packet {
                 return false;
             }
         }
-        return true;
+        true
     }
 }
 
 #[derive(Clone, Debug)]
 /// A Packet that will be send over the air
 pub struct Data {
-    pub text: String,
+    pub text: Text,
     pub sender_cid: CordId,
 }
 
 impl Data {
-    pub fn new(ttext: String, ssender_cid: CordId) -> Self {
+    pub fn new(ttext: Text, ssender_cid: CordId) -> Self {
         Data {
             text: ttext,
             sender_cid: ssender_cid,
@@ -98,39 +411,426 @@ impl Data {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Application data that arrived at its final receiver
+pub struct Delivery {
+    pub port: Port,
+    /// Position of the node that sent it
+    pub source: CordId,
+    pub payload: Payload,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A publication that arrived at a subscriber
+pub struct Publication {
+    pub topic: TopicId,
+    /// Position of the node that published it
+    pub source: CordId,
+    pub payload: Payload,
+}
+
+#[derive(Clone, Debug)]
+/// A join of this node that its neighbors did not confirm yet
+struct Join {
+    token: JoinToken,
+    /// The neighbors that were asked to make room, with the last join they made room for
+    /// before. Each confirms the join by sending its token back with a Hello.
+    asked: List<(CordId, JoinToken), 2>,
+    /// When the position is given up, if it is still not confirmed
+    deadline: Millis,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// A flood this node has seen, its duplicates are dropped until it is forgotten
+struct SeenFlood {
+    source: CordId,
+    seq: u16,
+    forget: Millis,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// A subscription, kept by the rendezvous node of its topic until the lease runs out
+struct Lease {
+    topic: TopicId,
+    subscriber: CordId,
+    expires: Millis,
+}
+
+#[derive(Clone, Debug)]
+/// The fragments of a message that arrived so far
+struct Reassembly {
+    source: CordId,
+    id: u16,
+    count: u8,
+    /// The message is given up if it is not complete by then
+    deadline: Millis,
+    fragments: List<Fragment, MAX_FRAGMENTS>,
+}
+
 // General Code
 
-type CordId = u32;
-type NeighborMap = BTreeMap<CordId, NeighborInfo>;
-#[derive(Clone, Debug, Copy, Serialize, Deserialize)]
+pub type CordId = u32;
+
+/// Seed of the join tokens, until the driver calls `Vcp::seed`
+const DEFAULT_SEED: u32 = 0x9e37_79b9;
+
+/// Length of the string in JSON, with the quotes and the escaped characters
+fn json_str_len(s: &str) -> usize {
+    let escaped: usize = s
+        .bytes()
+        .map(|b| match b {
+            b'"' | b'\\' | b'\n' | b'\r' | b'\t' | 0x08 | 0x0c => 2,
+            0..=0x1f => 6,
+            _ => 1,
+        })
+        .sum();
+    escaped + 2
+}
+
+/// Length of the bytes as JSON list, with the brackets and the commas
+fn json_bytes_len(bytes: &[u8]) -> usize {
+    let digits: usize = bytes
+        .iter()
+        .map(|&b| match b {
+            0..=9 => 1,
+            10..=99 => 2,
+            _ => 3,
+        })
+        .sum();
+    digits + bytes.len().saturating_sub(1) + 2
+}
+
+/// Length of the positions as JSON list, with the brackets and the commas
+fn json_cids_len(cids: &[CordId]) -> usize {
+    let digits: usize = cids
+        .iter()
+        .map(|c| c.checked_ilog10().map_or(1, |d| d as usize + 1))
+        .sum();
+    digits + cids.len().saturating_sub(1) + 2
+}
+
+/// Cut the text into pieces that take at most `len` bytes in a JSON string, and at most
+/// `CHUNK_CAPACITY` bytes unescaped, between characters. It stops early at a character
+/// that takes more than `len` bytes.
+fn split(text: &str, len: usize) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    core::iter::from_fn(move || {
+        let mut escaped = 0;
+        let end = rest
+            .char_indices()
+            .find_map(|(i, c)| {
+                escaped += escaped_len(c);
+                (escaped > len || i + c.len_utf8() > CHUNK_CAPACITY).then_some(i)
+            })
+            .unwrap_or(rest.len());
+        // nothing left, or a character that does not fit at all
+        if end == 0 {
+            return None;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// Bytes of the character in a JSON string, as serde_json escapes it
+fn escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\u{8}' | '\u{c}' | '\n' | '\r' | '\t' => 2,
+        '\0'..='\u{1f}' => 6,
+        _ => c.len_utf8(),
+    }
+}
+
+/// The position in the middle of `a` and `b`, without overflowing
+fn midpoint(a: CordId, b: CordId) -> CordId {
+    ((a as u64 + b as u64) / 2) as CordId
+}
+type NeighborMap = Map<CordId, NeighborInfo, MAX_NEIGHBORS>;
+#[derive(Clone, Debug, Copy, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 /// Packs information about all neighbors, that have to be remembered
 pub struct NeighborInfo {
     predecessor: Option<CordId>,
     successor: Option<CordId>,
+    #[serde(default, skip_serializing_if = "is_false")]
     is_virtual: bool,
-    age: u64,
+    /// Milliseconds since the last Hello, counted by the receiver, never sent
+    #[serde(skip)]
+    age: Millis,
+    /// Counts the Hellos of the sender, to find the lost ones
+    seq: u16,
+    /// Measured by the receiver, never sent
+    #[serde(skip)]
+    link: LinkQuality,
+    /// Services the sender offers
+    #[serde(default, skip_serializing_if = "no_services")]
+    services: Services,
+    /// Hops from the sender to the closest other provider of each service, 0 if it knows none
+    #[serde(default, skip_serializing_if = "no_hops")]
+    service_hops: [u8; SERVICE_COUNT],
+    /// The predecessors of the sender don't lead to the start of the cord
+    #[serde(default, skip_serializing_if = "is_false")]
+    lost_start: bool,
+    /// The successors of the sender don't lead to the end of the cord
+    #[serde(default, skip_serializing_if = "is_false")]
+    lost_end: bool,
+    /// The last join the sender made room for. A joining node waits for its token here.
+    #[serde(default, skip_serializing_if = "no_join")]
+    joined: JoinToken,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn no_join(token: &JoinToken) -> bool {
+    *token == 0
+}
+
+fn no_services(services: &Services) -> bool {
+    *services == 0
+}
+
+fn no_hops(hops: &[u8; SERVICE_COUNT]) -> bool {
+    hops.iter().all(|&h| h == 0)
+}
+
+impl NeighborInfo {
+    pub fn link(&self) -> &LinkQuality {
+        &self.link
+    }
+
+    /// Hops from here to the closest provider of the service by way of this neighbor
+    fn hops_via(&self, service: Service) -> Option<u8> {
+        if self.services & (1 << service) != 0 {
+            return Some(1);
+        }
+        match self.service_hops[usize::from(service)] {
+            0 => None,
+            hops => Some(hops.saturating_add(1)).filter(|&h| h < MAX_SERVICE_HOPS),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// When a node broadcasts its Hello
+pub enum HelloSchedule {
+    /// Every `hello_interval`
+    Fixed,
+    /// Trickle-style: start with `hello_interval` and double the interval after every Hello,
+    /// up to `max_interval`. A new or lost neighbor starts over with fast Hellos,
+    /// a new predecessor or successor is announced right away.
+    /// The interval never exceeds half the `neighbor_timeout`, so neighbors don't time out.
+    Adaptive { max_interval: Duration },
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Tunable protocol parameters. The times are kept as well as `timer_call` is called,
+/// so call it more often than the shortest of them.
+pub struct VcpConfig {
+    /// A Hello is broadcast every `hello_interval`
+    pub hello_interval: Duration,
+    pub hello_schedule: HelloSchedule,
+    /// Neighbors not heard of for this long are forgotten
+    pub neighbor_timeout: Duration,
+    /// Print every sent packet and data transmission to stdout
+    pub verbose: bool,
+    /// Position of the first node of the cord
+    pub cord_start: CordId,
+    /// Position of the last node of the cord
+    pub cord_end: CordId,
+    /// Capacities of the tables and queues
+    pub limits: Limits,
+    /// When a link to a neighbor is good enough to use it
+    pub link: LinkConfig,
+    /// Longest encoded packet a frame can carry. Longer routed messages are fragmented.
+    pub max_frame_len: usize,
+    /// A fragmented message is given up if it is not complete this long after its first fragment
+    pub reassembly_timeout: Duration,
+    /// A rendezvous node forgets a subscription that was not refreshed for this long.
+    /// Subscribers refresh three times per lease.
+    pub subscription_lease: Duration,
+    /// Hops a flood travels at most
+    pub flood_ttl: u8,
+    /// How long a node remembers a flood, to drop its duplicates.
+    /// Longer than a flood takes to cross the network.
+    pub flood_memory: Duration,
+}
+
+impl Default for VcpConfig {
+    fn default() -> Self {
+        VcpConfig {
+            hello_interval: Duration::from_secs(1),
+            hello_schedule: HelloSchedule::Fixed,
+            neighbor_timeout: Duration::from_secs(5),
+            verbose: true,
+            cord_start: 0,
+            cord_end: 1000,
+            limits: Limits::default(),
+            link: LinkConfig::default(),
+            // ESP-NOW
+            max_frame_len: 250,
+            reassembly_timeout: Duration::from_secs(5),
+            subscription_lease: Duration::from_secs(30),
+            flood_ttl: 16,
+            flood_memory: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What the driver has to do after `Vcp::receive`
+pub enum Wakeup {
+    /// Nothing to send, wait for the next packet or `timer_call`
+    Idle,
+    /// Send `outgoing_msgs` now, instead of waiting for the next `timer_call`
+    Flush,
+}
+
+/// A virtual node, hosted by a physical one. It can't host virtual nodes itself.
+pub type VirtualNode = Vcp<NoVirtualNodes>;
+
+/// The virtual nodes a physical node hosts
+pub type VirtualNodes = List<VirtualNode, MAX_VIRTUAL_NODES>;
+
+#[derive(Clone, Copy, Debug, Default)]
+/// Virtual nodes don't host virtual nodes, so they have nowhere to store them
+pub struct NoVirtualNodes;
+
+/// Where a node keeps the virtual nodes it hosts
+pub trait VirtualNodeStore: Default {
+    fn as_slice(&self) -> &[VirtualNode];
+    fn as_mut_slice(&mut self) -> &mut [VirtualNode];
+    /// Take over a new virtual node, if the limit allows it
+    fn host(&mut self, node: VirtualNode, limit: &Limit, stats: &mut QueueStats);
+    /// Drop the virtual nodes that gave up their position
+    fn drop_unplaced(&mut self);
+}
+
+impl VirtualNodeStore for VirtualNodes {
+    fn as_slice(&self) -> &[VirtualNode] {
+        self
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [VirtualNode] {
+        self
+    }
+
+    fn host(&mut self, node: VirtualNode, limit: &Limit, stats: &mut QueueStats) {
+        push_limited(self, node, limit, stats, |_| 0);
+    }
+
+    fn drop_unplaced(&mut self) {
+        self.retain(|v| v.c_id.is_some());
+    }
+}
+
+impl VirtualNodeStore for NoVirtualNodes {
+    fn as_slice(&self) -> &[VirtualNode] {
+        &[]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [VirtualNode] {
+        &mut []
+    }
+
+    fn host(&mut self, _node: VirtualNode, _limit: &Limit, stats: &mut QueueStats) {
+        stats.rejected += 1;
+    }
+
+    fn drop_unplaced(&mut self) {}
 }
 
-pub struct Vcp {
+/// A node of the cord. `V` is where its virtual nodes are kept.
+pub struct Vcp<V = VirtualNodes> {
     /// The Cord Id (the Position). `None` means not assigned. And 0 is the first device.
     pub c_id: Option<CordId>,
-    pub debug_name: String,
+    pub debug_name: Name,
     /// All messages that should be sent
-    pub outgoing_msgs: Vec<Packet>,
+    pub outgoing_msgs: List<Packet, MAX_OUTGOING_MSGS>,
 
     pub predecessor: Option<CordId>,
     pub successor: Option<CordId>,
 
     pub neighbors: NeighborMap,
 
-    ticks: u64,
-    pub virtual_nodes: Vec<Vcp>,
+    /// Time of the first and of the last `timer_call`
+    started: Option<Millis>,
+    now: Millis,
+    /// The current interval of an adaptive `HelloSchedule`, and when the next Hello is due
+    hello_interval: Millis,
+    next_hello: Millis,
+    /// Predecessor and successor, as sent with the last Hello
+    announced: (Option<CordId>, Option<CordId>),
+    /// Sequence number of the next Hello
+    hello_seq: u16,
+    /// The position was restored after a reboot and no neighbor confirmed it yet
+    restored: bool,
+    /// When the restored position is given up, if it is still not confirmed
+    confirm_by: Option<Millis>,
+    /// The predecessors don't lead to the start of the cord, see `repair_cord`
+    lost_start: bool,
+    /// The successors don't lead to the end of the cord
+    lost_end: bool,
+    /// Since when the node has no predecessor or successor, though it is not at that end
+    loose_since: Option<Millis>,
+    /// The predecessor or successor the node had before
+    lost_neighbor: Option<CordId>,
+    /// The own join, until the neighbors confirmed it
+    join: Option<Join>,
+    /// The last join this node made room for, sent with the Hellos
+    accepted_join: JoinToken,
+    /// State of the generator of the join tokens, see `seed`
+    rng: u32,
+    /// Random wait before joining again, so nodes that gave up together don't join together
+    backoff: Millis,
+    pub virtual_nodes: V,
     is_virtual: bool,
 
-    pub data_storage: Vec<Data>,
+    pub data_storage: List<Data, MAX_DATA_STORAGE>,
+    /// Received payloads for this node, still encrypted. Kept within the `data_storage` limit.
+    pub sealed_storage: List<Sealed, MAX_DATA_STORAGE>,
+    /// Received data for the bound ports, oldest first. Kept within the `data_storage` limit.
+    pub inbox: List<Delivery, MAX_DATA_STORAGE>,
+    /// Ports data is accepted on, see `bind`
+    ports: List<Port, MAX_PORTS>,
+    /// Fragmented messages for this node, that are not complete yet
+    reassembly: List<Reassembly, MAX_REASSEMBLY>,
+    /// Id of the next message that is fragmented
+    next_fragment_id: u16,
+    /// Topics this node subscribed to, see `subscribe`
+    topics: List<TopicId, MAX_TOPICS>,
+    /// When the subscriptions are refreshed next
+    next_refresh: Millis,
+    /// Subscriptions of the topics this node is the rendezvous of
+    leases: List<Lease, MAX_SUBSCRIBERS>,
+    /// Received publications of the subscribed topics, oldest first.
+    /// Kept within the `data_storage` limit.
+    pub publications: List<Publication, MAX_DATA_STORAGE>,
+    /// Floods seen lately, oldest first
+    seen_floods: List<SeenFlood, MAX_FLOODS>,
+    /// Sequence number of the next flood
+    next_flood_seq: u16,
+    /// Id of the last walk this node started
+    walk_id: u16,
+    /// The nodes that reported to the last walk, in the order the reports arrived.
+    /// Kept within the `data_storage` limit.
+    pub topology: List<CordEntry, MAX_DATA_STORAGE>,
+    /// Public key of this node, sent on request. Without one, the node can't receive sealed
+    /// payloads.
+    pub public_key: Option<PublicKey>,
+    /// Services this node offers, sent with the Hellos. Anycasts for them end here.
+    pub services: Services,
+    /// Public keys of other nodes by position, from key requests
+    pub keys: Map<CordId, PublicKey, MAX_KEYS>,
+
+    pub config: VcpConfig,
+    /// How full the tables and queues got and what was dropped
+    pub stats: VcpStats,
 }
 
-impl fmt::Display for Vcp {
+impl<V: VirtualNodeStore> fmt::Display for Vcp<V> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let prev = self
             .predecessor
@@ -144,9 +844,9 @@ impl fmt::Display for Vcp {
             write!(f, "{}", cid)?;
         }
         //write!(f, "'{}'", self.debug_name)?;
-        if !self.virtual_nodes.is_empty() {
+        if !self.virtual_nodes.as_slice().is_empty() {
             write!(f, "\nvirt{{")?;
-            for virt in &self.virtual_nodes {
+            for virt in self.virtual_nodes.as_slice() {
                 write!(f, "{},", virt.c_id.unwrap())?;
             }
             write!(f, "}}")?;
@@ -162,127 +862,205 @@ impl fmt::Display for Vcp {
 
 impl Vcp {
     pub fn new(is_first: bool) -> Self {
-        let id = if is_first { Some(0) } else { None };
+        Vcp::with_config(is_first, VcpConfig::default())
+    }
+
+    pub fn with_config(is_first: bool, config: VcpConfig) -> Self {
+        let id = if is_first {
+            Some(config.cord_start)
+        } else {
+            None
+        };
+        Vcp::create(id, config)
+    }
+}
+
+impl VirtualNode {
+    /// A virtual node at a fixed position
+    pub fn new_virtual(position: CordId, config: VcpConfig) -> Self {
+        let mut node = Vcp::create(Some(position), config);
+        node.is_virtual = true;
+        node
+    }
+}
+
+impl<V: VirtualNodeStore> Vcp<V> {
+    fn create(c_id: Option<CordId>, config: VcpConfig) -> Self {
         Vcp {
-            c_id: id,
-            debug_name: String::from(""),
-            outgoing_msgs: Vec::new(),
+            c_id,
+            debug_name: Name::new(),
+            outgoing_msgs: List::new(),
             successor: None,
             predecessor: None,
-            neighbors: BTreeMap::new(),
-            ticks: 0, // the clock of the node
-            virtual_nodes: Vec::new(),
+            neighbors: NeighborMap::new(),
+            started: None,
+            now: 0,
+            hello_interval: millis(config.hello_interval).max(1),
+            next_hello: 0,
+            announced: (None, None),
+            hello_seq: 0,
+            restored: false,
+            confirm_by: None,
+            lost_start: false,
+            lost_end: false,
+            loose_since: None,
+            lost_neighbor: None,
+            join: None,
+            accepted_join: 0,
+            rng: DEFAULT_SEED,
+            backoff: 0,
+            virtual_nodes: V::default(),
             is_virtual: false,
-            data_storage: Vec::new(),
+            data_storage: List::new(),
+            sealed_storage: List::new(),
+            inbox: List::new(),
+            ports: List::new(),
+            reassembly: List::new(),
+            next_fragment_id: 0,
+            topics: List::new(),
+            next_refresh: 0,
+            leases: List::new(),
+            publications: List::new(),
+            seen_floods: List::new(),
+            next_flood_seq: 0,
+            walk_id: 0,
+            topology: List::new(),
+            public_key: None,
+            services: 0,
+            keys: Map::new(),
+            config,
+            stats: VcpStats::default(),
         }
     }
 
     /// Function to request a CID, and send PositionChange Requests to Other nodes
     fn set_my_position(&mut self) {
-        if self.neighbors.len() == 0 {
+        // only join next to nodes that reach the start, the others are about to move
+        let anchors: List<(CordId, NeighborInfo), MAX_NEIGHBORS> = self
+            .neighbors
+            .iter()
+            .filter(|(_, n)| !n.lost_start && !n.lost_end)
+            .map(|(&cid, &n)| (cid, n))
+            .collect();
+        if anchors.is_empty() {
             return;
         }
 
-        const S: CordId = 0;
-        const E: CordId = 1000;
+        let start = self.config.cord_start;
+        let end = self.config.cord_end;
+        let token = self.next_token();
         const I: f64 = 0.5;
 
         fn position(a: CordId, b: CordId) -> CordId {
             let tmp = a as f64 - I * (a as f64 - b as f64);
 
-            return tmp as CordId;
+            tmp as CordId
         }
 
-        for (&cid, neighbor) in self.neighbors.clone().iter() {
-            if cid == S {
+        for &(cid, neighbor) in anchors.iter() {
+            if cid == start {
                 // neigh is the first node
-                let new_position: CordId;
-                if neighbor.successor.is_none() {
-                    new_position = E;
-                } else if neighbor.successor == Some(E) {
-                    new_position = (S + E) / 2;
-                } else {
-                    if neighbor.successor != None{
-                        new_position = position(neighbor.successor.unwrap(), cid);
-                    }
-                    else{
-                        new_position = position(0, cid);
-                    }
+                let new_position = match neighbor.successor {
+                    None => end,
+                    Some(succ) if succ == end => midpoint(start, end),
+                    Some(succ) => position(succ, cid),
+                };
+                // the neighbor can't move where another node is
+                if self.neighbors.contains_key(&new_position) {
+                    continue;
                 }
-                self.c_id = Some(S);
+                self.c_id = Some(start);
                 self.successor = Some(new_position);
                 self.send(&Packet::new_unicast(
                     self,
                     cid,
-                    Message::SendUpdatePredecessor { new_position },
+                    Message::SendUpdatePredecessor {
+                        new_position,
+                        token,
+                    },
                 ));
+                self.neighbor_moved(cid, new_position);
+                self.await_confirmation(token, [(new_position, neighbor.joined)]);
                 return;
-            } else if cid == E {
+            } else if cid == end {
                 // neigh is the last node
-                let new_position: CordId;
-                if neighbor.successor == Some(S) {
-                    new_position = (S + E) / 2;
+                let new_position = if neighbor.successor == Some(start) {
+                    midpoint(start, end)
                 } else {
-                    if neighbor.predecessor != None{
-                        new_position = position(neighbor.predecessor.unwrap(), cid);
-                    }
-                    else{
-                        new_position = position(0, cid);
-                    }
+                    position(neighbor.predecessor.unwrap_or(start), cid)
+                };
+                if self.neighbors.contains_key(&new_position) {
+                    continue;
                 }
-                self.c_id = Some(E);
+                self.c_id = Some(end);
                 self.predecessor = Some(new_position);
                 self.send(&Packet::new_unicast(
                     self,
                     cid,
-                    Message::SendUpdateSuccessor { new_position },
+                    Message::SendUpdateSuccessor {
+                        new_position,
+                        token,
+                    },
                 ));
+                self.neighbor_moved(cid, new_position);
+                self.await_confirmation(token, [(new_position, neighbor.joined)]);
                 return;
             }
         }
 
         // ... no neighbor at End or Start found
 
+        // a node that joined a gap a moment ago is not known to both sides yet
+        let known: List<CordId, MAX_NEIGHBORS> = self.neighbors.keys().copied().collect();
+        let free = |a: CordId, b: CordId| !known.iter().any(|&c| a.min(b) < c && c < a.max(b));
+
         // search for two neighbors which are direct neighbors
         // cid -> cid2 and request to put in between:
         // cid -> self.c_id -> cid2
-        for (&cid, neighbor) in self.neighbors.clone().iter() {
+        for &(cid, neighbor) in anchors.iter() {
             let p_temp: CordId;
-            for (&cid2, _) in self.neighbors.clone().iter() {
+            for &(cid2, neighbor2) in anchors.iter() {
                 if cid2 == cid {
                     continue;
                 }
-                if neighbor.predecessor == Some(cid2) {
-                    p_temp = (cid + cid2) / 2;
+                if neighbor.predecessor == Some(cid2) && free(cid, cid2) {
+                    p_temp = midpoint(cid, cid2);
                     self.c_id = Some(p_temp);
                     self.predecessor = Some(cid);
                     self.successor = Some(cid2);
                     self.send(&Packet::new_unicast(
                         self,
                         cid,
-                        Message::SendUpdateSuccessor { new_position: cid },
+                        Message::SendUpdateSuccessor {
+                            new_position: cid,
+                            token,
+                        },
                     ));
                     self.send(&Packet::new_unicast(
                         self,
                         cid2,
-                        Message::SendUpdatePredecessor { new_position: cid2 },
+                        Message::SendUpdatePredecessor {
+                            new_position: cid2,
+                            token,
+                        },
                     ));
+                    self.await_confirmation(
+                        token,
+                        [(cid, neighbor.joined), (cid2, neighbor2.joined)],
+                    );
                     return;
                 }
             }
         }
 
         // Otherwise request to create a virtual node
-        if let Some((&cid, neigh)) = self.neighbors.iter().find(|n| !n.1.is_virtual) {
+        if let Some(&(cid, neigh)) = anchors
+            .iter()
+            .find(|(cid, n)| !n.is_virtual && free(*cid, n.successor.unwrap_or(0)))
+        {
             // find a neighbor which is not virtual
-            let new_virt: CordId;
-            if neigh.successor != None{
-                new_virt = (cid + neigh.successor.unwrap()) / 2;
-            }
-            else{
-                new_virt = (cid) / 2;
-            }
-            let new_cid = (cid + new_virt) / 2;
+            let new_virt = midpoint(cid, neigh.successor.unwrap_or(0));
+            let new_cid = midpoint(cid, new_virt);
             self.c_id = Some(new_cid);
             self.predecessor = Some(cid);
             self.successor = Some(new_virt);
@@ -291,157 +1069,1368 @@ impl Vcp {
                 cid,
                 Message::CreateVirtualNode {
                     virtual_position: new_virt,
+                    token,
                 },
             ));
+            self.await_confirmation(token, [(cid, neigh.joined)]);
+        }
+    }
+
+    /// Keep the position only if the neighbors that were asked to make room confirm the join.
+    /// Nodes that join at the same moment can take the same position, the neighbors tell which
+    /// of them got it.
+    fn await_confirmation(
+        &mut self,
+        token: JoinToken,
+        asked: impl IntoIterator<Item = (CordId, JoinToken)>,
+    ) {
+        self.join = Some(Join {
+            token,
+            asked: asked.into_iter().collect(),
+            deadline: self
+                .now
+                .saturating_add(millis(self.config.neighbor_timeout)),
+        });
+    }
+
+    /// A Hello of a neighbor that was asked to make room for the own join. Once it sends the
+    /// token of the join back, the join is confirmed by it. If it made room for another join
+    /// instead, the other node got the position.
+    fn check_join(&mut self, sender_cid: CordId, joined: JoinToken) {
+        let Some(join) = self.join.as_mut() else {
+            return;
+        };
+        let Some(i) = join.asked.iter().position(|&(cid, _)| cid == sender_cid) else {
+            return;
+        };
+        if joined == join.token {
+            join.asked.remove(i);
+            if join.asked.is_empty() {
+                self.join = None;
+            }
+        } else if joined != join.asked[i].1 {
+            trace!(
+                self,
+                "Another node joined at the same position, joining again."
+            );
+            self.give_up_position();
+        }
+    }
+
+    /// Seed the tokens of the joins and the wait before joining again. Nodes that can join at
+    /// the same moment need different seeds, take them from a random source.
+    pub fn seed(&mut self, seed: u32) {
+        self.rng = seed.max(1);
+    }
+
+    /// A new random join token, xorshift32 never returns 0
+    fn next_token(&mut self) -> JoinToken {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    /// A neighbor was asked to move. Its entry is kept under the new position,
+    /// so the Hellos until its next own Hello already tell the right cord.
+    fn neighbor_moved(&mut self, old: CordId, new: CordId) {
+        if let Some(info) = self.neighbors.remove(&old) {
+            self.neighbors.insert(new, info);
         }
     }
 
     /// Method is called, when a new message is received.
-    pub fn receive(&mut self, packet: &Packet) {
+    /// Packets that are malformed or not meant for this node are ignored.
+    /// Returns `Wakeup::Flush` if the packet added to `outgoing_msgs`, like a forwarded text.
+    pub fn receive(&mut self, packet: &Packet) -> Wakeup {
+        self.receive_with_rssi(packet, None)
+    }
+
+    /// Like `receive`, for transports that report the signal strength of the frame in dBm
+    pub fn receive_with_rssi(&mut self, packet: &Packet, rssi: Option<i8>) -> Wakeup {
+        // packets the driver didn't take yet wait for the next flush anyway
+        let queued = self.outgoing_msgs.len();
         // call receive for all Sub Nodes
-        for virt in self.virtual_nodes.iter_mut() {
-            virt.receive(packet);
+        for virt in self.virtual_nodes.as_mut_slice() {
+            virt.receive_with_rssi(packet, rssi);
+        }
+        self.handle(packet, rssi);
+        // what they forward is sent right away as well
+        self.take_virtual_outgoing();
+        if self.outgoing_msgs.len() > queued {
+            Wakeup::Flush
+        } else {
+            Wakeup::Idle
         }
+    }
+
+    fn handle(&mut self, packet: &Packet, rssi: Option<i8>) {
         if !packet.is_for(self.c_id) {
             return;
         }
 
         match packet.message {
             Message::Text(ref msg) => {
-                let final_cid = packet.final_cid.expect("No final cid in text packet.");
-                let self_cid = self
-                    .c_id
-                    .expect("Expected self.cid beeing set when text msg is send.");
-                let sender_cid = packet
-                    .sender_cid
-                    .expect("Expected sender_cis beeing set when text msg send.");
-                let next_receiver = self.calc_closesed_to_final(final_cid);
-                if next_receiver == self_cid {
-                    //store message
-                    let _data = Data::new(msg.clone(), sender_cid);
-                    self.data_storage.push(_data);
-                    println!(
-                        "Node with cid: {} is final receiver of data text: {}.",
-                        self_cid, msg
-                    );
-                } else {
-                    println!(
-                        "Node with cid: {} forwarding data to node {}.",
-                        self_cid, next_receiver
+                let Some((self_cid, sender_cid)) = self.route(packet) else {
+                    return;
+                };
+                //store message
+                let _data = Data::new(msg.clone(), sender_cid);
+                push_limited(
+                    &mut self.data_storage,
+                    _data,
+                    &self.config.limits.data_storage,
+                    &mut self.stats.data_storage,
+                    |_| 0,
+                );
+                trace!(
+                    self,
+                    "Node with cid: {} is final receiver of data text: {}.",
+                    self_cid,
+                    msg
+                );
+            }
+            Message::Data {
+                port,
+                source,
+                ref payload,
+            } => {
+                if self.route(packet).is_some() {
+                    self.deliver(port, source, payload);
+                }
+            }
+            Message::Flood {
+                source,
+                seq,
+                ttl,
+                port,
+                ref payload,
+            } => {
+                // the host floods for its virtual nodes
+                if self.is_virtual || Some(source) == self.c_id || !self.remember_flood(source, seq)
+                {
+                    return;
+                }
+                self.deliver(port, source, payload);
+                if let Some(ttl) = ttl.checked_sub(1) {
+                    let message = Message::Flood {
+                        source,
+                        seq,
+                        ttl,
+                        port,
+                        payload: payload.clone(),
+                    };
+                    self.send(&Packet::new(self, message));
+                }
+            }
+            Message::Multicast {
+                from,
+                to,
+                source,
+                port,
+                ref payload,
+            } => {
+                let Some((self_cid, _)) = self.route(packet) else {
+                    return;
+                };
+                if (from..=to).contains(&self_cid) {
+                    self.deliver(port, source, payload);
+                }
+                if let Some(successor) = self.successor.filter(|&s| s > self_cid && s <= to) {
+                    self.send_routed(successor, packet.message.clone());
+                }
+            }
+            Message::Anycast {
+                service,
+                hops,
+                source,
+                port,
+                ref payload,
+            } => {
+                // only the chosen neighbor takes it
+                if matches!(packet.receiver, Receiver::Broadcast) {
+                    return;
+                }
+                self.anycast_hop(service, hops, source, port, payload.clone());
+            }
+            Message::Walk { origin, id } => {
+                let Some((self_cid, _)) = self.route(packet) else {
+                    return;
+                };
+                let entry = self.cord_entry(self_cid);
+                self.send_routed_or_handle(origin, Message::WalkReport { id, entry });
+                // the positions only grow on the way, so the walk ends
+                if let Some(successor) = self.successor.filter(|&s| s > self_cid) {
+                    self.send_routed(successor, packet.message.clone());
+                }
+            }
+            Message::WalkReport { id, ref entry } => {
+                if self.route(packet).is_none() || id != self.walk_id {
+                    return;
+                }
+                if self.topology.iter().any(|e| e.c_id == entry.c_id) {
+                    return;
+                }
+                push_limited(
+                    &mut self.topology,
+                    entry.clone(),
+                    &self.config.limits.data_storage,
+                    &mut self.stats.data_storage,
+                    |_| 0,
+                );
+            }
+            Message::Sealed(ref sealed) => {
+                if self.route(packet).is_some() {
+                    push_limited(
+                        &mut self.sealed_storage,
+                        sealed.clone(),
+                        &self.config.limits.data_storage,
+                        &mut self.stats.data_storage,
+                        |_| 0,
                     );
-                    //update packet info forward to closest neighbor to final
-                    self.send(&Packet::new_unicast_data(
+                }
+            }
+            Message::KeyRequest { reply_to } => {
+                if let (Some(_), Some(key)) = (self.route(packet), self.public_key) {
+                    let Some(c_id) = self.c_id else { return };
+                    self.send_routed(reply_to, Message::KeyReply { c_id, key });
+                }
+            }
+            Message::KeyReply { c_id, key } => {
+                if self.route(packet).is_some() {
+                    self.learn_key(c_id, key);
+                }
+            }
+            Message::Fragment(ref fragment) => {
+                if self.route(packet).is_some() {
+                    self.reassemble(packet, fragment);
+                }
+            }
+            Message::Subscribe { topic, subscriber } => {
+                if self.route(packet).is_some() {
+                    self.add_lease(topic, subscriber);
+                }
+            }
+            Message::Publish {
+                topic,
+                source,
+                ref payload,
+            } => {
+                if self.route(packet).is_none() {
+                    return;
+                }
+                let subscribers: List<CordId, MAX_SUBSCRIBERS> = self
+                    .leases
+                    .iter()
+                    .filter(|l| l.topic == topic)
+                    .map(|l| l.subscriber)
+                    .collect();
+                for subscriber in subscribers {
+                    let message = Message::Notify {
+                        topic,
+                        source,
+                        payload: payload.clone(),
+                    };
+                    self.send_routed_or_handle(subscriber, message);
+                }
+            }
+            Message::Notify {
+                topic,
+                source,
+                ref payload,
+            } => {
+                if self.route(packet).is_none() {
+                    return;
+                }
+                if !self.topics.contains(&topic) {
+                    trace!(
                         self,
-                        next_receiver,
-                        final_cid,
-                        packet.message.clone(),
-                    ));
+                        "Dropping publication of unsubscribed topic {}.",
+                        topic
+                    );
+                    return;
                 }
+                push_limited(
+                    &mut self.publications,
+                    Publication {
+                        topic,
+                        source,
+                        payload: payload.clone(),
+                    },
+                    &self.config.limits.data_storage,
+                    &mut self.stats.data_storage,
+                    |_| 0,
+                );
             }
             Message::Hello(neigh) => {
-                let r = self.neighbors.insert(
-                    packet.sender_cid.expect("Expected that CID is set"),
-                    neigh.clone(), // age is set to 0
-                );
-                if r.is_none() {
-                    /*println!(
-                        "{}: received '{:?}' from {}",
-                        self.debug_name, packet.message, packet.sender_name
-                    );*/
+                let Some(sender_cid) = packet.sender_cid else {
+                    return;
+                };
+                // the age and the link are measured locally, never trust the sender
+                let mut link = self
+                    .neighbors
+                    .get(&sender_cid)
+                    .map(|n| n.link)
+                    .unwrap_or_default();
+                link.on_hello(neigh.seq, rssi, &self.config.link);
+                if self.restored {
+                    if Some(sender_cid) == self.c_id {
+                        trace!(self, "Restored position is taken, joining again.");
+                        self.give_up_position();
+                    } else if self.c_id.is_some()
+                        && (neigh.predecessor == self.c_id || neigh.successor == self.c_id)
+                    {
+                        self.restored = false;
+                    }
+                } else if Some(sender_cid) == self.c_id && self.join.is_none() {
+                    // the neighbors decide a join that is not confirmed yet. Until then the
+                    // node that a join at the edge moved announces its old position.
+                    trace!(self, "Position is taken, joining again.");
+                    self.give_up_position();
+                    return;
                 }
+                self.check_join(sender_cid, neigh.joined);
+                self.insert_neighbor(
+                    sender_cid,
+                    NeighborInfo {
+                        age: 0,
+                        link,
+                        ..neigh
+                    },
+                );
             }
-            Message::SendUpdatePredecessor { new_position } => {
-                let _old_cid = self.c_id.clone();
+            // requests to change the cord are only accepted when addressed directly
+            _ if matches!(packet.receiver, Receiver::Broadcast) => {}
+            // a node that joined at the same position is no anchor until its join is confirmed
+            _ if self.join.is_some() => {}
+            // the joiner didn't hear the neighbor that already has the position, it joins again
+            _ if packet.sender_cid.is_some_and(|cid| self.is_taken(cid)) => {}
+            Message::SendUpdatePredecessor { new_position, .. }
+            | Message::SendUpdateSuccessor { new_position, .. }
+                if !self.can_move_to(new_position) => {}
+            Message::SendUpdatePredecessor {
+                new_position,
+                token,
+            } => {
                 self.c_id = Some(new_position);
                 self.predecessor = packet.sender_cid;
+                self.accepted_join = token;
             }
-            Message::SendUpdateSuccessor { new_position } => {
-                let _old_cid = self.c_id.clone();
+            Message::SendUpdateSuccessor {
+                new_position,
+                token,
+            } => {
                 self.c_id = Some(new_position);
                 self.successor = packet.sender_cid;
+                self.accepted_join = token;
             }
-            Message::CreateVirtualNode { virtual_position } => {
-                let mut new_vcp = Vcp::new(false);
-                new_vcp.c_id = Some(virtual_position);
-                new_vcp.debug_name = format!("Virt {}", self.debug_name);
-                new_vcp.is_virtual = true;
-                self.virtual_nodes.push(new_vcp);
+            Message::CreateVirtualNode {
+                virtual_position,
+                token,
+            } => {
+                // virtual nodes don't host virtual nodes, and every position is hosted once
+                if self.is_virtual
+                    || self.c_id == Some(virtual_position)
+                    || self.is_taken(virtual_position)
+                    || self
+                        .virtual_nodes
+                        .as_slice()
+                        .iter()
+                        .any(|v| v.c_id == Some(virtual_position))
+                {
+                    return;
+                }
+                let mut new_vcp = VirtualNode::new_virtual(virtual_position, self.config);
+                // the host reads what is sealed or sent to its virtual nodes
+                new_vcp.public_key = self.public_key;
+                new_vcp.services = self.services;
+                new_vcp.ports = self.ports.clone();
+                // the name is only for debugging, cut it if it is too long
+                let _ = write!(new_vcp.debug_name, "Virt {}", self.debug_name);
+                let hosted = self.virtual_nodes.as_slice().len();
+                self.virtual_nodes.host(
+                    new_vcp,
+                    &self.config.limits.virtual_nodes,
+                    &mut self.stats.virtual_nodes,
+                );
+                if self.virtual_nodes.as_slice().len() > hosted {
+                    self.accepted_join = token;
+                }
             }
         }
     }
 
     fn send(&mut self, packet: &Packet) {
-        println!("send{}", serde_json::to_string(packet).unwrap());
-        self.outgoing_msgs.push(packet.clone());
+        trace!(self, "send{}", serde_json::to_string(packet).unwrap());
+        // the virtual nodes share the radio, they never hear it
+        for virt in self.virtual_nodes.as_mut_slice() {
+            virt.handle(packet, None);
+        }
+        push_limited(
+            &mut self.outgoing_msgs,
+            packet.clone(),
+            &self.config.limits.outgoing_msgs,
+            &mut self.stats.outgoing_msgs,
+            Packet::priority,
+        );
     }
 
-    pub fn send_text_data(&mut self, final_cid: CordId, text: String) {
-        let next_receiver = self.calc_closesed_to_final(final_cid);
-        //check if
-        let self_cid = self
-            .c_id
-            .expect("Expected self.cid beeing set when text msg is send.");
+    /// A neighbor other than the node itself is at the position
+    fn is_taken(&self, position: CordId) -> bool {
+        self.c_id != Some(position) && self.neighbors.contains_key(&position)
+    }
 
-        if next_receiver == self_cid {
-            println!("Abort sending data text. final receiver == sender");
-        } else {
-            self.send(&Packet::new_unicast_data(
-                self,
-                next_receiver,
-                final_cid,
-                Message::Text(text),
-            ));
-            println!(
-                "Node with cid: {} starting send off data. First receiver is {}.",
-                self_cid, next_receiver
-            );
-        }
+    /// No neighbor is at the position or between it and the own one, the node can move there
+    /// without changing the order of the cord
+    fn can_move_to(&self, position: CordId) -> bool {
+        let own = self.c_id.unwrap_or(position);
+        let (low, high) = (own.min(position), own.max(position));
+        !self
+            .neighbors
+            .keys()
+            .any(|&n| n != own && low <= n && n <= high)
     }
 
-    /// Function that HAS to be called periodically
-    pub fn timer_call(&mut self) {
-        self.ticks += 1;
-        if self.c_id.is_none() {
-            // request own position
-            if self.ticks > 1 {
-                self.set_my_position();
+    /// Remember a neighbor. A full table makes room, if the policy allows it.
+    fn insert_neighbor(&mut self, cid: CordId, info: NeighborInfo) {
+        let limit = self.config.limits.neighbors;
+        let capacity = limit.capacity.min(self.neighbors.max_len());
+        if !self.neighbors.contains_key(&cid) && self.neighbors.len() >= capacity {
+            // close neighbors are needed for routing and as predecessor or successor
+            let own = self.c_id;
+            let priority = |n: &CordId| own.map(|o| CordId::MAX - o.abs_diff(*n)).unwrap_or(0);
+            let mut oldest_first: List<(&CordId, &NeighborInfo), MAX_NEIGHBORS> =
+                self.neighbors.iter().collect();
+            oldest_first.sort_by_key(|(_, n)| Reverse(n.age));
+            let old = oldest_first.into_iter().map(|(&c, _)| c);
+            match victim(old, &limit, priority(&cid), priority) {
+                Some(old) => {
+                    self.neighbors.remove(&old);
+                    self.stats.neighbors.evicted += 1;
+                }
+                None => {
+                    self.stats.neighbors.rejected += 1;
+                    return;
+                }
             }
-        } else {
-            // send hello messages, regularly
-            self.send(&Packet::new(
-                &self,
-                Message::Hello(NeighborInfo {
-                    predecessor: self.predecessor,
-                    successor: self.successor,
-                    is_virtual: self.is_virtual,
-                    age: 0,
-                }),
-            ));
         }
-
-        self.update_neighbor_ages();
-
-        // find best successor and predecessor
-        let (s, p) = Vcp::calc_successor_predecessor(&self);
-        self.successor = s;
-        self.predecessor = p;
-
-        // Call timer of all virtual_nodes
-        for virt in self.virtual_nodes.iter_mut() {
-            virt.timer_call();
-            self.outgoing_msgs.append(&mut virt.outgoing_msgs); // and move outgoing messages to self
+        if self.neighbors.insert(cid, info).is_none() {
+            self.reset_hello();
         }
+        self.stats.neighbors.peak = self.stats.neighbors.peak.max(self.neighbors.len());
     }
 
-    /// Change age of all neighbor information.
-    /// This useful if information about neighbors get outdated
+    pub fn send_text_data(&mut self, final_cid: CordId, text: &str) {
+        let Some(text) = to_text(text) else {
+            trace!(self, "Abort sending data text. Text is too long");
+            return;
+        };
+        self.send_routed(final_cid, Message::Text(text));
+    }
+
+    /// Send bytes to the application bound to `port` at the node at `final_cid`
+    pub fn send_data(&mut self, final_cid: CordId, port: Port, payload: Payload) {
+        let Some(source) = self.c_id else {
+            trace!(self, "Abort sending data. No own cid yet");
+            return;
+        };
+        self.send_routed(
+            final_cid,
+            Message::Data {
+                port,
+                source,
+                payload,
+            },
+        );
+    }
+
+    /// Accept data on `port`, for this node and the virtual nodes it hosts.
+    /// Returns false if no more ports can be bound.
+    pub fn bind(&mut self, port: Port) -> bool {
+        for virt in self.virtual_nodes.as_mut_slice() {
+            virt.bind(port);
+        }
+        self.ports.contains(&port) || Queue::try_push(&mut self.ports, port).is_ok()
+    }
+
+    /// Stop accepting data on `port`. Data that already arrived stays in the `inbox`.
+    pub fn unbind(&mut self, port: Port) {
+        for virt in self.virtual_nodes.as_mut_slice() {
+            virt.unbind(port);
+        }
+        self.ports.retain(|&p| p != port);
+    }
+
+    pub fn is_bound(&self, port: Port) -> bool {
+        self.ports.contains(&port)
+    }
+
+    /// Send a payload sealed for the node at `final_cid`, see `e2e`
+    pub fn send_sealed(&mut self, final_cid: CordId, sealed: Sealed) {
+        self.send_routed(final_cid, Message::Sealed(sealed));
+    }
+
+    /// Ask the node at `c_id` for its public key. The answer ends up in `keys`.
+    pub fn request_key(&mut self, c_id: CordId) {
+        let Some(reply_to) = self.c_id else {
+            trace!(self, "Abort key request. No own cid yet");
+            return;
+        };
+        self.send_routed(c_id, Message::KeyRequest { reply_to });
+    }
+
+    /// Send bytes to the application bound to `port` on every node of the network.
+    /// Nodes out of reach for `flood_ttl` hops don't get them.
+    pub fn flood(&mut self, port: Port, payload: Payload) {
+        let Some(source) = self.c_id else {
+            trace!(self, "Abort flooding. No own cid yet");
+            return;
+        };
+        let seq = self.next_flood_seq;
+        let packet = Packet::new(
+            self,
+            Message::Flood {
+                source,
+                seq,
+                ttl: self.config.flood_ttl.saturating_sub(1),
+                port,
+                payload,
+            },
+        );
+        // floods are not fragmented
+        if !packet.fits(self.config.max_frame_len) {
+            trace!(self, "Abort flooding. The packet does not fit into a frame");
+            self.stats.oversized += 1;
+            return;
+        }
+        self.next_flood_seq = seq.wrapping_add(1);
+        self.send(&packet);
+    }
+
+    /// Send bytes to the application bound to `port` on every node with a position
+    /// in `[from, to]`, this one included
+    pub fn multicast(&mut self, from: CordId, to: CordId, port: Port, payload: Payload) {
+        let Some(source) = self.c_id else {
+            trace!(self, "Abort multicast. No own cid yet");
+            return;
+        };
+        let message = Message::Multicast {
+            from,
+            to,
+            source,
+            port,
+            payload,
+        };
+        self.send_routed_or_handle(from, message);
+    }
+
+    /// Walk the cord from its start to its end. Every node on the way reports its position,
+    /// its neighbors and the virtual nodes it hosts, the reports end up in `topology`.
+    /// The reports of earlier walks are dropped.
+    pub fn walk(&mut self) {
+        let Some(origin) = self.c_id else {
+            trace!(self, "Abort walk. No own cid yet");
+            return;
+        };
+        self.walk_id = self.walk_id.wrapping_add(1);
+        self.topology.clear();
+        let message = Message::Walk {
+            origin,
+            id: self.walk_id,
+        };
+        self.send_routed_or_handle(self.config.cord_start, message);
+    }
+
+    /// What this node reports to a walk
+    fn cord_entry(&self, c_id: CordId) -> CordEntry {
+        CordEntry {
+            c_id,
+            predecessor: self.predecessor,
+            successor: self.successor,
+            neighbors: self.neighbors.keys().copied().collect(),
+            virtual_nodes: self
+                .virtual_nodes
+                .as_slice()
+                .iter()
+                .filter_map(|v| v.c_id)
+                .collect(),
+        }
+    }
+
+    /// Send bytes to the application bound to `port` on the provider of `service` that is
+    /// the fewest hops away, which can be this node
+    pub fn anycast(&mut self, service: Service, port: Port, payload: Payload) {
+        let Some(source) = self.c_id else {
+            trace!(self, "Abort anycast. No own cid yet");
+            return;
+        };
+        self.anycast_hop(service, 0, source, port, payload);
+    }
+
+    /// Hops to the closest provider of the service, 0 if this node offers it.
+    /// `None` if no provider is known.
+    pub fn service_hops(&self, service: Service) -> Option<u8> {
+        if usize::from(service) >= SERVICE_COUNT {
+            return None;
+        }
+        if self.services & (1 << service) != 0 {
+            return Some(0);
+        }
+        self.next_hop_to(service).map(|(_, hops)| hops)
+    }
+
+    /// The routable neighbor the closest provider of the service is the fewest hops away
+    /// through, and the hops from here
+    fn next_hop_to(&self, service: Service) -> Option<(CordId, u8)> {
+        self.neighbors
+            .iter()
+            .filter(|(_, n)| n.link.is_routable(&self.config.link))
+            .filter_map(|(&c_id, n)| Some((c_id, n.hops_via(service)?)))
+            .min_by_key(|&(c_id, hops)| (hops, c_id))
+    }
+
+    /// The hops to the closest provider of every service this node does not offer itself
+    fn advertised_hops(&self) -> [u8; SERVICE_COUNT] {
+        let mut hops = [0; SERVICE_COUNT];
+        for (service, h) in (0..).zip(&mut hops) {
+            if self.services & (1 << service) == 0 {
+                *h = self.next_hop_to(service).map_or(0, |(_, hops)| hops);
+            }
+        }
+        hops
+    }
+
+    /// Deliver the anycast if this node offers the service, or pass it on towards the
+    /// closest provider
+    fn anycast_hop(
+        &mut self,
+        service: Service,
+        hops: u8,
+        source: CordId,
+        port: Port,
+        payload: Payload,
+    ) {
+        match self.service_hops(service) {
+            Some(0) => self.deliver(port, source, &payload),
+            Some(_) if hops < MAX_SERVICE_HOPS => {
+                let (next, _) = self.next_hop_to(service).expect("a provider is known");
+                let message = Message::Anycast {
+                    service,
+                    hops: hops + 1,
+                    source,
+                    port,
+                    payload,
+                };
+                let packet = Packet::new_unicast(self, next, message);
+                // anycasts are not fragmented
+                if packet.fits(self.config.max_frame_len) {
+                    self.send(&packet);
+                } else {
+                    trace!(
+                        self,
+                        "Dropping anycast. The packet does not fit into a frame"
+                    );
+                    self.stats.oversized += 1;
+                }
+            }
+            _ => {
+                trace!(
+                    self,
+                    "Dropping anycast. No provider of service {} known",
+                    service
+                );
+                self.stats.unreachable += 1;
+            }
+        }
+    }
+
+    /// Put data for a bound port into the `inbox`
+    fn deliver(&mut self, port: Port, source: CordId, payload: &Payload) {
+        if !self.ports.contains(&port) {
+            trace!(self, "Dropping data for unbound port {}.", port);
+            return;
+        }
+        push_limited(
+            &mut self.inbox,
+            Delivery {
+                port,
+                source,
+                payload: payload.clone(),
+            },
+            &self.config.limits.data_storage,
+            &mut self.stats.data_storage,
+            |_| 0,
+        );
+    }
+
+    /// Remember the flood. Returns false if it was seen before.
+    fn remember_flood(&mut self, source: CordId, seq: u16) -> bool {
+        if self
+            .seen_floods
+            .iter()
+            .any(|f| f.source == source && f.seq == seq)
+        {
+            return false;
+        }
+        let seen = SeenFlood {
+            source,
+            seq,
+            forget: self.now.saturating_add(millis(self.config.flood_memory)),
+        };
+        push_limited(
+            &mut self.seen_floods,
+            seen,
+            &self.config.limits.floods,
+            &mut self.stats.floods,
+            |_| 0,
+        );
+        true
+    }
+
+    /// Position of the rendezvous node of the topic: the node closest to it keeps the
+    /// subscriptions and hands the publications to the subscribers
+    pub fn rendezvous(&self, topic: TopicId) -> CordId {
+        let start = u64::from(self.config.cord_start);
+        let span = u64::from(self.config.cord_end).saturating_sub(start) + 1;
+        (start + u64::from(topic) % span) as CordId
+    }
+
+    /// Receive the publications of the topic in `publications`, from the next
+    /// `subscription_lease` on. Returns false if no more topics can be subscribed to.
+    pub fn subscribe(&mut self, topic: &str) -> bool {
+        let topic = topic_id(topic);
+        if !self.topics.contains(&topic) {
+            if Queue::try_push(&mut self.topics, topic).is_err() {
+                return false;
+            }
+            self.register(topic);
+        }
+        true
+    }
+
+    /// Stop receiving the publications of the topic. The rendezvous node forgets the
+    /// subscription when its lease runs out.
+    pub fn unsubscribe(&mut self, topic: &str) {
+        let topic = topic_id(topic);
+        self.topics.retain(|&t| t != topic);
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.topics.contains(&topic_id(topic))
+    }
+
+    /// Send the payload to the subscribers of the topic, by way of its rendezvous node
+    pub fn publish(&mut self, topic: &str, payload: Payload) {
+        let Some(source) = self.c_id else {
+            trace!(self, "Abort publishing. No own cid yet");
+            return;
+        };
+        let topic = topic_id(topic);
+        let message = Message::Publish {
+            topic,
+            source,
+            payload,
+        };
+        self.send_routed_or_handle(self.rendezvous(topic), message);
+    }
+
+    /// Subscribers registered at this node, as rendezvous of the topic
+    pub fn subscribers(&self, topic: TopicId) -> impl Iterator<Item = CordId> + '_ {
+        self.leases
+            .iter()
+            .filter(move |l| l.topic == topic)
+            .map(|l| l.subscriber)
+    }
+
+    /// Register this node for the topic at its rendezvous node
+    fn register(&mut self, topic: TopicId) {
+        let Some(subscriber) = self.c_id else {
+            // registered by the next refresh
+            return;
+        };
+        let rendezvous = self.rendezvous(topic);
+        self.send_routed_or_handle(rendezvous, Message::Subscribe { topic, subscriber });
+    }
+
+    /// Register all topics again, before their leases run out
+    fn refresh_subscriptions(&mut self) {
+        if self.now < self.next_refresh || self.c_id.is_none() {
+            return;
+        }
+        self.next_refresh = self
+            .now
+            .saturating_add((millis(self.config.subscription_lease) / 3).max(1));
+        let topics = self.topics.clone();
+        for topic in topics {
+            self.register(topic);
+        }
+    }
+
+    /// Keep or renew the subscription, as rendezvous node of the topic
+    fn add_lease(&mut self, topic: TopicId, subscriber: CordId) {
+        let expires = self
+            .now
+            .saturating_add(millis(self.config.subscription_lease));
+        let same = |l: &&mut Lease| l.topic == topic && l.subscriber == subscriber;
+        if let Some(lease) = self.leases.iter_mut().find(same) {
+            lease.expires = expires;
+            return;
+        }
+        let lease = Lease {
+            topic,
+            subscriber,
+            expires,
+        };
+        push_limited(
+            &mut self.leases,
+            lease,
+            &self.config.limits.subscribers,
+            &mut self.stats.subscribers,
+            |_| 0,
+        );
+    }
+
+    /// Forget the subscriptions that were not refreshed in time
+    fn expire_leases(&mut self) {
+        let now = self.now;
+        self.leases.retain(|l| l.expires > now);
+    }
+
+    /// Like `send_routed`, but if this node is the closest to `final_cid` already,
+    /// it handles the message itself
+    fn send_routed_or_handle(&mut self, final_cid: CordId, message: Message) {
+        let Some(self_cid) = self.c_id else {
+            return;
+        };
+        if self.calc_closesed_to_final(final_cid) == self_cid {
+            self.handle(
+                &Packet::new_unicast_data(self, self_cid, final_cid, message),
+                None,
+            );
+        } else {
+            self.send_routed(final_cid, message);
+        }
+    }
+
+    /// Start sending the message along the cord, to the neighbor closest to `final_cid`
+    fn send_routed(&mut self, final_cid: CordId, message: Message) {
+        let Some(self_cid) = self.c_id else {
+            trace!(self, "Abort sending data. No own cid yet");
+            return;
+        };
+        let next_receiver = self.calc_closesed_to_final(final_cid);
+
+        if next_receiver == self_cid {
+            trace!(self, "Abort sending data. final receiver == sender");
+        } else {
+            self.send_routed_packet(Packet::new_unicast_data(
+                self,
+                next_receiver,
+                final_cid,
+                message,
+            ));
+            trace!(
+                self,
+                "Node with cid: {} starting send off data. First receiver is {}.",
+                self_cid,
+                next_receiver
+            );
+        }
+    }
+
+    /// Forward a routed packet to the neighbor closest to its `final_cid`.
+    /// Returns the own and the sender cid if this node is the final receiver.
+    fn route(&mut self, packet: &Packet) -> Option<(CordId, CordId)> {
+        let (Some(final_cid), Some(self_cid), Some(sender_cid)) =
+            (packet.final_cid, self.c_id, packet.sender_cid)
+        else {
+            trace!(self, "Dropping data without final, own or sender cid.");
+            return None;
+        };
+        // every node in range hears a broadcast, the host and its virtual nodes as well
+        if !matches!(packet.receiver, Receiver::Unicast(_)) {
+            trace!(self, "Dropping data that was not sent to a next hop.");
+            return None;
+        }
+        let next_receiver = self.calc_closesed_to_final(final_cid);
+        if next_receiver == self_cid {
+            return Some((self_cid, sender_cid));
+        }
+        trace!(
+            self,
+            "Node with cid: {} forwarding data to node {}.",
+            self_cid,
+            next_receiver
+        );
+        //update packet info forward to closest neighbor to final
+        self.send_routed_packet(Packet::new_unicast_data(
+            self,
+            next_receiver,
+            final_cid,
+            packet.message.clone(),
+        ));
+        None
+    }
+
+    /// Queue a routed packet, in fragments if it does not fit into a frame
+    fn send_routed_packet(&mut self, mut packet: Packet) {
+        let Some(source) = self.c_id else {
+            return;
+        };
+        // the fragments are sized without the names of the relays
+        if matches!(packet.message, Message::Fragment(_)) {
+            packet.sender_name = Name::new();
+        }
+        if packet.fits(self.config.max_frame_len) {
+            self.send(&packet);
+            return;
+        }
+        let id = self.next_fragment_id;
+        let json = serde_json::to_string(&packet.message).expect("messages can always be encoded");
+        let chunk_len = self.chunk_len(source, id, packet.final_cid);
+        let max_count = List::<Fragment, MAX_FRAGMENTS>::new()
+            .max_len()
+            .min(u8::MAX.into());
+        let chunks: Vec<&str> = split(&json, chunk_len).collect();
+        let whole = chunks.iter().map(|c| c.len()).sum::<usize>() == json.len();
+        // a fragment that does not fit is never fragmented again
+        if !whole || chunks.len() > max_count || matches!(packet.message, Message::Fragment(_)) {
+            trace!(
+                self,
+                "Abort sending data. {} bytes need too many fragments",
+                json.len()
+            );
+            self.stats.oversized += 1;
+            return;
+        }
+        let count = chunks.len();
+        self.next_fragment_id = id.wrapping_add(1);
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let fragment = Fragment {
+                source,
+                id,
+                index: index as u8,
+                count: count as u8,
+                chunk: to_chunk(chunk).expect("chunks fit into CHUNK_CAPACITY"),
+            };
+            self.send(&Packet {
+                sender_name: Name::new(),
+                message: Message::Fragment(fragment),
+                ..packet.clone()
+            });
+        }
+    }
+
+    /// Bytes of escaped JSON that fit into a fragment, on the whole way to the final receiver
+    fn chunk_len(&self, source: CordId, id: u16, final_cid: Option<CordId>) -> usize {
+        // every relay sends it from its own position to the next one, assume the longest
+        let header = Packet {
+            receiver: Receiver::Unicast(CordId::MAX),
+            sender_name: Name::new(),
+            sender_cid: Some(CordId::MAX),
+            final_cid,
+            message: Message::Fragment(Fragment {
+                source,
+                id,
+                index: u8::MAX,
+                count: u8::MAX,
+                chunk: Chunk::new(),
+            }),
+        };
+        self.config
+            .max_frame_len
+            .saturating_sub(header.encode().len())
+    }
+
+    /// Keep a fragment for this node. Once all fragments arrived, the message is handled
+    /// as if it came in one piece.
+    fn reassemble(&mut self, packet: &Packet, fragment: &Fragment) {
+        let max_count = List::<Fragment, MAX_FRAGMENTS>::new().max_len();
+        if fragment.index >= fragment.count || usize::from(fragment.count) > max_count {
+            trace!(self, "Dropping malformed fragment.");
+            return;
+        }
+        let same = |r: &Reassembly| r.source == fragment.source && r.id == fragment.id;
+        let i = match self.reassembly.iter().position(same) {
+            Some(i) => i,
+            None => {
+                let new = Reassembly {
+                    source: fragment.source,
+                    id: fragment.id,
+                    count: fragment.count,
+                    deadline: self
+                        .now
+                        .saturating_add(millis(self.config.reassembly_timeout)),
+                    fragments: List::new(),
+                };
+                if !push_limited(
+                    &mut self.reassembly,
+                    new,
+                    &self.config.limits.reassembly,
+                    &mut self.stats.reassembly,
+                    |_| 0,
+                ) {
+                    return;
+                }
+                self.reassembly.len() - 1
+            }
+        };
+        let reassembly = &mut self.reassembly[i];
+        if reassembly.count != fragment.count
+            || reassembly
+                .fragments
+                .iter()
+                .any(|f| f.index == fragment.index)
+            || Queue::try_push(&mut reassembly.fragments, fragment.clone()).is_err()
+        {
+            return;
+        }
+        if reassembly.fragments.len() < usize::from(reassembly.count) {
+            return;
+        }
+        let mut complete = self.reassembly.remove(i);
+        complete.fragments.sort_unstable_by_key(|f| f.index);
+        let json: String = complete.fragments.iter().map(|f| &f.chunk[..]).collect();
+        let Ok(message) = serde_json::from_str(&json) else {
+            trace!(self, "Dropping reassembled message that does not decode.");
+            return;
+        };
+        let whole = Packet {
+            message,
+            ..packet.clone()
+        };
+        // only routed data is fragmented. A Hello or a change of the cord would be taken as
+        // one of the last relay, and fragments of fragments are never sent.
+        if !whole.is_type_data() || matches!(whole.message, Message::Fragment(_)) {
+            trace!(self, "Dropping reassembled message that is no routed data.");
+            return;
+        }
+        self.handle(&whole, None);
+    }
+
+    /// Give up the fragmented messages that took too long
+    fn expire_reassembly(&mut self) {
+        let now = self.now;
+        let known = self.reassembly.len();
+        self.reassembly.retain(|r| r.deadline > now);
+        self.stats.incomplete += (known - self.reassembly.len()) as u64;
+    }
+
+    /// Remember the public key of a node. The newest key wins, positions change hands.
+    /// A full directory keeps the keys of the nodes closest to this one.
+    fn learn_key(&mut self, c_id: CordId, key: PublicKey) {
+        let limit = self.config.limits.keys;
+        let capacity = limit.capacity.min(self.keys.max_len());
+        if !self.keys.contains_key(&c_id) && self.keys.len() >= capacity {
+            let own = self.c_id;
+            let priority = |n: &CordId| own.map(|o| CordId::MAX - o.abs_diff(*n)).unwrap_or(0);
+            match victim(self.keys.keys().copied(), &limit, priority(&c_id), priority) {
+                Some(old) => {
+                    self.keys.remove(&old);
+                    self.stats.keys.evicted += 1;
+                }
+                None => {
+                    self.stats.keys.rejected += 1;
+                    return;
+                }
+            }
+        }
+        self.keys.insert(c_id, key);
+        self.stats.keys.peak = self.stats.keys.peak.max(self.keys.len());
+    }
+
+    /// Function that HAS to be called periodically
+    pub fn timer_call(&mut self, clock: &impl Clock) {
+        let now = clock.now();
+        let started = *self.started.get_or_insert(now);
+        let elapsed = now.saturating_sub(self.now.max(started));
+        self.now = now;
+        if self.c_id.is_none() {
+            // request own position, once the Hellos of the neighbors could arrive
+            if now.saturating_sub(started) >= millis(self.config.hello_interval) + self.backoff {
+                self.set_my_position();
+            }
+        } else if now >= self.next_hello && self.join.is_none() {
+            // send hello messages, regularly. Until the join is confirmed the position might
+            // belong to another node, nobody should take the node as anchor.
+            self.send_hello();
+        }
+
+        self.update_neighbor_ages(elapsed);
+        self.expire_reassembly();
+        self.expire_leases();
+        self.refresh_subscriptions();
+        let now = self.now;
+        self.seen_floods.retain(|f| f.forget > now);
+
+        if self.join.as_ref().is_some_and(|j| now >= j.deadline) {
+            trace!(self, "Join was not confirmed, joining again.");
+            self.give_up_position();
+        }
+
+        if self.restored && !self.neighbors.is_empty() {
+            // the neighbors forgot the node while it was away, give them time to take it back
+            let timeout = millis(self.config.neighbor_timeout);
+            let deadline = now.saturating_add(timeout.saturating_mul(2));
+            if now >= *self.confirm_by.get_or_insert(deadline) {
+                trace!(self, "Restored position was not confirmed, joining again.");
+                self.give_up_position();
+            }
+        }
+
+        // find best successor and predecessor
+        let (start, end) = (self.config.cord_start, self.config.cord_end);
+        let old = (self.predecessor, self.successor);
+        let (s, p) = Vcp::calc_successor_predecessor(self);
+        self.successor = s;
+        self.predecessor = p;
+        self.repair_cord(old);
+        // the start is only reached by way of predecessors that reach it, a node alone is
+        // the whole cord
+        let lost = |edge: CordId,
+                    next: Option<CordId>,
+                    other: Option<CordId>,
+                    flag: fn(&NeighborInfo) -> bool| {
+            self.c_id != Some(edge)
+                && match next {
+                    Some(n) => self.neighbors.get(&n).is_none_or(flag),
+                    None => other.is_some(),
+                }
+        };
+        self.lost_start = lost(start, p, s, |n| n.lost_start);
+        self.lost_end = lost(end, s, p, |n| n.lost_end);
+
+        let adaptive = matches!(self.config.hello_schedule, HelloSchedule::Adaptive { .. });
+        if adaptive && self.c_id.is_some() && self.join.is_none() && self.announced != (p, s) {
+            // the neighbors need to know about the change now
+            self.reset_hello();
+            self.send_hello();
+        }
+
+        // Call timer of all virtual_nodes
+        for virt in self.virtual_nodes.as_mut_slice() {
+            virt.timer_call(clock);
+        }
+        self.virtual_nodes.drop_unplaced();
+        self.take_virtual_outgoing();
+    }
+
+    /// Take the position the node had before a reboot. It is given up again if another node
+    /// has it by now, or if no neighbor lists it as predecessor or successor in time.
+    pub fn restore(&mut self, c_id: CordId) {
+        self.c_id = Some(c_id);
+        self.restored = true;
+        self.confirm_by = None;
+    }
+
+    /// The position is not a restored one, that still waits for the neighbors
+    pub fn is_confirmed(&self) -> bool {
+        !self.restored
+    }
+
+    /// The time of the last `timer_call`
+    pub fn now(&self) -> Millis {
+        self.now
+    }
+
+    /// Change how often Hellos are sent and when neighbors are forgotten, from the next
+    /// Hello on
+    pub fn set_timing(&mut self, hello_interval: Duration, neighbor_timeout: Duration) {
+        self.config.hello_interval = hello_interval;
+        self.config.neighbor_timeout = neighbor_timeout;
+        self.hello_interval = millis(hello_interval).max(1);
+        self.next_hello = self
+            .next_hello
+            .min(self.now.saturating_add(self.hello_interval));
+    }
+
+    /// Give up the position and join the cord again, like a new node. The neighbors forget
+    /// the old position when it times out.
+    pub fn rejoin(&mut self) {
+        trace!(self, "Joining again.");
+        self.give_up_position();
+    }
+
+    fn give_up_position(&mut self) {
+        self.c_id = None;
+        self.predecessor = None;
+        self.successor = None;
+        self.restored = false;
+        self.confirm_by = None;
+        self.join = None;
+        self.loose_since = None;
+        self.lost_start = false;
+        self.lost_end = false;
+        // the virtual nodes were placed next to the old position
+        self.virtual_nodes = V::default();
+        // entries of neighbors that moved since their last Hello would mislead the join,
+        // it waits for fresh Hellos like at the start
+        self.neighbors.retain(|_, _| false);
+        self.started = None;
+        self.backoff = self.next_token() as Millis
+            % millis(self.config.hello_interval).saturating_mul(2).max(1);
+    }
+
+    /// A node without predecessor that is not at the start of the cord lost the link to the
+    /// nodes below, when a neighbor left. If that neighbor was the start, the node takes its
+    /// place. Otherwise the rest of the cord is only reachable around the node, and it joins
+    /// again, unless it has no neighbor left. The same holds for a node without successor and
+    /// the end of the cord.
+    /// A node that is not the successor of its predecessor hangs off the cord as a fork and
+    /// joins again as well. A virtual node is dropped by its host instead.
+    /// Joins change the cord for a moment, so it waits for at least a whole neighbor timeout.
+    fn repair_cord(&mut self, old: (Option<CordId>, Option<CordId>)) {
+        let (start, end) = (self.config.cord_start, self.config.cord_end);
+        let (pred, succ) = (self.predecessor, self.successor);
+        let Some(c) = self.c_id.filter(|_| !self.restored) else {
+            self.loose_since = None;
+            return;
+        };
+        // a successor in between that the node hears takes its place soon
+        let forked = pred
+            .and_then(|p| self.neighbors.get(&p))
+            .is_some_and(|n| match n.successor {
+                Some(s) if s < c => !self.neighbors.contains_key(&s),
+                s => s != Some(c),
+            });
+        // a fork waits until the loose ends are repaired, their joins must not meet
+        let timeout = millis(self.config.neighbor_timeout);
+        let (lost, edge, wait) = if c != start && pred.is_none() {
+            (old.0, Some(start), timeout)
+        } else if c != end && succ.is_none() {
+            (old.1, Some(end), timeout)
+        } else if forked {
+            (None, None, timeout.saturating_mul(2))
+        } else {
+            self.loose_since = None;
+            return;
+        };
+        if self.loose_since.is_none() {
+            self.loose_since = Some(self.now);
+            self.lost_neighbor = lost;
+        }
+        let since = self.loose_since.unwrap_or(self.now);
+        if self.now.saturating_sub(since) < wait {
+            return;
+        }
+        match edge {
+            // another node took the edge, its link is not usable yet
+            Some(edge) if self.neighbors.contains_key(&edge) => {}
+            Some(edge) if self.lost_neighbor == Some(edge) => {
+                trace!(self, "The node at {} left, taking its place.", edge);
+                self.c_id = Some(edge);
+                self.loose_since = None;
+            }
+            // alone, the node is the whole cord
+            _ if pred.is_none() && succ.is_none() => self.loose_since = None,
+            _ => {
+                trace!(
+                    self,
+                    "Lost the link to the rest of the cord, joining again."
+                );
+                self.give_up_position();
+            }
+        }
+    }
+
+    /// Move the outgoing messages of the virtual nodes to self.
+    /// The host and its virtual nodes share the radio and never hear their own frames,
+    /// so the packets are handed to the host and the other virtual nodes as well.
+    fn take_virtual_outgoing(&mut self) {
+        // every handover gets a packet closer to its destination, that ends
+        for _ in 0..=MAX_VIRTUAL_NODES {
+            let mut moved = false;
+            // a handed packet can make the host give up its position and drop the virtual
+            // nodes with it
+            let mut i = 0;
+            while i < self.virtual_nodes.as_slice().len() {
+                let packets =
+                    core::mem::take(&mut self.virtual_nodes.as_mut_slice()[i].outgoing_msgs);
+                for packet in packets {
+                    moved = true;
+                    for (j, sibling) in self.virtual_nodes.as_mut_slice().iter_mut().enumerate() {
+                        if j != i {
+                            sibling.handle(&packet, None);
+                        }
+                    }
+                    self.handle(&packet, None);
+                    push_limited(
+                        &mut self.outgoing_msgs,
+                        packet,
+                        &self.config.limits.outgoing_msgs,
+                        &mut self.stats.outgoing_msgs,
+                        Packet::priority,
+                    );
+                }
+                i += 1;
+            }
+            if !moved {
+                break;
+            }
+        }
+    }
+
+    fn send_hello(&mut self) {
+        self.send(&Packet::new(
+            self,
+            Message::Hello(NeighborInfo {
+                predecessor: self.predecessor,
+                successor: self.successor,
+                is_virtual: self.is_virtual,
+                age: 0,
+                seq: self.hello_seq,
+                link: LinkQuality::default(),
+                services: self.services,
+                service_hops: self.advertised_hops(),
+                lost_start: self.lost_start,
+                lost_end: self.lost_end,
+                joined: self.accepted_join,
+            }),
+        ));
+        self.hello_seq = self.hello_seq.wrapping_add(1);
+        self.announced = (self.predecessor, self.successor);
+        match self.config.hello_schedule {
+            // keep to the multiples of the interval, even if the timer is late
+            HelloSchedule::Fixed => {
+                self.next_hello =
+                    (self.now / self.hello_interval + 1).saturating_mul(self.hello_interval);
+            }
+            // nothing changed until now, wait longer for the next one
+            HelloSchedule::Adaptive { max_interval } => {
+                let timeout = millis(self.config.neighbor_timeout);
+                let max = millis(max_interval).min(timeout / 2).max(1);
+                self.next_hello = self.now.saturating_add(self.hello_interval);
+                self.hello_interval = self.hello_interval.saturating_mul(2).min(max);
+            }
+        }
+    }
+
+    /// The neighborhood changed, go back to fast Hellos
+    fn reset_hello(&mut self) {
+        if let HelloSchedule::Adaptive { .. } = self.config.hello_schedule {
+            self.hello_interval = millis(self.config.hello_interval).max(1);
+            self.next_hello = self
+                .next_hello
+                .min(self.now.saturating_add(self.hello_interval));
+        }
+    }
+
+    /// Change age of all neighbor information.
+    /// This useful if information about neighbors get outdated
     /// Also delete neighbors that are too old.
-    fn update_neighbor_ages(&mut self) {
+    fn update_neighbor_ages(&mut self, elapsed: Millis) {
         for n in self.neighbors.iter_mut() {
-            n.1.age += 1;
+            n.1.age += elapsed;
+        }
+        // remove all that timed out
+        let timeout = millis(self.config.neighbor_timeout);
+        let known = self.neighbors.len();
+        self.neighbors.retain(|_, n| n.age < timeout);
+        if self.neighbors.len() < known {
+            self.reset_hello();
         }
-        // remove all older than 5
-        self.neighbors.retain(|_, n| n.age < 5);
     }
 
     /// Calculate the predecessor and successor by choosing the closest neighbor.
@@ -455,7 +2444,7 @@ impl Vcp {
                     return *set;
                 }
             }
-            return Some(new);
+            Some(new)
         }
         fn set_if_larger(set: &Option<CordId>, new: CordId) -> Option<CordId> {
             if let Some(min1) = *set {
@@ -463,18 +2452,27 @@ impl Vcp {
                     return *set;
                 }
             }
-            return Some(new);
+            Some(new)
         }
 
         if let Some(cid) = self.c_id {
-            for (&n, _neigh) in self.neighbors.iter() {
-                if n > cid {
+            // neighbors that lost the start or the end are only taken if there is no other
+            let usable = self.neighbors.iter().filter(|(_, n)| n.link.is_usable());
+            let (mut lost_succ, mut lost_pred) = (None, None);
+            for (&n, neigh) in usable {
+                if n > cid && neigh.lost_end {
+                    lost_succ = set_if_larger(&lost_succ, n);
+                } else if n > cid {
                     succ = set_if_larger(&succ, n);
                 }
-                if n < cid {
+                if n < cid && neigh.lost_start {
+                    lost_pred = set_if_smaller(&lost_pred, n);
+                } else if n < cid {
                     pred = set_if_smaller(&pred, n);
                 }
             }
+            succ = succ.or(lost_succ);
+            pred = pred.or(lost_pred);
         }
         (succ, pred)
     }
@@ -485,8 +2483,9 @@ impl Vcp {
         //calc diff btw. own id and final goal id
         let mut smallest_diff = final_cid.abs_diff(self_cid);
 
-        //chek if some neighbor is closer
-        for (&n, _neigh) in self.neighbors.iter() {
+        //chek if some neighbor is closer, over a link that works
+        let routable = |n: &&NeighborInfo| n.link.is_routable(&self.config.link);
+        for (&n, _neigh) in self.neighbors.iter().filter(|(_, n)| routable(n)) {
             let diff = n.abs_diff(final_cid);
             if diff < smallest_diff {
                 smallest_diff = diff;
@@ -500,6 +2499,9 @@ impl Vcp {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::collections::to_payload;
+    use crate::limits::{DropPolicy, Limit};
 
     #[test]
     fn calc_successor_predecessor() {
@@ -511,6 +2513,7 @@ mod tests {
             successor: None,
             is_virtual: false,
             age: 0,
+            ..Default::default()
         };
         slf.neighbors.insert(60, ni);
         slf.neighbors.insert(70, ni);
@@ -523,6 +2526,313 @@ mod tests {
         assert_eq!(p, Some(45));
     }
 
+    #[test]
+    fn malformed_packets_are_ignored() {
+        let mut slf = Vcp::new(false);
+        let other = Vcp::new(true);
+        let mut text = Packet::new(&other, Message::Text(to_text("hi").unwrap()));
+        text.sender_cid = None;
+        slf.receive(&text);
+        slf.receive(&Packet::new(
+            &slf,
+            Message::Hello(NeighborInfo {
+                predecessor: None,
+                successor: None,
+                is_virtual: false,
+                age: 0,
+                ..Default::default()
+            }),
+        ));
+        // cord changes have to be addressed to the node
+        slf.receive(&Packet::new(
+            &other,
+            Message::CreateVirtualNode {
+                virtual_position: 5,
+                token: 1,
+            },
+        ));
+        slf.receive(&Packet::new(
+            &other,
+            Message::SendUpdatePredecessor {
+                new_position: 5,
+                token: 1,
+            },
+        ));
+
+        assert!(slf.neighbors.is_empty());
+        assert!(slf.virtual_nodes.is_empty());
+        assert_eq!(slf.c_id, None);
+        assert!(Packet::decode(b"{\"receiver\":").is_err());
+    }
+
+    #[test]
+    fn virtual_positions_are_hosted_once() {
+        let mut slf = Vcp::new(true);
+        let create = Packet::new_unicast(
+            &slf,
+            0,
+            Message::CreateVirtualNode {
+                virtual_position: u32::MAX,
+                token: 1,
+            },
+        );
+        slf.receive(&create);
+        slf.receive(&create);
+        assert_eq!(slf.virtual_nodes.len(), 1);
+
+        // the virtual node must not host another one
+        let mut nested = create.clone();
+        nested.receiver = Receiver::Unicast(u32::MAX);
+        slf.receive(&nested);
+        assert_eq!(slf.virtual_nodes.len(), 1);
+        assert!(slf.virtual_nodes[0].virtual_nodes.as_slice().is_empty());
+        assert_eq!(midpoint(u32::MAX, u32::MAX - 2), u32::MAX - 1);
+    }
+
+    #[test]
+    fn forwarded_text_wakes_the_driver() {
+        let mut slf = Vcp::new(false);
+        slf.c_id = Some(500);
+        let mut far = Vcp::new(false);
+        far.c_id = Some(900);
+        let hello = Packet::new(
+            &far,
+            Message::Hello(NeighborInfo {
+                predecessor: None,
+                successor: None,
+                is_virtual: false,
+                age: 0,
+                ..Default::default()
+            }),
+        );
+        assert_eq!(slf.receive(&hello), Wakeup::Idle);
+
+        let text = Packet::new_unicast_data(&far, 500, 1000, Message::Text(to_text("hi").unwrap()));
+        assert_eq!(slf.receive(&text), Wakeup::Flush);
+        assert!(slf.outgoing_msgs[0].is_for(Some(900)));
+
+        // the forwarded text still waits, but nothing new was queued
+        assert_eq!(slf.receive(&hello), Wakeup::Idle);
+    }
+
+    #[test]
+    fn neighbor_table_keeps_closest() {
+        let mut slf = Vcp::new(false);
+        slf.c_id = Some(500);
+        slf.config.limits.neighbors = Limit::new(2, DropPolicy::DropLowestPriority);
+        let hello = |cid: CordId| {
+            let mut other = Vcp::new(false);
+            other.c_id = Some(cid);
+            Packet::new(
+                &other,
+                Message::Hello(NeighborInfo {
+                    predecessor: None,
+                    successor: None,
+                    is_virtual: false,
+                    age: 0,
+                    ..Default::default()
+                }),
+            )
+        };
+        for cid in [100, 900, 450, 0, 550] {
+            slf.receive(&hello(cid));
+        }
+        assert_eq!(
+            slf.neighbors.keys().copied().collect::<Vec<_>>(),
+            vec![450, 550]
+        );
+        assert_eq!(slf.stats.neighbors.evicted, 2);
+        assert_eq!(slf.stats.neighbors.rejected, 1);
+        assert_eq!(slf.stats.neighbors.peak, 2);
+    }
+
+    #[test]
+    fn outgoing_queue_keeps_cord_changes() {
+        let mut slf = Vcp::new(true);
+        slf.config.limits.outgoing_msgs = Limit::new(1, DropPolicy::DropLowestPriority);
+        slf.send(&Packet::new_unicast(
+            &slf,
+            1,
+            Message::CreateVirtualNode {
+                virtual_position: 2,
+                token: 1,
+            },
+        ));
+        slf.timer_call(&ManualClock::new(1000)); // the Hello does not fit anymore
+        assert_eq!(slf.outgoing_msgs.len(), 1);
+        assert!(matches!(
+            slf.outgoing_msgs[0].message,
+            Message::CreateVirtualNode { .. }
+        ));
+        assert_eq!(slf.stats.outgoing_msgs.rejected, 1);
+    }
+
+    #[test]
+    fn adaptive_hellos_back_off_and_reset() {
+        let mut slf = Vcp::new(true);
+        slf.config.hello_schedule = HelloSchedule::Adaptive {
+            max_interval: Duration::from_secs(8),
+        };
+        slf.config.neighbor_timeout = Duration::from_secs(100);
+        let clock = ManualClock::default();
+        // the seconds in which Hellos were sent
+        let hello_ticks = |slf: &mut Vcp, ticks: u64| {
+            let mut sent = Vec::new();
+            for _ in 0..ticks {
+                clock.advance(Duration::from_secs(1));
+                slf.timer_call(&clock);
+                if !core::mem::take(&mut slf.outgoing_msgs).is_empty() {
+                    sent.push(clock.now() / 1000);
+                }
+            }
+            sent
+        };
+        assert_eq!(hello_ticks(&mut slf, 30), vec![1, 2, 4, 8, 16, 24]);
+
+        // a new neighbor becomes the successor: announce it now, then start over
+        let mut other = Vcp::new(false);
+        other.c_id = Some(700);
+        slf.receive(&Packet::new(
+            &other,
+            Message::Hello(NeighborInfo {
+                predecessor: None,
+                successor: None,
+                is_virtual: false,
+                age: 0,
+                ..Default::default()
+            }),
+        ));
+        assert_eq!(hello_ticks(&mut slf, 4), vec![31, 32, 34]);
+        assert_eq!(slf.successor, Some(700));
+    }
+
+    #[test]
+    fn huge_timings_saturate() {
+        let clock = ManualClock::new(1000);
+        let mut slf = placed(500);
+        slf.config.hello_schedule = HelloSchedule::Adaptive {
+            max_interval: Duration::MAX,
+        };
+        slf.set_timing(Duration::MAX, Duration::MAX);
+        slf.restore(500);
+        slf.receive(&hello_of(0, None, Some(1000)));
+        for _ in 0..3 {
+            slf.timer_call(&clock);
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(slf.next_hello, Millis::MAX);
+        assert_eq!(slf.confirm_by, Some(Millis::MAX));
+    }
+
+    #[test]
+    fn routing_avoids_weak_links() {
+        let mut slf = Vcp::new(false);
+        slf.c_id = Some(500);
+        let hello = |cid: CordId, seq: u16| {
+            let mut other = Vcp::new(false);
+            other.c_id = Some(cid);
+            Packet::new(
+                &other,
+                Message::Hello(NeighborInfo {
+                    seq,
+                    ..Default::default()
+                }),
+            )
+        };
+        // 900 is closer to the destination, but only every third Hello of it arrives
+        for seq in 0..12 {
+            slf.receive(&hello(700, seq));
+            if seq % 3 == 0 {
+                slf.receive_with_rssi(&hello(900, seq), Some(-50));
+            }
+        }
+        assert!(slf.neighbors.get(&900).unwrap().link().ratio() < slf.config.link.route_ratio);
+        assert_eq!(slf.calc_closesed_to_final(1000), 700);
+        slf.timer_call(&ManualClock::new(1000));
+        assert_eq!(slf.successor, Some(700));
+
+        // a good link is taken again
+        for seq in 12..30 {
+            slf.receive(&hello(900, seq));
+        }
+        assert_eq!(slf.calc_closesed_to_final(1000), 900);
+    }
+
+    #[test]
+    fn neighbors_time_out_after_a_duration() {
+        let clock = ManualClock::default();
+        let mut slf = Vcp::new(false);
+        slf.timer_call(&clock);
+        let mut other = Vcp::new(false);
+        other.c_id = Some(100);
+        slf.receive(&Packet::new(
+            &other,
+            Message::Hello(NeighborInfo::default()),
+        ));
+        // the timer runs ten times as often as the Hellos, the timeout stays 5 s
+        let mut calls = 0;
+        while !slf.neighbors.is_empty() {
+            clock.advance(Duration::from_millis(100));
+            slf.timer_call(&clock);
+            calls += 1;
+        }
+        assert_eq!(calls, 50);
+    }
+
+    #[test]
+    fn clock_going_backwards_is_tolerated() {
+        // a joining node, and one that lost its predecessor
+        let mut joining = Vcp::new(false);
+        let mut loose = Vcp::new(false);
+        loose.c_id = Some(500);
+        loose.successor = Some(600);
+        for slf in [&mut joining, &mut loose] {
+            slf.timer_call(&ManualClock::new(10_000));
+            slf.timer_call(&ManualClock::new(0));
+            assert_eq!(slf.now, 0);
+        }
+    }
+
+    #[test]
+    fn restored_position_is_checked() {
+        let hello = |cid: CordId, successor: Option<CordId>| {
+            let mut other = Vcp::new(false);
+            other.c_id = Some(cid);
+            Packet::new(
+                &other,
+                Message::Hello(NeighborInfo {
+                    successor,
+                    ..Default::default()
+                }),
+            )
+        };
+        // a neighbor took the node back as its successor
+        let mut slf = Vcp::new(false);
+        slf.restore(500);
+        slf.receive(&hello(400, Some(500)));
+        assert!(slf.is_confirmed());
+        assert_eq!(slf.c_id, Some(500));
+
+        // another node has the position by now
+        let mut slf = Vcp::new(false);
+        slf.restore(500);
+        slf.receive(&hello(500, None));
+        assert_eq!(slf.c_id, None);
+
+        // the neighbor keeps its successor, the position is given up after two timeouts
+        let clock = ManualClock::default();
+        let mut slf = Vcp::new(false);
+        slf.restore(500);
+        let mut seconds = 0;
+        while slf.c_id.is_some() {
+            slf.receive(&hello(400, Some(600)));
+            clock.advance(Duration::from_secs(1));
+            slf.timer_call(&clock);
+            seconds += 1;
+        }
+        assert_eq!(seconds, 11);
+    }
+
     #[test]
     fn calc_successor_predecessor_virtual() {
         let mut slf = Vcp::new(false);
@@ -533,6 +2843,7 @@ mod tests {
             successor: None,
             is_virtual: true,
             age: 0,
+            ..Default::default()
         };
         slf.neighbors.insert(0, ni);
         slf.neighbors.insert(60, ni);
@@ -542,4 +2853,819 @@ mod tests {
         assert_eq!(s, Some(60));
         assert_eq!(p, Some(0));
     }
+
+    #[test]
+    fn long_messages_are_fragmented() {
+        let config = VcpConfig {
+            verbose: false,
+            max_frame_len: 220,
+            ..Default::default()
+        };
+        let node = |c_id| {
+            let mut vcp = Vcp::with_config(false, config);
+            vcp.c_id = Some(c_id);
+            vcp
+        };
+        let mut sender = node(0);
+        let _ = write!(sender.debug_name, "a long node name");
+        let mut receiver = node(1000);
+        sender.receive(&Packet::new(
+            &receiver,
+            Message::Hello(NeighborInfo::default()),
+        ));
+        // escaped in the JSON, and cut between characters
+        let text = "\"ä€\\".repeat(18);
+        sender.send_text_data(1000, &text);
+        let fragments = core::mem::take(&mut sender.outgoing_msgs);
+        assert!(fragments.len() > 1);
+        for packet in &fragments {
+            assert!(matches!(packet.message, Message::Fragment(_)));
+            assert!(packet.encode().len() <= 220);
+        }
+        // only the positions of the relays are not known, the rest of the frame is used
+        for packet in &fragments[..fragments.len() - 1] {
+            assert!(packet.encode().len() > 190);
+        }
+
+        // in any order, and twice
+        receiver.receive(&fragments[fragments.len() - 1]);
+        for packet in fragments.iter().rev() {
+            receiver.receive(packet);
+        }
+        assert_eq!(receiver.data_storage.len(), 1);
+        assert_eq!(&receiver.data_storage[0].text[..], text);
+
+        // a fragment is lost, the rest is given up after the timeout
+        let clock = ManualClock::new(0);
+        receiver.timer_call(&clock);
+        sender.send_text_data(1000, &text);
+        for packet in core::mem::take(&mut sender.outgoing_msgs).iter().skip(1) {
+            receiver.receive(packet);
+        }
+        clock.advance(config.reassembly_timeout);
+        receiver.timer_call(&clock);
+        assert_eq!(receiver.stats.incomplete, 1);
+        assert_eq!(receiver.data_storage.len(), 1);
+
+        // no frame is long enough
+        sender.config.max_frame_len = 140;
+        sender.send_text_data(1000, &text);
+        assert!(sender.outgoing_msgs.is_empty());
+        assert_eq!(sender.stats.oversized, 1);
+        assert_eq!(split("aä€", 4).collect::<Vec<_>>(), ["aä", "€"]);
+        assert_eq!(split("a\"\n", 3).collect::<Vec<_>>(), ["a\"", "\n"]);
+        assert_eq!(split("a\u{1}", 3).collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn heapless_nodes_stay_small() {
+        // most of it are the virtual nodes, each has all the tables of a node
+        assert!(core::mem::size_of::<Vcp>() <= 120 * 1024);
+    }
+
+    #[test]
+    fn only_data_is_reassembled() {
+        let mut receiver = placed(1000);
+        let sender = placed(500);
+        receiver.receive(&hello_of(500, None, Some(1000)));
+        // a Hello from far away, it would be taken as one of the relay
+        let message = Message::Hello(NeighborInfo {
+            predecessor: Some(123),
+            ..Default::default()
+        });
+        let json = serde_json::to_string(&message).unwrap();
+        let (first, second) = json.split_at(json.len() / 2);
+        for (index, chunk) in [first, second].into_iter().enumerate() {
+            let fragment = Fragment {
+                source: 0,
+                id: 1,
+                index: index as u8,
+                count: 2,
+                chunk: to_chunk(chunk).unwrap(),
+            };
+            receiver.receive(&Packet::new_unicast_data(
+                &sender,
+                1000,
+                1000,
+                Message::Fragment(fragment),
+            ));
+        }
+        assert!(receiver.reassembly.is_empty());
+        assert_eq!(receiver.neighbors.get(&500).unwrap().predecessor, None);
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn node_ids_come_from_the_public_key() {
+        let id = node_id(&[1; 32]);
+        assert_eq!(id, node_id(&[1; 32]));
+        assert_ne!(id, node_id(&[2; 32]));
+        assert_ne!(id, u32::from_le_bytes([1; 4]));
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn signed_hello_fits_an_esp_now_frame() {
+        let m = CordId::MAX;
+        let mut slf = Vcp::new(false);
+        slf.config.verbose = false;
+        (slf.c_id, slf.predecessor, slf.successor) = (Some(m - 1), Some(m - 2), Some(m));
+        slf.public_key = Some([255; 32]);
+        slf.services = Services::MAX;
+        slf.hello_seq = u16::MAX;
+        (slf.lost_start, slf.lost_end) = (true, true);
+        slf.timer_call(&ManualClock::new(0));
+        let hello = &slf.outgoing_msgs[0];
+        assert!(matches!(hello.message, Message::Hello(_)));
+        assert!(hello.encode().len() + crate::auth::OVERHEAD <= 250);
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn signed_texts_fit_an_esp_now_frame() {
+        let m = CordId::MAX;
+        let mut sender = Vcp::with_config(
+            false,
+            VcpConfig {
+                verbose: false,
+                max_frame_len: 250 - crate::auth::OVERHEAD,
+                ..Default::default()
+            },
+        );
+        sender.c_id = Some(m - 1);
+        sender.receive(&hello_of(m, Some(m - 1), None));
+        let mut auth = crate::auth::Authenticator::new(b"network key", u32::MAX, u32::MAX);
+        // whole up to the longest packet, fragmented beyond
+        for len in 1..300 {
+            sender.send_text_data(m, &"\"".repeat(len));
+            for packet in core::mem::take(&mut sender.outgoing_msgs) {
+                assert!(auth.seal(&packet).len() <= 250, "{} bytes of text", len);
+            }
+        }
+        assert_eq!(sender.stats.oversized, 0);
+    }
+
+    #[test]
+    fn encoded_length_is_bounded() {
+        let m = CordId::MAX;
+        let messages = [
+            Message::Hello(NeighborInfo {
+                predecessor: Some(m),
+                successor: Some(m),
+                age: Millis::MAX,
+                seq: u16::MAX,
+                services: Services::MAX,
+                service_hops: [MAX_SERVICE_HOPS; SERVICE_COUNT],
+                lost_start: true,
+                lost_end: true,
+                ..Default::default()
+            }),
+            Message::Hello(NeighborInfo::default()),
+            Message::CreateVirtualNode {
+                virtual_position: m,
+                token: u32::MAX,
+            },
+            Message::Text(to_text("\"\\\n\u{1}ä").unwrap()),
+            Message::Data {
+                port: u16::MAX,
+                source: m,
+                payload: to_payload(&[0, 9, 10, 99, 100, 255]).unwrap(),
+            },
+            Message::Sealed(Sealed {
+                sender_key: [100; 32],
+                nonce: u64::MAX,
+                ciphertext: to_payload(&[255; 3]).unwrap(),
+            }),
+            Message::KeyRequest { reply_to: m },
+            Message::KeyReply {
+                c_id: m,
+                key: [9; 32],
+            },
+            Message::Fragment(Fragment {
+                source: m,
+                id: u16::MAX,
+                index: u8::MAX,
+                count: u8::MAX,
+                chunk: to_chunk("{\"Text\":").unwrap(),
+            }),
+            Message::Subscribe {
+                topic: TopicId::MAX,
+                subscriber: m,
+            },
+            Message::Publish {
+                topic: TopicId::MAX,
+                source: m,
+                payload: to_payload(&[1, 2]).unwrap(),
+            },
+            Message::Notify {
+                topic: TopicId::MAX,
+                source: m,
+                payload: to_payload(&[]).unwrap(),
+            },
+            Message::Flood {
+                source: m,
+                seq: u16::MAX,
+                ttl: u8::MAX,
+                port: Port::MAX,
+                payload: to_payload(&[7]).unwrap(),
+            },
+            Message::Multicast {
+                from: m,
+                to: m,
+                source: m,
+                port: Port::MAX,
+                payload: to_payload(&[]).unwrap(),
+            },
+            Message::Anycast {
+                service: Service::MAX,
+                hops: u8::MAX,
+                source: m,
+                port: Port::MAX,
+                payload: to_payload(&[200]).unwrap(),
+            },
+            Message::Walk {
+                origin: m,
+                id: u16::MAX,
+            },
+            Message::WalkReport {
+                id: u16::MAX,
+                entry: CordEntry {
+                    c_id: m,
+                    predecessor: Some(m),
+                    successor: Some(m),
+                    neighbors: [0, 9, 10, m].into_iter().collect(),
+                    virtual_nodes: [999_999].into_iter().collect(),
+                },
+            },
+            Message::WalkReport {
+                id: 0,
+                entry: CordEntry::default(),
+            },
+        ];
+        for message in messages {
+            let mut packet = Packet::new_unicast_data(&Vcp::new(false), m, m, message);
+            packet.sender_cid = Some(m);
+            let _ = write!(packet.sender_name, "\tx");
+            let len = packet.encode().len();
+            assert!(packet.max_encoded_len() >= len, "{:?}", packet.message);
+            assert!(packet.fits(len) && !packet.fits(len - 1));
+        }
+    }
+
+    #[test]
+    fn subscriptions_are_leased() {
+        assert_eq!(topic_id(""), 0x811c_9dc5);
+        assert_eq!(topic_id("a"), 0xe40c_292c);
+        let mut slf = Vcp::new(true);
+        slf.config.verbose = false;
+        slf.config.cord_end = u32::MAX;
+        assert_eq!(slf.rendezvous(u32::MAX), u32::MAX);
+        slf.config.cord_end = 1000;
+        assert!((0..1000).all(|t| slf.rendezvous(t * 7919) <= 1000));
+
+        // alone on the cord, the node is the rendezvous of every topic
+        let clock = ManualClock::new(0);
+        assert!(slf.subscribe("temperature"));
+        slf.publish("temperature", to_payload(b"21").unwrap());
+        slf.publish("humidity", to_payload(b"40").unwrap());
+        let topic = topic_id("temperature");
+        assert_eq!(slf.subscribers(topic).collect::<Vec<_>>(), [0]);
+        assert_eq!(
+            slf.publications[..],
+            [Publication {
+                topic,
+                source: 0,
+                payload: to_payload(b"21").unwrap(),
+            }]
+        );
+
+        // refreshed while subscribed, forgotten a lease after the last refresh
+        let lease = slf.config.subscription_lease;
+        for _ in 0..3 {
+            clock.advance(lease / 2);
+            slf.timer_call(&clock);
+            assert_eq!(slf.subscribers(topic).count(), 1);
+        }
+        slf.unsubscribe("temperature");
+        assert!(!slf.is_subscribed("temperature"));
+        clock.advance(lease);
+        slf.timer_call(&clock);
+        assert_eq!(slf.subscribers(topic).count(), 0);
+
+        slf.config.limits.subscribers = Limit::new(1, DropPolicy::Reject);
+        slf.subscribe("a");
+        slf.subscribe("b");
+        assert_eq!(slf.stats.subscribers.rejected, 1);
+    }
+
+    #[test]
+    fn floods_are_forwarded_once() {
+        let mut source = Vcp::new(true);
+        let mut relay = Vcp::new(false);
+        for node in [&mut source, &mut relay] {
+            node.config.verbose = false;
+            node.config.flood_ttl = 2;
+        }
+        relay.c_id = Some(500);
+        relay.bind(3);
+        source.flood(3, to_payload(b"config").unwrap());
+        let flood = source.outgoing_msgs.pop().unwrap();
+        assert!(matches!(flood.message, Message::Flood { ttl: 1, .. }));
+
+        relay.receive(&flood);
+        relay.receive(&flood);
+        assert_eq!(relay.inbox.len(), 1);
+        assert_eq!(relay.inbox[0].source, 0);
+        let [forwarded] = &relay.outgoing_msgs[..] else {
+            panic!("one forwarded flood expected");
+        };
+        assert!(matches!(forwarded.message, Message::Flood { ttl: 0, .. }));
+        // the last hop does not forward, the source drops its own flood
+        let mut last = Vcp::new(false);
+        last.c_id = Some(1000);
+        last.receive(forwarded);
+        assert!(last.outgoing_msgs.is_empty());
+        source.receive(forwarded);
+        assert!(source.outgoing_msgs.is_empty());
+
+        // forgotten after a while, then it is new again
+        let clock = ManualClock::new(0);
+        relay.outgoing_msgs.clear();
+        clock.advance(relay.config.flood_memory);
+        relay.timer_call(&clock);
+        relay.outgoing_msgs.clear();
+        relay.receive(&flood);
+        assert_eq!(relay.inbox.len(), 2);
+        assert_eq!(relay.outgoing_msgs.len(), 1);
+    }
+
+    #[test]
+    fn anycast_takes_the_fewest_hops() {
+        let mut relay = Vcp::new(false);
+        relay.config.verbose = false;
+        relay.c_id = Some(500);
+        let gateway = NeighborInfo {
+            services: 0b10,
+            ..Default::default()
+        };
+        let mut far = NeighborInfo::default();
+        far.service_hops[1] = 3;
+        far.service_hops[2] = MAX_SERVICE_HOPS - 1;
+        relay.neighbors.insert(100, gateway);
+        relay.neighbors.insert(900, far);
+        assert_eq!(relay.service_hops(1), Some(1));
+        assert_eq!(relay.service_hops(2), None);
+        assert_eq!(relay.advertised_hops(), [0, 1, 0, 0, 0, 0, 0, 0]);
+
+        relay.bind(4);
+        relay.anycast(1, 4, to_payload(b"telemetry").unwrap());
+        let packet = relay.outgoing_msgs.pop().unwrap();
+        assert!(matches!(packet.receiver, Receiver::Unicast(100)));
+        assert!(matches!(packet.message, Message::Anycast { hops: 1, .. }));
+        relay.anycast(2, 4, to_payload(b"telemetry").unwrap());
+        assert!(relay.outgoing_msgs.is_empty());
+        assert_eq!(relay.stats.unreachable, 1);
+        // a provider takes what is sent to it
+        relay.services = 0b100;
+        relay.anycast(2, 4, to_payload(b"telemetry").unwrap());
+        assert_eq!(relay.inbox.len(), 1);
+
+        // the services travel with the Hellos
+        relay.send_hello();
+        let hello = Packet::decode(&relay.outgoing_msgs.pop().unwrap().encode()).unwrap();
+        let Message::Hello(info) = hello.message else {
+            panic!("Hello expected");
+        };
+        assert_eq!(info.services, 0b100);
+        assert_eq!(info.service_hops, [0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /// A node at `cid` that does not print
+    fn placed(cid: CordId) -> Vcp {
+        let mut slf = Vcp::new(false);
+        slf.config.verbose = false;
+        slf.c_id = Some(cid);
+        slf
+    }
+
+    /// A Hello of the node at `cid`
+    fn hello_of(cid: CordId, predecessor: Option<CordId>, successor: Option<CordId>) -> Packet {
+        Packet::new(
+            &placed(cid),
+            Message::Hello(NeighborInfo {
+                predecessor,
+                successor,
+                ..Default::default()
+            }),
+        )
+    }
+
+    /// Seconds until the position of the node changes, while it hears `hellos` every second
+    fn seconds_until_moved(slf: &mut Vcp, clock: &ManualClock, hellos: &[Packet]) -> u64 {
+        let position = slf.c_id;
+        let mut seconds = 0;
+        while slf.c_id == position && seconds < 60 {
+            for hello in hellos {
+                slf.receive(hello);
+            }
+            clock.advance(Duration::from_secs(1));
+            slf.timer_call(clock);
+            seconds += 1;
+        }
+        seconds
+    }
+
+    #[test]
+    fn lost_start_is_taken_over() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        let end = hello_of(1000, Some(500), None);
+        slf.receive(&hello_of(0, None, Some(500)));
+        slf.receive(&end);
+        slf.timer_call(&clock);
+        assert_eq!((slf.predecessor, slf.successor), (Some(0), Some(1000)));
+
+        // the start times out, one neighbor timeout later its successor takes its place
+        assert_eq!(seconds_until_moved(&mut slf, &clock, &[end]), 10);
+        assert_eq!(slf.c_id, Some(0));
+        assert_eq!(slf.successor, Some(1000));
+    }
+
+    #[test]
+    fn lost_predecessor_joins_again() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        let end = hello_of(1000, Some(500), None);
+        slf.receive(&hello_of(400, None, Some(500)));
+        slf.timer_call(&clock);
+        assert_eq!(slf.predecessor, Some(400));
+
+        // the rest of the cord is only reachable around the node
+        assert_eq!(seconds_until_moved(&mut slf, &clock, &[end]), 10);
+        assert_eq!(slf.c_id, None);
+        assert!(slf.neighbors.is_empty());
+    }
+
+    #[test]
+    fn alone_the_node_keeps_its_position() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        slf.receive(&hello_of(400, None, Some(500)));
+        slf.receive(&hello_of(1000, Some(500), None));
+        slf.timer_call(&clock);
+        assert_eq!(seconds_until_moved(&mut slf, &clock, &[]), 60);
+        assert_eq!(slf.c_id, Some(500));
+        assert_eq!((slf.predecessor, slf.successor), (None, None));
+    }
+
+    #[test]
+    fn new_node_at_the_edge_is_waited_for() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        let end = hello_of(1000, Some(500), None);
+        slf.receive(&hello_of(0, None, Some(500)));
+        slf.timer_call(&clock);
+
+        // the old start left, a new one is heard now and then, too rarely to use the link
+        let mut weak_start = hello_of(0, None, Some(500));
+        let mut seconds = 0;
+        while seconds < 30 {
+            if seconds % 4 == 0 {
+                if let Message::Hello(ref mut info) = weak_start.message {
+                    info.seq = seconds;
+                }
+                slf.receive(&weak_start);
+            }
+            slf.receive(&end);
+            clock.advance(Duration::from_secs(1));
+            slf.timer_call(&clock);
+            seconds += 1;
+        }
+        assert!(!slf.neighbors.get(&0).unwrap().link().is_usable());
+        assert_eq!(slf.predecessor, None);
+        assert_eq!(slf.c_id, Some(500));
+    }
+
+    #[test]
+    fn fork_joins_again() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        // 400 took 450 as its successor, which this node does not hear
+        let hellos = [
+            hello_of(400, Some(0), Some(450)),
+            hello_of(1000, Some(500), None),
+        ];
+        assert_eq!(seconds_until_moved(&mut slf, &clock, &hellos), 11);
+        assert_eq!(slf.c_id, None);
+    }
+
+    /// A node that joins at the start, `joined` is what the moved start confirms
+    fn join_at_the_start(clock: &ManualClock, seed: u32) -> (Vcp, JoinToken) {
+        let mut slf = Vcp::new(false);
+        slf.config.verbose = false;
+        slf.seed(seed);
+        slf.timer_call(clock);
+        slf.receive(&hello_of(0, None, Some(1000)));
+        slf.receive(&hello_of(1000, Some(0), None));
+        clock.advance(Duration::from_secs(1));
+        slf.timer_call(clock);
+        assert_eq!(slf.c_id, Some(0));
+        let Message::SendUpdatePredecessor {
+            new_position,
+            token,
+        } = slf.outgoing_msgs[0].message
+        else {
+            panic!("update of the start expected");
+        };
+        assert_eq!(new_position, 500);
+        // the entry of the moved start is kept under its new position
+        assert!(slf.neighbors.contains_key(&500) && !slf.neighbors.contains_key(&0));
+        (slf, token)
+    }
+
+    /// The Hello of the moved start, after it made room for the join with `token`
+    fn moved_start(token: JoinToken) -> Packet {
+        let mut hello = hello_of(500, Some(0), Some(1000));
+        if let Message::Hello(ref mut info) = hello.message {
+            info.joined = token;
+        }
+        hello
+    }
+
+    #[test]
+    fn concurrent_joins_are_decided_by_the_neighbors() {
+        let clock = ManualClock::default();
+        let (mut slf, token) = join_at_the_start(&clock, 1);
+        let (mut other, other_token) = join_at_the_start(&clock, 2);
+        assert_ne!(token, other_token);
+
+        // the moved start still announces its old position until it got the updates,
+        // and both joins wait for it
+        slf.receive(&hello_of(0, None, Some(1000)));
+        assert_eq!(slf.c_id, Some(0));
+        // it got the other update last, that node has the position
+        slf.receive(&moved_start(other_token));
+        other.receive(&moved_start(other_token));
+        assert_eq!(slf.c_id, None);
+        assert_eq!(slf.neighbors.keys().copied().collect::<Vec<_>>(), [500]);
+        assert_eq!(other.c_id, Some(0));
+        assert!(other.join.is_none());
+
+        // a confirmed node that hears another one at its position gives it up
+        other.receive(&hello_of(0, None, Some(500)));
+        assert_eq!(other.c_id, None);
+
+        // a join nobody confirms is given up after a neighbor timeout
+        let (mut slf, _) = join_at_the_start(&clock, 1);
+        let mut seconds = 0;
+        while slf.c_id.is_some() {
+            slf.receive(&moved_start(0));
+            clock.advance(Duration::from_secs(1));
+            slf.timer_call(&clock);
+            seconds += 1;
+        }
+        assert_eq!(seconds, 5);
+    }
+
+    #[test]
+    fn joins_are_confirmed_with_hellos() {
+        let mut anchor = placed(0);
+        anchor.receive(&Packet::new_unicast(
+            &placed(0),
+            0,
+            Message::SendUpdatePredecessor {
+                new_position: 500,
+                token: 7,
+            },
+        ));
+        anchor.send_hello();
+        let hello = Packet::decode(&anchor.outgoing_msgs.pop().unwrap().encode()).unwrap();
+        assert!(matches!(
+            hello.message,
+            Message::Hello(NeighborInfo { joined: 7, .. })
+        ));
+
+        // a virtual position that is hosted already is no room for the join
+        let create = |token| {
+            Packet::new_unicast(
+                &placed(100),
+                500,
+                Message::CreateVirtualNode {
+                    virtual_position: 200,
+                    token,
+                },
+            )
+        };
+        anchor.receive(&create(8));
+        anchor.receive(&create(9));
+        assert_eq!(anchor.accepted_join, 8);
+    }
+
+    #[test]
+    fn anchors_keep_the_order_of_the_cord() {
+        let mut anchor = placed(0);
+        anchor.receive(&hello_of(250, Some(0), None));
+        let update = |sender, new_position| {
+            Packet::new_unicast(
+                &placed(sender),
+                0,
+                Message::SendUpdatePredecessor {
+                    new_position,
+                    token: 7,
+                },
+            )
+        };
+        // not past a known neighbor
+        anchor.receive(&update(0, 500));
+        assert_eq!(anchor.c_id, Some(0));
+        // not for a joiner at the position of a known neighbor
+        anchor.receive(&update(250, 100));
+        assert_eq!(anchor.c_id, Some(0));
+        anchor.receive(&Packet::new_unicast(
+            &placed(100),
+            0,
+            Message::CreateVirtualNode {
+                virtual_position: 250,
+                token: 8,
+            },
+        ));
+        assert!(anchor.virtual_nodes.as_slice().is_empty());
+        assert_eq!(anchor.accepted_join, 0);
+
+        anchor.receive(&update(0, 100));
+        assert_eq!(anchor.c_id, Some(100));
+        assert_eq!(anchor.accepted_join, 7);
+    }
+
+    #[test]
+    fn lost_edges_are_told_and_avoided() {
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        let mut lost = hello_of(400, Some(300), Some(500));
+        if let Message::Hello(ref mut info) = lost.message {
+            info.lost_start = true;
+        }
+        slf.receive(&lost);
+        slf.receive(&hello_of(1000, Some(500), None));
+        slf.timer_call(&clock);
+        assert_eq!(slf.predecessor, Some(400));
+        assert!(slf.lost_start && !slf.lost_end);
+        slf.outgoing_msgs.clear();
+        clock.advance(Duration::from_secs(1));
+        slf.timer_call(&clock);
+        let hello = Packet::decode(&slf.outgoing_msgs.pop().unwrap().encode()).unwrap();
+        let Message::Hello(info) = hello.message else {
+            panic!("Hello expected");
+        };
+        assert!(info.lost_start && !info.lost_end);
+
+        // a predecessor that reaches the start is taken over a closer one that doesn't
+        slf.receive(&hello_of(300, Some(0), Some(400)));
+        clock.advance(Duration::from_secs(1));
+        slf.timer_call(&clock);
+        assert_eq!(slf.predecessor, Some(300));
+        assert!(!slf.lost_start);
+
+        // a new node does not join next to a node that lost an edge
+        let mut joining = Vcp::new(false);
+        joining.config.verbose = false;
+        joining.timer_call(&clock);
+        joining.receive(&lost);
+        clock.advance(Duration::from_secs(1));
+        joining.timer_call(&clock);
+        assert_eq!(joining.c_id, None);
+    }
+
+    #[test]
+    fn rejoins_wait_a_random_time() {
+        let backoff = |seed| {
+            let mut slf = placed(500);
+            slf.seed(seed);
+            slf.rejoin();
+            slf.backoff
+        };
+        let limit = 2 * millis(VcpConfig::default().hello_interval);
+        assert!((1..20).all(|seed| backoff(seed) < limit));
+        assert_ne!(backoff(1), backoff(2));
+
+        // the node asks for a position one Hello interval and the backoff after the rejoin
+        let clock = ManualClock::default();
+        let mut slf = placed(500);
+        slf.seed(1);
+        slf.rejoin();
+        let wait = millis(slf.config.hello_interval) + slf.backoff;
+        slf.timer_call(&clock);
+        slf.receive(&hello_of(0, None, Some(1000)));
+        slf.receive(&hello_of(1000, Some(0), None));
+        clock.advance(Duration::from_millis(wait - 1));
+        slf.timer_call(&clock);
+        assert_eq!(slf.c_id, None);
+        clock.advance(Duration::from_millis(1));
+        slf.timer_call(&clock);
+        assert!(slf.c_id.is_some());
+    }
+
+    #[test]
+    fn join_fields_are_optional_on_the_wire() {
+        // a healthy Hello is as long as before the joins were confirmed
+        let hello = String::from_utf8(hello_of(0, None, Some(500)).encode()).unwrap();
+        assert!(!hello.contains("joined") && !hello.contains("lost_"));
+
+        // messages of nodes that don't send the fields
+        let old: Message =
+            serde_json::from_str(r#"{"Hello":{"predecessor":null,"successor":500,"seq":3}}"#)
+                .unwrap();
+        let Message::Hello(info) = old else {
+            panic!("Hello expected");
+        };
+        assert!(info.joined == 0 && !info.lost_start && !info.lost_end);
+        let old: Message =
+            serde_json::from_str(r#"{"SendUpdatePredecessor":{"new_position":250}}"#).unwrap();
+        assert!(matches!(
+            old,
+            Message::SendUpdatePredecessor {
+                new_position: 250,
+                token: 0
+            }
+        ));
+    }
+
+    #[test]
+    fn virtual_nodes_share_the_radio() {
+        let clock = ManualClock::default();
+        let mut host = placed(0);
+        host.receive(&Packet::new_unicast(
+            &placed(1000),
+            0,
+            Message::CreateVirtualNode {
+                virtual_position: 500,
+                token: 1,
+            },
+        ));
+        host.timer_call(&clock);
+        // the host and its virtual node hear each other's Hellos without the radio
+        assert!(host.neighbors.contains_key(&500));
+        assert!(host.virtual_nodes[0].neighbors.contains_key(&0));
+
+        // a virtual node that gave up its position is dropped
+        clock.advance(Duration::from_secs(1));
+        host.timer_call(&clock);
+        host.receive(&hello_of(500, Some(0), Some(1000)));
+        assert_eq!(host.virtual_nodes[0].c_id, None);
+        host.timer_call(&clock);
+        assert!(host.virtual_nodes.is_empty());
+    }
+
+    #[test]
+    fn handed_packets_may_drop_the_virtual_nodes() {
+        let mut host = placed(0);
+        for (virtual_position, token) in [(500, 1), (700, 2)] {
+            host.receive(&Packet::new_unicast(
+                &placed(1000),
+                0,
+                Message::CreateVirtualNode {
+                    virtual_position,
+                    token,
+                },
+            ));
+        }
+        assert_eq!(host.virtual_nodes.len(), 2);
+
+        // the first virtual node announces the position of the host
+        host.virtual_nodes[0].send(&hello_of(0, None, Some(500)));
+        host.take_virtual_outgoing();
+        assert_eq!(host.c_id, None);
+        assert!(host.virtual_nodes.is_empty());
+    }
+
+    #[test]
+    fn broadcast_data_is_not_routed() {
+        let clock = ManualClock::default();
+        let mut host = placed(0);
+        host.receive(&Packet::new_unicast(
+            &placed(1000),
+            0,
+            Message::CreateVirtualNode {
+                virtual_position: 500,
+                token: 1,
+            },
+        ));
+        host.timer_call(&clock);
+        host.outgoing_msgs.clear();
+
+        // the virtual node would hand the text to the host, that heard it already
+        let mut text =
+            Packet::new_unicast_data(&placed(1000), 500, 0, Message::Text(to_text("hi").unwrap()));
+        text.receiver = Receiver::Broadcast;
+        host.receive(&text);
+        host.timer_call(&clock);
+        assert!(host.data_storage.is_empty());
+        assert!(host.virtual_nodes[0].outgoing_msgs.is_empty());
+
+        text.receiver = Receiver::Unicast(500);
+        host.receive(&text);
+        host.timer_call(&clock);
+        assert_eq!(host.data_storage.len(), 1);
+    }
 }